@@ -0,0 +1,234 @@
+use std::{fmt, net::IpAddr, str::FromStr};
+
+use axum::http::HeaderMap;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Access, AccessList};
+
+/// An ip range, eg: `192.168.0.0/16`. A bare address is a range of one
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+
+        let addr = addr
+            .trim()
+            .parse::<IpAddr>()
+            .map_err(|e| format!("invalid ip range {s}: {e}"))?
+            .to_canonical();
+
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("invalid prefix length in ip range {s}"))?,
+            None => max,
+        };
+
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(value: Cidr) -> Self {
+        value.to_string()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl AccessList {
+    /// Denies win over allows, and when there are allows, anything not in them is denied
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|c| c.contains(ip)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|c| c.contains(ip))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+}
+
+/// Whether the address is one of the proxies in front of us
+pub fn trusted_proxy(ip: IpAddr, config: &Access) -> bool {
+    config.trusted_proxies.iter().any(|c| c.contains(ip))
+}
+
+/// The ip of the client, which is the peer unless it's a trusted proxy which said otherwise
+pub fn client_ip(peer: IpAddr, headers: &HeaderMap, config: &Access) -> IpAddr {
+    let trusted = |ip: IpAddr| trusted_proxy(ip, config);
+
+    let Some(header) = &config.client_ip_header else {
+        return peer;
+    };
+
+    if !trusted(peer) {
+        return peer;
+    }
+
+    // eg: X-Forwarded-For: client, proxy1, proxy2
+    // proxies append, so the first untrusted address from the right is the furthest we can trust
+    let hops = headers
+        .get_all(header.as_str())
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+        .collect::<Vec<_>>();
+
+    hops.iter()
+        .rev()
+        .find(|ip| !trusted(**ip))
+        .or(hops.first())
+        .copied()
+        .unwrap_or(peer)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn cidr(cidr: &str) -> Cidr {
+        cidr.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(cidr("192.168.0.0/16").to_string(), "192.168.0.0/16");
+        assert_eq!(cidr(" 10.0.0.1 ").to_string(), "10.0.0.1/32");
+        assert_eq!(cidr("::1").to_string(), "::1/128");
+        assert_eq!(cidr("fd00::/8").to_string(), "fd00::/8");
+        // mapped addresses are treated as the ipv4 ones they are
+        assert_eq!(cidr("::ffff:10.0.0.0/8").to_string(), "10.0.0.0/8");
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+        assert!("example.com".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn contains_addresses() {
+        let lan = cidr("192.168.0.0/16");
+        assert!(lan.contains(ip("192.168.1.2")));
+        assert!(lan.contains(ip("::ffff:192.168.1.2")));
+        assert!(!lan.contains(ip("192.169.0.1")));
+        assert!(!lan.contains(ip("::1")));
+
+        assert!(cidr("0.0.0.0/0").contains(ip("8.8.8.8")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+        assert!(cidr("2001:db8::/32").contains(ip("2001:db8:1::1")));
+        assert!(!cidr("2001:db8::/32").contains(ip("2001:db9::1")));
+        assert!(cidr("10.0.0.1").contains(ip("10.0.0.1")));
+        assert!(!cidr("10.0.0.1").contains(ip("10.0.0.2")));
+    }
+
+    #[test]
+    fn deny_wins() {
+        let list = AccessList {
+            allow: vec![cidr("10.0.0.0/8")],
+            deny: vec![cidr("10.1.0.0/16")],
+        };
+
+        assert!(list.permits(ip("10.0.0.1")));
+        assert!(!list.permits(ip("10.1.0.1")));
+        assert!(!list.permits(ip("192.168.0.1")));
+        assert!(AccessList::default().permits(ip("192.168.0.1")));
+    }
+
+    #[test]
+    fn client_ip_from_trusted_proxies() {
+        let config = Access {
+            client_ip_header: Some("X-Forwarded-For".to_owned()),
+            trusted_proxies: vec![cidr("10.0.0.0/8")],
+            ..Default::default()
+        };
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("1.1.1.1, 2.2.2.2, 10.0.0.2"),
+        );
+
+        // the first untrusted hop from the right, as anything before it could be made up
+        assert_eq!(client_ip(ip("10.0.0.1"), &headers, &config), ip("2.2.2.2"));
+        // only trusted proxies are listened to
+        assert_eq!(client_ip(ip("3.3.3.3"), &headers, &config), ip("3.3.3.3"));
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), &HeaderMap::new(), &config),
+            ip("10.0.0.1")
+        );
+
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("10.0.0.3, 10.0.0.2"),
+        );
+        assert_eq!(client_ip(ip("10.0.0.1"), &headers, &config), ip("10.0.0.3"));
+
+        let untrusting = Access {
+            trusted_proxies: Vec::new(),
+            ..config
+        };
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), &headers, &untrusting),
+            ip("10.0.0.1")
+        );
+    }
+}
@@ -0,0 +1,42 @@
+mod forward;
+mod htpasswd;
+mod oidc;
+
+use axum::{body::Body, http::HeaderMap};
+use hyper_rustls::{ConfigBuilderExt, HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::{
+    client::legacy::{Client, connect::HttpConnector},
+    rt::TokioExecutor,
+};
+use rustls::ClientConfig;
+
+pub use forward::{ForwardAuth, ForwardAuthError, Verdict};
+pub use htpasswd::Htpasswd;
+pub use oidc::{Oidc, OidcError, clear_cookie, get_cookie, strip_cookies};
+
+/// Headers describing the logged in user, for connections which don't forward the request's
+/// headers as-is, like websockets
+#[derive(Debug, Clone)]
+pub struct Identity(pub HeaderMap);
+
+/// Marks a request one of the auth layers let through, so its response is known to be for that
+/// user only
+#[derive(Debug, Clone, Copy)]
+pub struct Authenticated;
+
+/// Client for auth services, which unlike the backend may be https
+pub type AuthClient = Client<HttpsConnector<HttpConnector>, Body>;
+
+pub fn auth_client() -> AuthClient {
+    let connector = HttpsConnectorBuilder::new()
+        .with_tls_config(
+            ClientConfig::builder()
+                .with_webpki_roots()
+                .with_no_client_auth(),
+        )
+        .https_or_http()
+        .enable_http1()
+        .build();
+
+    Client::builder(TokioExecutor::new()).build(connector)
+}
@@ -0,0 +1,297 @@
+//! Forward auth: asks an external service (eg: Authelia, oauth2-proxy) whether to let a request
+//! through, before it's proxied
+
+use std::{net::IpAddr, time::Duration};
+
+use axum::{
+    body::Body,
+    http::{
+        HeaderMap, HeaderName, HeaderValue, Method, Request, Response, StatusCode, Uri,
+        header::{CONTENT_LENGTH, HOST, TRANSFER_ENCODING},
+    },
+};
+use snafu::{ResultExt, Snafu};
+use tokio::time;
+
+use super::AuthClient;
+use crate::{config, proxy::strip_hop_by_hop};
+
+#[derive(Debug, Snafu)]
+pub enum ForwardAuthError {
+    #[snafu(display("invalid forward auth url: {source}"))]
+    Url { source: axum::http::uri::InvalidUri },
+    #[snafu(display("invalid forward auth header name: {source}"))]
+    Header {
+        source: axum::http::header::InvalidHeaderName,
+    },
+    #[snafu(display("failed to build auth request: {source}"))]
+    Build { source: axum::http::Error },
+    #[snafu(display("auth service error: {source}"))]
+    Request {
+        source: hyper_util::client::legacy::Error,
+    },
+    #[snafu(display("auth service didn't respond in time"))]
+    Timeout,
+}
+
+impl ForwardAuthError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+/// What the auth service decided
+#[derive(Debug)]
+pub enum Verdict {
+    /// let the request through, with these headers added
+    Allow(HeaderMap),
+    /// send the auth service's response (eg: a redirect to its login page) to the client
+    Deny(Response<Body>),
+}
+
+#[derive(Debug)]
+pub struct ForwardAuth {
+    url: Uri,
+    copy_headers: Vec<HeaderName>,
+    timeout: Duration,
+}
+
+impl ForwardAuth {
+    pub fn new(config: &config::ForwardAuth, url: &str) -> Result<Self, ForwardAuthError> {
+        let copy_headers = config
+            .copy_headers
+            .iter()
+            .map(|h| HeaderName::try_from(h.as_str()))
+            .collect::<Result<_, _>>()
+            .context(HeaderSnafu)?;
+
+        Ok(Self {
+            url: url.parse().context(UrlSnafu)?,
+            copy_headers,
+            timeout: Duration::from_secs(config.timeout),
+        })
+    }
+
+    /// Headers which only the auth service may set
+    pub fn copy_headers(&self) -> &[HeaderName] {
+        &self.copy_headers
+    }
+
+    pub async fn check(
+        &self,
+        client: &AuthClient,
+        method: &Method,
+        uri: &Uri,
+        headers: &HeaderMap,
+        client_ip: IpAddr,
+    ) -> Result<Verdict, ForwardAuthError> {
+        let mut sub_headers = headers.clone();
+        strip_hop_by_hop(&mut sub_headers);
+
+        // the subrequest has no body, and goes to a different host
+        for name in [CONTENT_LENGTH, TRANSFER_ENCODING, HOST] {
+            sub_headers.remove(name);
+        }
+
+        // http/2 requests carry the host in the uri instead
+        let host = headers
+            .get(HOST)
+            .and_then(|h| h.to_str().ok())
+            .or_else(|| uri.authority().map(|a| a.as_str()))
+            .unwrap_or_default();
+        let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+
+        let forwarded = [
+            ("x-forwarded-method", method.as_str().to_owned()),
+            ("x-forwarded-proto", "https".to_owned()),
+            ("x-forwarded-host", host.to_owned()),
+            ("x-forwarded-uri", path.to_owned()),
+            ("x-forwarded-for", client_ip.to_string()),
+            ("x-original-url", format!("https://{host}{path}")),
+        ];
+
+        for (name, value) in forwarded {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                sub_headers.insert(name, value);
+            }
+        }
+
+        let mut req = Request::builder()
+            .method(method)
+            .uri(&self.url)
+            .body(Body::empty())
+            .context(BuildSnafu)?;
+        *req.headers_mut() = sub_headers;
+
+        let res = time::timeout(self.timeout, client.request(req))
+            .await
+            .map_err(|_| ForwardAuthError::Timeout)?
+            .context(RequestSnafu)?;
+
+        if res.status().is_success() {
+            let mut allowed = HeaderMap::new();
+            for name in &self.copy_headers {
+                for value in res.headers().get_all(name) {
+                    allowed.append(name.clone(), value.clone());
+                }
+            }
+
+            return Ok(Verdict::Allow(allowed));
+        }
+
+        let mut res = res.map(Body::new);
+        strip_hop_by_hop(res.headers_mut());
+
+        Ok(Verdict::Deny(res))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{Router, extract::State, http::header::LOCATION, response::IntoResponse};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::auth::auth_client;
+
+    type Seen = Arc<Mutex<Option<HeaderMap>>>;
+
+    /// Lets requests with `Authorization: good` through, and sends the rest to a login page
+    async fn service(State(seen): State<Seen>, headers: HeaderMap) -> impl IntoResponse {
+        let allowed = headers.get("authorization").is_some_and(|a| a == "good");
+        *seen.lock().unwrap() = Some(headers);
+
+        if allowed {
+            (
+                StatusCode::OK,
+                [
+                    ("remote-user", "alice"),
+                    ("remote-groups", "admins"),
+                    ("x-not-copied", "1"),
+                ],
+            )
+                .into_response()
+        } else {
+            (
+                StatusCode::FOUND,
+                [
+                    (LOCATION, "https://login.example.com/"),
+                    (CONTENT_LENGTH, "0"),
+                ],
+            )
+                .into_response()
+        }
+    }
+
+    async fn forward_auth() -> (ForwardAuth, Seen) {
+        let seen = Seen::default();
+        let router = Router::new().fallback(service).with_state(seen.clone());
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let url = format!("http://{}/verify", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let config = config::ForwardAuth {
+            copy_headers: vec!["Remote-User".to_owned(), "Remote-Groups".to_owned()],
+            ..Default::default()
+        };
+
+        (ForwardAuth::new(&config, &url).unwrap(), seen)
+    }
+
+    fn headers(authorization: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", HeaderValue::from_static(authorization));
+        headers.insert(HOST, HeaderValue::from_static("app.example.com"));
+        headers.insert(CONTENT_LENGTH, HeaderValue::from_static("5"));
+        headers.insert("connection", HeaderValue::from_static("keep-alive"));
+        headers
+    }
+
+    #[tokio::test]
+    async fn copies_headers_when_allowed() {
+        let (auth, seen) = forward_auth().await;
+
+        let verdict = auth
+            .check(
+                &auth_client(),
+                &Method::POST,
+                &"/a/b?c=d".parse().unwrap(),
+                &headers("good"),
+                "10.0.0.1".parse().unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let Verdict::Allow(copied) = verdict else {
+            panic!("expected the request to be allowed");
+        };
+        assert_eq!(copied.len(), 2);
+        assert_eq!(copied["remote-user"], "alice");
+        assert_eq!(copied["remote-groups"], "admins");
+
+        let seen = seen.lock().unwrap().take().unwrap();
+        assert_eq!(seen["x-forwarded-method"], "POST");
+        assert_eq!(seen["x-forwarded-proto"], "https");
+        assert_eq!(seen["x-forwarded-host"], "app.example.com");
+        assert_eq!(seen["x-forwarded-uri"], "/a/b?c=d");
+        assert_eq!(seen["x-forwarded-for"], "10.0.0.1");
+        assert_eq!(seen["x-original-url"], "https://app.example.com/a/b?c=d");
+        // the client's own headers go along, except those about its body and connection
+        assert_eq!(seen["authorization"], "good");
+        assert!(!seen.contains_key(CONTENT_LENGTH));
+        assert_ne!(seen[HOST], "app.example.com");
+    }
+
+    #[tokio::test]
+    async fn passes_on_denials() {
+        let (auth, _) = forward_auth().await;
+
+        let verdict = auth
+            .check(
+                &auth_client(),
+                &Method::GET,
+                &"/".parse().unwrap(),
+                &headers("bad"),
+                "10.0.0.1".parse().unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let Verdict::Deny(res) = verdict else {
+            panic!("expected the request to be denied");
+        };
+        assert_eq!(res.status(), StatusCode::FOUND);
+        assert_eq!(res.headers()[LOCATION], "https://login.example.com/");
+        assert!(!res.headers().contains_key("remote-user"));
+    }
+
+    #[tokio::test]
+    async fn fails_when_unreachable() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let url = format!("http://{}/verify", listener.local_addr().unwrap());
+        drop(listener);
+
+        let auth = ForwardAuth::new(&config::ForwardAuth::default(), &url).unwrap();
+        let err = auth
+            .check(
+                &auth_client(),
+                &Method::GET,
+                &"/".parse().unwrap(),
+                &HeaderMap::new(),
+                "10.0.0.1".parse().unwrap(),
+            )
+            .await
+            .unwrap_err();
+
+        assert_eq!(err.status(), StatusCode::BAD_GATEWAY);
+    }
+}
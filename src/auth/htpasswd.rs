@@ -0,0 +1,231 @@
+//! Users for basic auth, from an htpasswd style file
+//!
+//! One `user:hash` per line, hashed with bcrypt (`htpasswd -B`) or argon2. Blank lines and lines
+//! starting with `#` are ignored. The file is re-read when it changes.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use argon2::{Argon2, PasswordHash, PasswordVerifier as _};
+use aws_lc_rs::{
+    constant_time::verify_slices_are_equal,
+    digest::{Digest, SHA256, digest},
+};
+use tokio::{fs, task};
+use tracing::{error, warn};
+
+// credentials which verified, so hashing (which is slow on purpose) isn't repeated every request
+const MAX_VERIFIED: usize = 1024;
+
+#[derive(Debug, Default)]
+struct Loaded {
+    modified: Option<SystemTime>,
+    users: HashMap<String, String>,
+    // user -> a digest of the password which verified, and the hash it matched
+    verified: HashMap<String, (Digest, String)>,
+}
+
+#[derive(Debug)]
+pub struct Htpasswd {
+    path: PathBuf,
+    loaded: Mutex<Loaded>,
+}
+
+impl Htpasswd {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            loaded: Mutex::default(),
+        }
+    }
+
+    pub async fn verify(&self, user: &str, password: &str) -> bool {
+        self.reload().await;
+
+        // only a digest is kept, so passwords don't sit around in memory
+        let key = credentials_digest(user, password);
+
+        let hash = {
+            let loaded = self.loaded.lock().unwrap();
+
+            let Some(hash) = loaded.users.get(user) else {
+                return false;
+            };
+
+            if let Some((digest, verified)) = loaded.verified.get(user)
+                && verified == hash
+                && verify_slices_are_equal(digest.as_ref(), key.as_ref()).is_ok()
+            {
+                return true;
+            }
+
+            hash.clone()
+        };
+
+        let password = password.to_owned();
+        let checked = {
+            let hash = hash.clone();
+            task::spawn_blocking(move || verify_hash(&password, &hash)).await
+        };
+
+        if !checked.unwrap_or(false) {
+            return false;
+        }
+
+        let mut loaded = self.loaded.lock().unwrap();
+        if loaded.verified.len() >= MAX_VERIFIED {
+            loaded.verified.clear();
+        }
+        loaded.verified.insert(user.to_owned(), (key, hash));
+
+        true
+    }
+
+    async fn reload(&self) {
+        let modified = match fs::metadata(&self.path).await {
+            Ok(meta) => meta.modified().ok(),
+            Err(e) => {
+                error!("failed to read {}: {e}", self.path.display());
+                return;
+            }
+        };
+
+        {
+            let loaded = self.loaded.lock().unwrap();
+            if loaded.modified.is_some() && loaded.modified == modified {
+                return;
+            }
+        }
+
+        let contents = match fs::read_to_string(&self.path).await {
+            Ok(contents) => contents,
+            Err(e) => {
+                error!("failed to read {}: {e}", self.path.display());
+                return;
+            }
+        };
+
+        *self.loaded.lock().unwrap() = Loaded {
+            modified,
+            users: parse(&contents, &self.path),
+            verified: HashMap::new(),
+        };
+    }
+}
+
+/// The users and their hashes, skipping any which can't be used
+fn parse(contents: &str, path: &Path) -> HashMap<String, String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .filter_map(|line| {
+            let Some((user, hash)) = line.split_once(':') else {
+                warn!("ignoring malformed line in {}", path.display());
+                return None;
+            };
+
+            if !is_supported(hash) {
+                warn!(
+                    "ignoring {user} in {}: only bcrypt and argon2 hashes are supported",
+                    path.display()
+                );
+                return None;
+            }
+
+            Some((user.to_owned(), hash.to_owned()))
+        })
+        .collect()
+}
+
+fn is_supported(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$", "$argon2"]
+        .iter()
+        .any(|prefix| hash.starts_with(prefix))
+}
+
+fn credentials_digest(user: &str, password: &str) -> Digest {
+    let mut credentials = Vec::with_capacity(user.len() + password.len() + 1);
+    credentials.extend_from_slice(user.as_bytes());
+    credentials.push(0);
+    credentials.extend_from_slice(password.as_bytes());
+
+    digest(&SHA256, &credentials)
+}
+
+fn verify_hash(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        PasswordHash::new(hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+    } else {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: &str = "$2b$04$kH8iTjnH57Y33BQ07eYtyeWOkGJ5VysStKmgokBHNV4skNjQPsyIe";
+    const BOB: &str = "$argon2id$v=19$m=19456,t=2,p=1$c29tZXNhbHRzb21lc2FsdA$wc8mWaN9GcJFF4O+MXKHCgW6pOYRKD+LbemDRxs5MO8";
+
+    #[test]
+    fn parses_users() {
+        let contents = format!(
+            "# comment\n\n  alice:{ALICE}  \nbob:{BOB}\nmalformed\ncarol:$apr1$abc$def\ndave:{{SHA}}abc\n"
+        );
+        let users = parse(&contents, Path::new("htpasswd"));
+
+        assert_eq!(users.len(), 2);
+        assert_eq!(users["alice"], ALICE);
+        assert_eq!(users["bob"], BOB);
+    }
+
+    #[test]
+    fn verifies_hashes() {
+        assert!(verify_hash("secret", ALICE));
+        assert!(!verify_hash("wrong", ALICE));
+        assert!(verify_hash("pw2", BOB));
+        assert!(!verify_hash("wrong", BOB));
+        assert!(!verify_hash("secret", "$2b$04$garbage"));
+    }
+
+    #[tokio::test]
+    async fn verifies_users_from_file() {
+        let path = std::env::temp_dir().join(format!("htpasswd-test-{}", std::process::id()));
+        std::fs::write(&path, format!("alice:{ALICE}\nbob:{BOB}\n")).unwrap();
+
+        let htpasswd = Htpasswd::new(path.clone());
+        assert!(htpasswd.verify("alice", "secret").await);
+        // a second time, from what was verified already
+        assert!(htpasswd.verify("alice", "secret").await);
+        assert!(!htpasswd.verify("alice", "pw2").await);
+        assert!(htpasswd.verify("bob", "pw2").await);
+        assert!(!htpasswd.verify("carol", "secret").await);
+
+        let loaded = htpasswd.loaded.lock().unwrap();
+        let (digest, hash) = &loaded.verified["alice"];
+        assert_eq!(
+            digest.as_ref(),
+            credentials_digest("alice", "secret").as_ref()
+        );
+        assert_eq!(hash, ALICE);
+        drop(loaded);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn digests_keep_user_and_password_apart() {
+        let a = credentials_digest("ab", "c");
+        let b = credentials_digest("a", "bc");
+        assert_ne!(a.as_ref(), b.as_ref());
+    }
+}
@@ -0,0 +1,851 @@
+//! OpenID Connect login, with the authorization code flow and PKCE
+
+mod jwt;
+mod session;
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use aws_lc_rs::digest::{SHA256, digest};
+use axum::{
+    body::{self, Body},
+    http::{
+        HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode,
+        header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE},
+    },
+};
+use base64::{
+    Engine as _,
+    prelude::{BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD},
+};
+use serde::{Deserialize, de::DeserializeOwned};
+use serde_json::{Map, Value};
+use snafu::{OptionExt, ResultExt, Snafu};
+use tokio::{
+    sync::{OnceCell, RwLock},
+    time,
+};
+use tracing::info;
+use url::{Url, form_urlencoded};
+
+use super::AuthClient;
+use crate::config;
+use jwt::{Jwks, TokenError};
+use session::{CookieKey, now, random_token, set_cookie};
+pub use session::{Login, Session, clear_cookie, get_cookie, strip_cookies};
+
+/// Id tokens are accepted this many seconds past their expiry, for clock skew
+const LEEWAY: u64 = 60;
+/// How long the issuer has to send the user back
+const LOGIN_LIFETIME: u64 = 10 * 60;
+/// The jwks isn't refetched for unknown keys more often than this
+const JWKS_REFETCH: Duration = Duration::from_secs(60);
+/// Concurrent refreshes of a session share the result for this long
+const REFRESH_SHARED: Duration = Duration::from_secs(60);
+/// How long tokens are assumed to last, if the issuer doesn't say
+const DEFAULT_EXPIRY: u64 = 5 * 60;
+/// Largest issuer response we'll read
+const MAX_RESPONSE: usize = 1024 * 1024;
+
+#[derive(Debug, Snafu)]
+pub enum OidcError {
+    #[snafu(display("invalid oidc issuer: {source}"))]
+    Issuer { source: url::ParseError },
+    #[snafu(display("oidc client_id is required"))]
+    ClientId,
+    #[snafu(display("invalid oidc claim header: {source}"))]
+    Header {
+        source: axum::http::header::InvalidHeaderName,
+    },
+    #[snafu(display("failed to build issuer request: {source}"))]
+    Build { source: axum::http::Error },
+    #[snafu(display("issuer error: {source}"))]
+    Request {
+        source: hyper_util::client::legacy::Error,
+    },
+    #[snafu(display("issuer didn't respond in time"))]
+    Timeout,
+    #[snafu(display("{url} responded {status}: {body}"))]
+    Status {
+        url: String,
+        status: StatusCode,
+        body: String,
+    },
+    #[snafu(display("failed to read issuer response: {source}"))]
+    Body { source: axum::Error },
+    #[snafu(display("bad issuer response: {source}"))]
+    Json { source: serde_json::Error },
+    #[snafu(display("bad issuer endpoint: {source}"))]
+    Endpoint { source: url::ParseError },
+    #[snafu(display("discovered issuer {found} doesn't match {expected}"))]
+    IssuerMismatch { expected: String, found: String },
+    #[snafu(display("issuer didn't send an id token"))]
+    NoIdToken,
+    #[snafu(display("invalid id token: {source}"))]
+    Token { source: TokenError },
+    #[snafu(display("invalid id token: {reason}"))]
+    Claims { reason: &'static str },
+}
+
+impl OidcError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+/// The issuer's endpoints, from discovery
+#[derive(Debug, Deserialize)]
+struct Provider {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+    end_session_endpoint: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: Option<String>,
+    refresh_token: Option<String>,
+    expires_in: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct KeyCache {
+    jwks: Jwks,
+    fetched: Option<Instant>,
+}
+
+type Refresh = Arc<OnceCell<Option<Session>>>;
+
+#[derive(Debug)]
+pub struct Oidc {
+    discovery: String,
+    client_id: String,
+    client_secret: Option<String>,
+    scopes: String,
+    claim_headers: Vec<(HeaderName, String)>,
+    key: CookieKey,
+    lifetime: u64,
+    timeout: Duration,
+    provider: OnceCell<Provider>,
+    keys: RwLock<KeyCache>,
+    /// refreshes by refresh token, so concurrent requests with the same expired session only
+    /// refresh once. Otherwise issuers which rotate refresh tokens would reject all but one
+    refreshes: Mutex<HashMap<String, (Instant, Refresh)>>,
+}
+
+impl Oidc {
+    pub fn new(config: &config::Oidc, issuer: &str) -> Result<Self, OidcError> {
+        let issuer = Url::parse(issuer).context(IssuerSnafu)?;
+
+        if config.client_id.is_empty() {
+            return ClientIdSnafu.fail();
+        }
+
+        let claim_headers = config
+            .claim_headers
+            .iter()
+            .map(|(header, claim)| Ok((HeaderName::try_from(header)?, claim.clone())))
+            .collect::<Result<_, _>>()
+            .context(HeaderSnafu)?;
+
+        Ok(Self {
+            discovery: format!(
+                "{}/.well-known/openid-configuration",
+                issuer.as_str().trim_end_matches('/')
+            ),
+            client_id: config.client_id.clone(),
+            client_secret: config.client_secret.clone(),
+            scopes: config.scopes.join(" "),
+            claim_headers,
+            key: CookieKey::new(config.session_secret.as_deref()),
+            lifetime: config.session_lifetime,
+            timeout: Duration::from_secs(config.timeout),
+            provider: OnceCell::new(),
+            keys: RwLock::default(),
+            refreshes: Mutex::default(),
+        })
+    }
+
+    /// Headers which only we may set
+    pub fn claim_headers(&self) -> impl Iterator<Item = &HeaderName> {
+        self.claim_headers.iter().map(|(header, _)| header)
+    }
+
+    /// The identity headers for a session
+    pub fn headers(&self, session: &Session) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        for (header, claim) in &self.claim_headers {
+            if let Some(value) = session.claims.get(claim)
+                && let Ok(value) = HeaderValue::from_str(value)
+            {
+                headers.insert(header.clone(), value);
+            }
+        }
+
+        headers
+    }
+
+    pub fn open_session(&self, name: &str, cookie: &str) -> Option<Session> {
+        self.key
+            .open::<Session>(name, cookie)
+            .filter(|s| s.created + self.lifetime > now())
+    }
+
+    pub fn seal_session(&self, name: &str, session: &Session) -> Option<HeaderValue> {
+        let value = self.key.seal(name, session)?;
+        let max_age = (session.created + self.lifetime).saturating_sub(now());
+
+        set_cookie(name, &value, max_age)
+    }
+
+    pub fn seal_login(&self, name: &str, login: &Login) -> Option<HeaderValue> {
+        set_cookie(name, &self.key.seal(name, login)?, LOGIN_LIFETIME)
+    }
+
+    pub fn open_login(&self, name: &str, cookie: &str) -> Option<Login> {
+        self.key
+            .open::<Login>(name, cookie)
+            .filter(|l| l.created + LOGIN_LIFETIME > now())
+    }
+
+    /// Starts a login, returning where to send the user and the login to remember until they're back
+    pub async fn login(
+        &self,
+        client: &AuthClient,
+        redirect_uri: String,
+        return_to: String,
+    ) -> Result<(String, Login), OidcError> {
+        let provider = self.provider(client).await?;
+
+        let login = Login {
+            state: random_token(),
+            nonce: random_token(),
+            verifier: random_token(),
+            redirect_uri,
+            return_to,
+            created: now(),
+        };
+
+        let challenge = BASE64_URL_SAFE_NO_PAD.encode(digest(&SHA256, login.verifier.as_bytes()));
+
+        let mut url = Url::parse(&provider.authorization_endpoint).context(EndpointSnafu)?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", &login.redirect_uri)
+            .append_pair("scope", &self.scopes)
+            .append_pair("state", &login.state)
+            .append_pair("nonce", &login.nonce)
+            .append_pair("code_challenge", &challenge)
+            .append_pair("code_challenge_method", "S256");
+
+        Ok((url.into(), login))
+    }
+
+    /// Exchanges the code from the callback for tokens
+    pub async fn finish_login(
+        &self,
+        client: &AuthClient,
+        code: &str,
+        login: &Login,
+    ) -> Result<Session, OidcError> {
+        let tokens = self
+            .token_request(
+                client,
+                &[
+                    ("grant_type", "authorization_code"),
+                    ("code", code),
+                    ("redirect_uri", &login.redirect_uri),
+                    ("code_verifier", &login.verifier),
+                ],
+            )
+            .await?;
+
+        let id_token = tokens.id_token.as_deref().context(NoIdTokenSnafu)?;
+        let claims = self
+            .verify_id_token(client, id_token, Some(&login.nonce))
+            .await?;
+
+        Ok(Session {
+            claims: self.pick_claims(&claims),
+            refresh_token: tokens.refresh_token,
+            expires: expiry(tokens.expires_in, &claims),
+            created: now(),
+        })
+    }
+
+    /// Gets fresh tokens for an expired session. None if the session can't be refreshed, and the
+    /// user has to log in again
+    pub async fn refresh(&self, client: &AuthClient, session: &Session) -> Option<Session> {
+        let refresh_token = session.refresh_token.as_ref()?;
+
+        let refresh = {
+            let mut refreshes = self.refreshes.lock().unwrap();
+            refreshes.retain(|_, (started, _)| started.elapsed() < REFRESH_SHARED);
+
+            refreshes
+                .entry(refresh_token.clone())
+                .or_insert_with(|| (Instant::now(), Refresh::default()))
+                .1
+                .clone()
+        };
+
+        refresh
+            .get_or_init(|| async {
+                match self.refresh_tokens(client, session, refresh_token).await {
+                    Ok(session) => Some(session),
+                    Err(e) => {
+                        info!("failed to refresh oidc session: {e}");
+                        None
+                    }
+                }
+            })
+            .await
+            .clone()
+    }
+
+    async fn refresh_tokens(
+        &self,
+        client: &AuthClient,
+        session: &Session,
+        refresh_token: &str,
+    ) -> Result<Session, OidcError> {
+        let tokens = self
+            .token_request(
+                client,
+                &[
+                    ("grant_type", "refresh_token"),
+                    ("refresh_token", refresh_token),
+                ],
+            )
+            .await?;
+
+        // issuers don't have to send a new id token, in which case the claims stay the same
+        let (claims, expires) = match &tokens.id_token {
+            Some(id_token) => {
+                let claims = self.verify_id_token(client, id_token, None).await?;
+                (
+                    self.pick_claims(&claims),
+                    expiry(tokens.expires_in, &claims),
+                )
+            }
+
+            None => (
+                session.claims.clone(),
+                now() + tokens.expires_in.unwrap_or(DEFAULT_EXPIRY),
+            ),
+        };
+
+        Ok(Session {
+            claims,
+            // rotated refresh tokens replace the old one
+            refresh_token: tokens.refresh_token.or(session.refresh_token.clone()),
+            expires,
+            created: session.created,
+        })
+    }
+
+    /// Where to send the user to log out at the issuer too, if it supports that
+    pub async fn logout_url(
+        &self,
+        client: &AuthClient,
+        post_logout_redirect_uri: &str,
+    ) -> Option<String> {
+        let endpoint = self
+            .provider(client)
+            .await
+            .ok()?
+            .end_session_endpoint
+            .as_ref()?;
+
+        let mut url = Url::parse(endpoint).ok()?;
+        url.query_pairs_mut()
+            .append_pair("client_id", &self.client_id)
+            .append_pair("post_logout_redirect_uri", post_logout_redirect_uri);
+
+        Some(url.into())
+    }
+
+    async fn provider(&self, client: &AuthClient) -> Result<&Provider, OidcError> {
+        // a failed discovery is retried on the next login
+        self.provider
+            .get_or_try_init(|| async {
+                let provider = self.get_json::<Provider>(client, &self.discovery).await?;
+
+                let expected = self
+                    .discovery
+                    .trim_end_matches("/.well-known/openid-configuration");
+                if provider.issuer.trim_end_matches('/') != expected {
+                    return IssuerMismatchSnafu {
+                        expected,
+                        found: provider.issuer,
+                    }
+                    .fail();
+                }
+
+                Ok(provider)
+            })
+            .await
+    }
+
+    async fn verify_id_token(
+        &self,
+        client: &AuthClient,
+        token: &str,
+        nonce: Option<&str>,
+    ) -> Result<Map<String, Value>, OidcError> {
+        let provider = self.provider(client).await?;
+
+        let verified = self.keys.read().await.jwks.verify(token);
+        let claims = match verified {
+            // the issuer may have rotated its keys, possibly reusing key ids
+            Err(TokenError::UnknownKey | TokenError::Signature) => {
+                let mut keys = self.keys.write().await;
+                if keys.fetched.is_none_or(|f| f.elapsed() > JWKS_REFETCH) {
+                    keys.jwks = self.get_json(client, &provider.jwks_uri).await?;
+                    keys.fetched = Some(Instant::now());
+                }
+
+                keys.jwks.verify(token)
+            }
+
+            verified => verified,
+        }
+        .context(TokenSnafu)?;
+
+        let claim = |name: &str| claims.get(name).and_then(Value::as_str);
+
+        if claim("iss") != Some(provider.issuer.as_str()) {
+            return ClaimsSnafu {
+                reason: "wrong issuer",
+            }
+            .fail();
+        }
+
+        let audience = match claims.get("aud") {
+            Some(Value::String(aud)) => aud == &self.client_id,
+            Some(Value::Array(aud)) => aud.iter().any(|a| a.as_str() == Some(&self.client_id)),
+            _ => false,
+        };
+        if !audience || claim("azp").is_some_and(|azp| azp != self.client_id) {
+            return ClaimsSnafu {
+                reason: "wrong audience",
+            }
+            .fail();
+        }
+
+        let exp = claims
+            .get("exp")
+            .and_then(Value::as_u64)
+            .unwrap_or_default();
+        if exp + LEEWAY < now() {
+            return ClaimsSnafu { reason: "expired" }.fail();
+        }
+
+        if let Some(nonce) = nonce
+            && claim("nonce") != Some(nonce)
+        {
+            return ClaimsSnafu {
+                reason: "wrong nonce",
+            }
+            .fail();
+        }
+
+        Ok(claims)
+    }
+
+    fn pick_claims(&self, claims: &Map<String, Value>) -> HashMap<String, String> {
+        self.claim_headers
+            .iter()
+            .filter_map(|(_, name)| {
+                let value = match claims.get(name)? {
+                    Value::String(s) => s.clone(),
+                    Value::Array(items) => items
+                        .iter()
+                        .filter_map(|v| match v {
+                            Value::String(s) => Some(s.clone()),
+                            Value::Number(_) | Value::Bool(_) => Some(v.to_string()),
+                            _ => None,
+                        })
+                        .collect::<Vec<_>>()
+                        .join(","),
+                    v @ (Value::Number(_) | Value::Bool(_)) => v.to_string(),
+                    _ => return None,
+                };
+
+                Some((name.clone(), value))
+            })
+            .collect()
+    }
+
+    async fn token_request(
+        &self,
+        client: &AuthClient,
+        params: &[(&str, &str)],
+    ) -> Result<TokenResponse, OidcError> {
+        let provider = self.provider(client).await?;
+
+        let mut req = Request::builder()
+            .method(Method::POST)
+            .uri(&provider.token_endpoint)
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(ACCEPT, "application/json");
+
+        // client_secret_basic, and public clients identify themselves in the body
+        if let Some(secret) = &self.client_secret {
+            let encode =
+                |s: &str| form_urlencoded::byte_serialize(s.as_bytes()).collect::<String>();
+            let credentials = format!("{}:{}", encode(&self.client_id), encode(secret));
+            req = req.header(
+                AUTHORIZATION,
+                format!("Basic {}", BASE64_STANDARD.encode(credentials)),
+            );
+        }
+
+        let client_id = self
+            .client_secret
+            .is_none()
+            .then_some(("client_id", self.client_id.as_str()));
+
+        let form = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(params.iter().copied().chain(client_id))
+            .finish();
+
+        let req = req.body(Body::from(form)).context(BuildSnafu)?;
+
+        self.send(client, req).await
+    }
+
+    async fn get_json<T: DeserializeOwned>(
+        &self,
+        client: &AuthClient,
+        url: &str,
+    ) -> Result<T, OidcError> {
+        let req = Request::builder()
+            .uri(url)
+            .header(ACCEPT, "application/json")
+            .body(Body::empty())
+            .context(BuildSnafu)?;
+
+        self.send(client, req).await
+    }
+
+    async fn send<T: DeserializeOwned>(
+        &self,
+        client: &AuthClient,
+        req: Request<Body>,
+    ) -> Result<T, OidcError> {
+        let url = req.uri().to_string();
+
+        let res = time::timeout(self.timeout, client.request(req))
+            .await
+            .map_err(|_| OidcError::Timeout)?
+            .context(RequestSnafu)?;
+
+        let status = res.status();
+        let body = time::timeout(
+            self.timeout,
+            body::to_bytes(Body::new(res.into_body()), MAX_RESPONSE),
+        )
+        .await
+        .map_err(|_| OidcError::Timeout)?
+        .context(BodySnafu)?;
+
+        if !status.is_success() {
+            let body = String::from_utf8_lossy(&body)
+                .chars()
+                .take(200)
+                .collect::<String>();
+            return StatusSnafu { url, status, body }.fail();
+        }
+
+        serde_json::from_slice(&body).context(JsonSnafu)
+    }
+}
+
+/// Tokens expire with the access token, or the id token if the issuer doesn't say
+fn expiry(expires_in: Option<u64>, claims: &Map<String, Value>) -> u64 {
+    match expires_in {
+        Some(expires_in) => now() + expires_in,
+        None => claims
+            .get("exp")
+            .and_then(Value::as_u64)
+            .unwrap_or_else(now),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use axum::{
+        Form, Json, Router,
+        extract::State,
+        routing::{get, post},
+    };
+    use serde_json::json;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::auth::auth_client;
+    use jwt::tests::Signer;
+
+    /// Just enough of an issuer to log in with
+    struct Issuer {
+        url: String,
+        jwks: Value,
+        id_token: Mutex<String>,
+        // the last token request
+        form: Mutex<HashMap<String, String>>,
+    }
+
+    async fn discovery(State(issuer): State<Arc<Issuer>>) -> Json<Value> {
+        let url = &issuer.url;
+
+        Json(json!({
+            "issuer": url,
+            "authorization_endpoint": format!("{url}/authorize"),
+            "token_endpoint": format!("{url}/token"),
+            "jwks_uri": format!("{url}/jwks"),
+        }))
+    }
+
+    async fn token(
+        State(issuer): State<Arc<Issuer>>,
+        Form(form): Form<HashMap<String, String>>,
+    ) -> Json<Value> {
+        *issuer.form.lock().unwrap() = form;
+
+        Json(json!({
+            "id_token": *issuer.id_token.lock().unwrap(),
+            "refresh_token": "refresh",
+            "expires_in": 300,
+        }))
+    }
+
+    async fn issuer() -> (Oidc, Arc<Issuer>, Signer) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let signer = Signer::new();
+        let issuer = Arc::new(Issuer {
+            url: url.clone(),
+            jwks: signer.jwks(),
+            id_token: Mutex::default(),
+            form: Mutex::default(),
+        });
+
+        let router = Router::new()
+            .route("/.well-known/openid-configuration", get(discovery))
+            .route(
+                "/jwks",
+                get(|State(i): State<Arc<Issuer>>| async move { Json(i.jwks.clone()) }),
+            )
+            .route("/token", post(token))
+            .with_state(issuer.clone());
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let config = config::Oidc {
+            client_id: "app".to_owned(),
+            scopes: vec!["openid".to_owned()],
+            session_secret: Some("secret".to_owned()),
+            session_lifetime: 60 * 60,
+            claim_headers: HashMap::from([
+                ("Remote-User".to_owned(), "preferred_username".to_owned()),
+                ("Remote-Groups".to_owned(), "groups".to_owned()),
+            ]),
+            timeout: 5,
+            ..Default::default()
+        };
+
+        (Oidc::new(&config, &url).unwrap(), issuer, signer)
+    }
+
+    fn claims(issuer: &Issuer, nonce: &str) -> Value {
+        json!({
+            "iss": issuer.url,
+            "aud": "app",
+            "sub": "1",
+            "exp": now() + 300,
+            "nonce": nonce,
+            "preferred_username": "alice",
+            "groups": ["admins", "users"],
+        })
+    }
+
+    fn cookie_value(cookie: &HeaderValue) -> &str {
+        let (_, value) = cookie.to_str().unwrap().split_once('=').unwrap();
+        value.split(';').next().unwrap()
+    }
+
+    #[tokio::test]
+    async fn logins_use_state_and_pkce() {
+        let (oidc, issuer, _) = issuer().await;
+        let client = auth_client();
+
+        let (location, login) = oidc
+            .login(&client, "https://app/cb".to_owned(), "/x?y=z".to_owned())
+            .await
+            .unwrap();
+
+        let location = Url::parse(&location).unwrap();
+        assert!(
+            location
+                .as_str()
+                .starts_with(&format!("{}/authorize?", issuer.url))
+        );
+
+        let query = location
+            .query_pairs()
+            .into_owned()
+            .collect::<HashMap<_, _>>();
+        assert_eq!(query["response_type"], "code");
+        assert_eq!(query["client_id"], "app");
+        assert_eq!(query["redirect_uri"], "https://app/cb");
+        assert_eq!(query["scope"], "openid");
+        assert_eq!(query["state"], login.state);
+        assert_eq!(query["nonce"], login.nonce);
+        assert_eq!(query["code_challenge_method"], "S256");
+        assert_eq!(
+            query["code_challenge"],
+            BASE64_URL_SAFE_NO_PAD.encode(digest(&SHA256, login.verifier.as_bytes()))
+        );
+        // the verifier itself stays with us
+        assert!(!location.as_str().contains(&login.verifier));
+
+        let cookie = oidc.seal_login("session_login", &login).unwrap();
+        let opened = oidc
+            .open_login("session_login", cookie_value(&cookie))
+            .unwrap();
+        assert_eq!(opened.state, login.state);
+        assert_eq!(opened.verifier, login.verifier);
+        assert_eq!(opened.return_to, "/x?y=z");
+        assert!(oidc.open_login("session", cookie_value(&cookie)).is_none());
+    }
+
+    #[tokio::test]
+    async fn finishes_logins() {
+        let (oidc, issuer, signer) = issuer().await;
+        let client = auth_client();
+
+        let (_, login) = oidc
+            .login(&client, "https://app/cb".to_owned(), "/".to_owned())
+            .await
+            .unwrap();
+        *issuer.id_token.lock().unwrap() = signer.token(&claims(&issuer, &login.nonce));
+
+        let session = oidc.finish_login(&client, "code", &login).await.unwrap();
+
+        let form = issuer.form.lock().unwrap().clone();
+        assert_eq!(form["grant_type"], "authorization_code");
+        assert_eq!(form["code"], "code");
+        assert_eq!(form["code_verifier"], login.verifier);
+        assert_eq!(form["redirect_uri"], "https://app/cb");
+        // a public client, so it says who it is
+        assert_eq!(form["client_id"], "app");
+
+        assert_eq!(session.refresh_token.as_deref(), Some("refresh"));
+        assert!(!session.expired());
+
+        let headers = oidc.headers(&session);
+        assert_eq!(headers["remote-user"], "alice");
+        assert_eq!(headers["remote-groups"], "admins,users");
+
+        let cookie = oidc.seal_session("session", &session).unwrap();
+        let opened = oidc.open_session("session", cookie_value(&cookie)).unwrap();
+        assert_eq!(opened.claims, session.claims);
+        assert!(
+            oidc.open_session("session_login", cookie_value(&cookie))
+                .is_none()
+        );
+
+        // past the session lifetime it's no good, however fresh its tokens
+        let old = Session {
+            created: now() - 2 * 60 * 60,
+            ..session
+        };
+        let cookie = oidc.key.seal("session", &old).unwrap();
+        assert!(oidc.open_session("session", &cookie).is_none());
+    }
+
+    #[tokio::test]
+    async fn validates_id_tokens() {
+        let (oidc, issuer, signer) = issuer().await;
+        let client = auth_client();
+        let nonce = "nonce";
+
+        let verify = |claims: Value| {
+            let token = signer.token(&claims);
+            let oidc = &oidc;
+            let client = &client;
+            async move { oidc.verify_id_token(client, &token, Some(nonce)).await }
+        };
+
+        let reason = |result: Result<Map<String, Value>, OidcError>| match result {
+            Err(OidcError::Claims { reason }) => reason,
+            result => panic!("expected bad claims, got {result:?}"),
+        };
+
+        let valid = claims(&issuer, nonce);
+        assert!(verify(valid.clone()).await.is_ok());
+
+        let mut claims = valid.clone();
+        claims["aud"] = json!(["other", "app"]);
+        assert!(verify(claims).await.is_ok());
+
+        // within the leeway
+        let mut claims = valid.clone();
+        claims["exp"] = json!(now() - LEEWAY / 2);
+        assert!(verify(claims).await.is_ok());
+
+        let mut claims = valid.clone();
+        claims["aud"] = json!("other");
+        assert_eq!(reason(verify(claims).await), "wrong audience");
+
+        let mut claims = valid.clone();
+        claims["aud"] = json!(["other"]);
+        assert_eq!(reason(verify(claims).await), "wrong audience");
+
+        let mut claims = valid.clone();
+        claims.as_object_mut().unwrap().remove("aud");
+        assert_eq!(reason(verify(claims).await), "wrong audience");
+
+        let mut claims = valid.clone();
+        claims["azp"] = json!("other");
+        assert_eq!(reason(verify(claims).await), "wrong audience");
+
+        let mut claims = valid.clone();
+        claims["exp"] = json!(now() - LEEWAY * 2);
+        assert_eq!(reason(verify(claims).await), "expired");
+
+        let mut claims = valid.clone();
+        claims.as_object_mut().unwrap().remove("exp");
+        assert_eq!(reason(verify(claims).await), "expired");
+
+        let mut claims = valid.clone();
+        claims["iss"] = json!("https://evil.example.com");
+        assert_eq!(reason(verify(claims).await), "wrong issuer");
+
+        let mut claims = valid.clone();
+        claims["nonce"] = json!("other");
+        assert_eq!(reason(verify(claims).await), "wrong nonce");
+
+        // signed by a key the issuer doesn't have
+        let token = Signer::new().token(&valid);
+        assert!(matches!(
+            oidc.verify_id_token(&client, &token, Some(nonce)).await,
+            Err(OidcError::Token {
+                source: TokenError::Signature
+            })
+        ));
+    }
+}
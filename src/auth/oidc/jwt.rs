@@ -0,0 +1,278 @@
+//! Just enough JWS to check id tokens are signed by the issuer
+
+use aws_lc_rs::signature::{
+    self, EcdsaVerificationAlgorithm, RsaParameters, RsaPublicKeyComponents, UnparsedPublicKey,
+};
+use base64::{Engine as _, prelude::BASE64_URL_SAFE_NO_PAD};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use snafu::{OptionExt, ResultExt, Snafu};
+
+#[derive(Debug, Snafu)]
+pub enum TokenError {
+    #[snafu(display("malformed token"))]
+    Malformed,
+    #[snafu(display("malformed token: {source}"))]
+    Json { source: serde_json::Error },
+    #[snafu(display("unsupported signing algorithm {alg}"))]
+    Algorithm { alg: String },
+    #[snafu(display("no key found for the token"))]
+    UnknownKey,
+    #[snafu(display("bad signature"))]
+    Signature,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    #[serde(rename = "use")]
+    use_: Option<String>,
+    // rsa
+    n: Option<String>,
+    e: Option<String>,
+    // ec
+    crv: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Header {
+    alg: String,
+    kid: Option<String>,
+}
+
+enum Algorithm {
+    Rsa(&'static RsaParameters),
+    Ec(&'static EcdsaVerificationAlgorithm, &'static str),
+}
+
+impl Algorithm {
+    fn from_name(alg: &str) -> Option<Self> {
+        let alg = match alg {
+            "RS256" => Self::Rsa(&signature::RSA_PKCS1_2048_8192_SHA256),
+            "RS384" => Self::Rsa(&signature::RSA_PKCS1_2048_8192_SHA384),
+            "RS512" => Self::Rsa(&signature::RSA_PKCS1_2048_8192_SHA512),
+            "PS256" => Self::Rsa(&signature::RSA_PSS_2048_8192_SHA256),
+            "PS384" => Self::Rsa(&signature::RSA_PSS_2048_8192_SHA384),
+            "PS512" => Self::Rsa(&signature::RSA_PSS_2048_8192_SHA512),
+            "ES256" => Self::Ec(&signature::ECDSA_P256_SHA256_FIXED, "P-256"),
+            "ES384" => Self::Ec(&signature::ECDSA_P384_SHA384_FIXED, "P-384"),
+            _ => return None,
+        };
+
+        Some(alg)
+    }
+
+    fn kty(&self) -> &'static str {
+        match self {
+            Self::Rsa(_) => "RSA",
+            Self::Ec(..) => "EC",
+        }
+    }
+}
+
+impl Jwks {
+    /// Checks the token's signature, returning its claims
+    pub fn verify(&self, token: &str) -> Result<Map<String, Value>, TokenError> {
+        let mut parts = token.split('.');
+        let (Some(header), Some(claims), Some(sig), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return MalformedSnafu.fail();
+        };
+
+        let header = serde_json::from_slice::<Header>(&decode(header)?).context(JsonSnafu)?;
+        let alg = Algorithm::from_name(&header.alg).context(AlgorithmSnafu { alg: header.alg })?;
+
+        let key = self
+            .keys
+            .iter()
+            .filter(|k| k.kty == alg.kty() && k.use_.as_deref().is_none_or(|u| u == "sig"))
+            .find(|k| header.kid.is_none() || k.kid == header.kid)
+            .context(UnknownKeySnafu)?;
+
+        // the signature covers the encoded header and claims
+        let message = &token[..header_and_claims_len(token)];
+        let sig = decode(sig)?;
+
+        let verified = match alg {
+            Algorithm::Rsa(params) => {
+                let n = decode(key.n.as_deref().context(MalformedSnafu)?)?;
+                let e = decode(key.e.as_deref().context(MalformedSnafu)?)?;
+
+                RsaPublicKeyComponents { n, e }.verify(params, message.as_bytes(), &sig)
+            }
+
+            Algorithm::Ec(params, crv) => {
+                if key.crv.as_deref() != Some(crv) {
+                    return UnknownKeySnafu.fail();
+                }
+
+                // uncompressed sec1 point
+                let mut point = vec![0x04];
+                point.extend(decode(key.x.as_deref().context(MalformedSnafu)?)?);
+                point.extend(decode(key.y.as_deref().context(MalformedSnafu)?)?);
+
+                UnparsedPublicKey::new(params, point).verify(message.as_bytes(), &sig)
+            }
+        };
+
+        verified.map_err(|_| TokenError::Signature)?;
+
+        serde_json::from_slice(&decode(claims)?).context(JsonSnafu)
+    }
+}
+
+fn header_and_claims_len(token: &str) -> usize {
+    token.rfind('.').unwrap_or_default()
+}
+
+fn decode(part: &str) -> Result<Vec<u8>, TokenError> {
+    BASE64_URL_SAFE_NO_PAD
+        .decode(part)
+        .map_err(|_| TokenError::Malformed)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use aws_lc_rs::{
+        rand::SystemRandom,
+        signature::{ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, KeyPair as _},
+    };
+    use serde_json::json;
+
+    use super::*;
+
+    /// Signs tokens with a P-256 key, as an issuer would
+    pub struct Signer(EcdsaKeyPair);
+
+    impl Signer {
+        pub fn new() -> Self {
+            Self(EcdsaKeyPair::generate(&ECDSA_P256_SHA256_FIXED_SIGNING).unwrap())
+        }
+
+        /// The issuer's jwks, with the key as `k1`
+        pub fn jwks(&self) -> Value {
+            let point = self.0.public_key().as_ref();
+            let (x, y) = point[1..].split_at(32);
+
+            json!({ "keys": [{
+                "kty": "EC",
+                "kid": "k1",
+                "use": "sig",
+                "crv": "P-256",
+                "x": BASE64_URL_SAFE_NO_PAD.encode(x),
+                "y": BASE64_URL_SAFE_NO_PAD.encode(y),
+            }]})
+        }
+
+        pub fn sign(&self, header: &Value, claims: &Value) -> String {
+            let message = format!(
+                "{}.{}",
+                BASE64_URL_SAFE_NO_PAD.encode(header.to_string()),
+                BASE64_URL_SAFE_NO_PAD.encode(claims.to_string())
+            );
+            let sig = self
+                .0
+                .sign(&SystemRandom::new(), message.as_bytes())
+                .unwrap();
+
+            format!("{message}.{}", BASE64_URL_SAFE_NO_PAD.encode(sig))
+        }
+
+        pub fn token(&self, claims: &Value) -> String {
+            self.sign(&json!({ "alg": "ES256", "kid": "k1" }), claims)
+        }
+    }
+
+    fn jwks(signer: &Signer) -> Jwks {
+        serde_json::from_value(signer.jwks()).unwrap()
+    }
+
+    #[test]
+    fn verifies_signed_tokens() {
+        let signer = Signer::new();
+        let claims = json!({ "sub": "alice", "aud": "app" });
+
+        let verified = jwks(&signer).verify(&signer.token(&claims)).unwrap();
+        assert_eq!(Value::Object(verified), claims);
+
+        // without a kid, any key of the right type is tried
+        let token = signer.sign(&json!({ "alg": "ES256" }), &claims);
+        assert!(jwks(&signer).verify(&token).is_ok());
+    }
+
+    #[test]
+    fn rejects_tampered_tokens() {
+        let signer = Signer::new();
+        let token = signer.token(&json!({ "sub": "alice" }));
+        let forged = signer.token(&json!({ "sub": "mallory" }));
+
+        // mallory's claims, alice's signature
+        let (header, _) = token.split_once('.').unwrap();
+        let (_, rest) = forged.split_once('.').unwrap();
+        let (claims, _) = rest.split_once('.').unwrap();
+        let (_, sig) = token.rsplit_once('.').unwrap();
+        let tampered = format!("{header}.{claims}.{sig}");
+
+        assert!(matches!(
+            jwks(&signer).verify(&tampered),
+            Err(TokenError::Signature)
+        ));
+
+        // signed by someone else
+        assert!(matches!(
+            jwks(&Signer::new()).verify(&token),
+            Err(TokenError::Signature)
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_keys_and_algorithms() {
+        let signer = Signer::new();
+        let claims = json!({ "sub": "alice" });
+
+        let token = signer.sign(&json!({ "alg": "ES256", "kid": "k2" }), &claims);
+        assert!(matches!(
+            jwks(&signer).verify(&token),
+            Err(TokenError::UnknownKey)
+        ));
+
+        // there's no P-384 key
+        let token = signer.sign(&json!({ "alg": "ES384", "kid": "k1" }), &claims);
+        assert!(matches!(
+            jwks(&signer).verify(&token),
+            Err(TokenError::UnknownKey)
+        ));
+
+        for alg in ["none", "HS256"] {
+            let token = signer.sign(&json!({ "alg": alg, "kid": "k1" }), &claims);
+            assert!(matches!(
+                jwks(&signer).verify(&token),
+                Err(TokenError::Algorithm { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_tokens() {
+        let signer = Signer::new();
+        let token = signer.token(&json!({ "sub": "alice" }));
+
+        for token in [
+            "",
+            "a.b",
+            &format!("{token}.extra"),
+            &token.replacen('.', ".!", 1),
+        ] {
+            assert!(jwks(&signer).verify(token).is_err(), "{token}");
+        }
+    }
+}
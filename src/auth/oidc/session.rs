@@ -0,0 +1,241 @@
+//! Sessions live entirely in encrypted cookies, so there's nothing to store or clean up
+
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use aws_lc_rs::{
+    aead::{AES_256_GCM, Aad, NONCE_LEN, Nonce, RandomizedNonceKey},
+    digest::{SHA256, digest},
+    rand,
+};
+use axum::http::{HeaderMap, HeaderValue, header::COOKIE};
+use base64::{Engine as _, prelude::BASE64_URL_SAFE_NO_PAD};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+/// A logged in user
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    /// the id token claims used for the identity headers
+    pub claims: HashMap<String, String>,
+    pub refresh_token: Option<String>,
+    /// when the tokens need refreshing, in unix seconds
+    pub expires: u64,
+    /// when the user logged in, in unix seconds
+    pub created: u64,
+}
+
+impl Session {
+    /// The tokens expired, and need refreshing
+    pub fn expired(&self) -> bool {
+        self.expires <= now()
+    }
+}
+
+/// A login which was sent to the issuer, and is waiting for the callback
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Login {
+    pub state: String,
+    pub nonce: String,
+    pub verifier: String,
+    pub redirect_uri: String,
+    /// where to send the user once they're logged in
+    pub return_to: String,
+    pub created: u64,
+}
+
+pub struct CookieKey(RandomizedNonceKey);
+
+impl std::fmt::Debug for CookieKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("CookieKey")
+    }
+}
+
+impl CookieKey {
+    pub fn new(secret: Option<&str>) -> Self {
+        let key = match secret {
+            Some(secret) => digest(&SHA256, secret.as_bytes()).as_ref().to_vec(),
+            None => random_bytes::<32>().to_vec(),
+        };
+
+        Self(RandomizedNonceKey::new(&AES_256_GCM, &key).expect("key is 32 bytes"))
+    }
+
+    /// Encrypts `value` for the cookie `name`
+    pub fn seal<T: Serialize>(&self, name: &str, value: &T) -> Option<String> {
+        let mut data = serde_json::to_vec(value).ok()?;
+        let nonce = self
+            .0
+            .seal_in_place_append_tag(Aad::from(name), &mut data)
+            .ok()?;
+
+        let mut sealed = nonce.as_ref().to_vec();
+        sealed.extend(data);
+
+        Some(BASE64_URL_SAFE_NO_PAD.encode(sealed))
+    }
+
+    /// Decrypts the cookie `name`. The name is authenticated too, so cookies can't be swapped
+    pub fn open<T: DeserializeOwned>(&self, name: &str, cookie: &str) -> Option<T> {
+        let mut data = BASE64_URL_SAFE_NO_PAD.decode(cookie).ok()?;
+        if data.len() < NONCE_LEN {
+            return None;
+        }
+
+        let mut sealed = data.split_off(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(&data).ok()?;
+        let data = self
+            .0
+            .open_in_place(nonce, Aad::from(name), &mut sealed)
+            .ok()?;
+
+        serde_json::from_slice(data).ok()
+    }
+}
+
+pub fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    rand::fill(&mut bytes).expect("system rng failed");
+    bytes
+}
+
+/// Random url safe string, for states, nonces and verifiers
+pub fn random_token() -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(random_bytes::<32>())
+}
+
+pub fn get_cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(';'))
+        .filter_map(|c| c.trim().split_once('='))
+        .find(|(n, _)| *n == name)
+        .map(|(_, value)| value)
+}
+
+/// Removes our cookies, so they aren't sent to the backend
+pub fn strip_cookies(headers: &mut HeaderMap, names: &[&str]) {
+    let cookies = headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(';'))
+        .map(str::trim)
+        .filter(|c| !names.iter().any(|n| c.split('=').next() == Some(n)))
+        .collect::<Vec<_>>()
+        .join("; ");
+
+    let cookies = HeaderValue::from_str(&cookies).ok();
+
+    headers.remove(COOKIE);
+    if let Some(cookies) = cookies.filter(|c| !c.is_empty()) {
+        headers.insert(COOKIE, cookies);
+    }
+}
+
+pub fn set_cookie(name: &str, value: &str, max_age: u64) -> Option<HeaderValue> {
+    let cookie =
+        format!("{name}={value}; Path=/; Max-Age={max_age}; Secure; HttpOnly; SameSite=Lax");
+    HeaderValue::from_str(&cookie).ok()
+}
+
+pub fn clear_cookie(name: &str) -> Option<HeaderValue> {
+    set_cookie(name, "", 0)
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session() -> Session {
+        Session {
+            claims: HashMap::from([("sub".to_owned(), "alice".to_owned())]),
+            refresh_token: Some("refresh".to_owned()),
+            expires: now() + 60,
+            created: now(),
+        }
+    }
+
+    #[test]
+    fn cookies_round_trip() {
+        let key = CookieKey::new(Some("secret"));
+        let sealed = key.seal("session", &session()).unwrap();
+
+        let opened = key.open::<Session>("session", &sealed).unwrap();
+        assert_eq!(opened.claims["sub"], "alice");
+        assert_eq!(opened.refresh_token.as_deref(), Some("refresh"));
+
+        // the same secret opens it after a restart
+        let restarted = CookieKey::new(Some("secret"));
+        assert!(restarted.open::<Session>("session", &sealed).is_some());
+
+        // nonces are random, so the same session seals differently every time
+        assert_ne!(key.seal("session", &session()).unwrap(), sealed);
+    }
+
+    #[test]
+    fn cookies_only_open_as_themselves() {
+        let key = CookieKey::new(Some("secret"));
+        let sealed = key.seal("session", &session()).unwrap();
+
+        assert!(key.open::<Session>("session_login", &sealed).is_none());
+        assert!(
+            CookieKey::new(Some("other"))
+                .open::<Session>("session", &sealed)
+                .is_none()
+        );
+        assert!(
+            CookieKey::new(None)
+                .open::<Session>("session", &sealed)
+                .is_none()
+        );
+
+        let mut tampered = BASE64_URL_SAFE_NO_PAD.decode(&sealed).unwrap();
+        *tampered.last_mut().unwrap() ^= 1;
+        let tampered = BASE64_URL_SAFE_NO_PAD.encode(tampered);
+        assert!(key.open::<Session>("session", &tampered).is_none());
+
+        for garbage in ["", "abc", "!!!"] {
+            assert!(key.open::<Session>("session", garbage).is_none());
+        }
+    }
+
+    #[test]
+    fn reads_and_strips_cookies() {
+        let mut headers = HeaderMap::new();
+        headers.append(COOKIE, HeaderValue::from_static("a=1; session=s"));
+        headers.append(COOKIE, HeaderValue::from_static("session_login=l;b=2"));
+
+        assert_eq!(get_cookie(&headers, "session"), Some("s"));
+        assert_eq!(get_cookie(&headers, "session_login"), Some("l"));
+        assert_eq!(get_cookie(&headers, "b"), Some("2"));
+        assert_eq!(get_cookie(&headers, "sess"), None);
+
+        strip_cookies(&mut headers, &["session", "session_login"]);
+        assert_eq!(headers.get_all(COOKIE).iter().count(), 1);
+        assert_eq!(headers[COOKIE], "a=1; b=2");
+
+        strip_cookies(&mut headers, &["a", "b"]);
+        assert!(!headers.contains_key(COOKIE));
+    }
+
+    #[test]
+    fn expires() {
+        let mut session = session();
+        assert!(!session.expired());
+
+        session.expires = now();
+        assert!(session.expired());
+    }
+}
@@ -0,0 +1,434 @@
+//! Caching of backend responses, in memory or on disk, as RFC 9111 allows a shared cache to
+
+mod body;
+mod disk;
+mod policy;
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    http::{
+        HeaderMap, HeaderName, HeaderValue, StatusCode, Uri,
+        header::{AGE, CONTENT_LENGTH, HOST},
+    },
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use ulid::Ulid;
+
+use crate::{
+    config::{self, CacheRoute, CacheStore},
+    utils::path_matches,
+};
+
+pub use body::TeeBody;
+use disk::Disk;
+pub use policy::{Directives, Policy, age, not_modified, validators, vary_names};
+
+/// What responses are cached under, their variants told apart by Vary
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Key {
+    pub host: String,
+    pub path: String,
+}
+
+impl Key {
+    pub fn new(headers: &HeaderMap, uri: &Uri) -> Self {
+        // http/2 and http/3 requests carry the host in the uri instead
+        let host = headers
+            .get(HOST)
+            .and_then(|h| h.to_str().ok())
+            .or_else(|| uri.authority().map(|a| a.as_str()))
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+
+        let path = uri.path_and_query().map_or("/", |p| p.as_str()).to_owned();
+
+        Self { host, path }
+    }
+}
+
+/// A cached response
+#[derive(Debug)]
+pub struct Entry {
+    // names the files of disk entries
+    id: Ulid,
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    // the request headers Vary names, and the values they had
+    vary: Vec<(HeaderName, Option<HeaderValue>)>,
+    pub policy: Policy,
+    // when the response was received, or last revalidated
+    stored: SystemTime,
+    // how old the response already was then
+    initial_age: Duration,
+    // None when it's on disk
+    body: Option<Bytes>,
+    size: u64,
+    // millis since the cache was created
+    last_used: AtomicU64,
+    revalidating: AtomicBool,
+}
+
+impl Entry {
+    /// A response received just now
+    pub fn new(
+        status: StatusCode,
+        headers: HeaderMap,
+        vary: Vec<(HeaderName, Option<HeaderValue>)>,
+        policy: Policy,
+        initial_age: Duration,
+        body: Option<Bytes>,
+        body_len: u64,
+    ) -> Self {
+        let header_len = headers
+            .iter()
+            .map(|(name, value)| (name.as_str().len() + value.len()) as u64)
+            .sum::<u64>();
+
+        Self {
+            id: Ulid::new(),
+            status,
+            headers,
+            vary,
+            policy,
+            stored: SystemTime::now(),
+            initial_age,
+            body,
+            size: body_len + header_len,
+            last_used: AtomicU64::new(0),
+            revalidating: AtomicBool::new(false),
+        }
+    }
+
+    /// The values of the request headers the response varies by
+    pub fn vary_values(
+        req: &HeaderMap,
+        names: Vec<HeaderName>,
+    ) -> Vec<(HeaderName, Option<HeaderValue>)> {
+        names
+            .into_iter()
+            .map(|name| {
+                let value = req.get(&name).cloned();
+                (name, value)
+            })
+            .collect()
+    }
+
+    pub fn age(&self) -> Duration {
+        let elapsed = SystemTime::now()
+            .duration_since(self.stored)
+            .unwrap_or_default();
+
+        self.initial_age + elapsed
+    }
+
+    /// Seconds left until the response is stale, negative once it is
+    pub fn ttl(&self) -> i64 {
+        (self.policy.fresh_for.as_secs_f64() - self.age().as_secs_f64()).floor() as i64
+    }
+
+    pub fn fresh(&self) -> bool {
+        !self.policy.no_cache && self.age() < self.policy.fresh_for
+    }
+
+    /// Whether the response may be served stale while it's revalidated
+    pub fn stale_while_revalidate(&self) -> bool {
+        !self.policy.no_cache
+            && !self.policy.must_revalidate
+            && self.age() < self.policy.fresh_for + self.policy.stale_while_revalidate
+    }
+
+    /// Whether the response may be served stale when the backend fails
+    pub fn stale_if_error(&self) -> bool {
+        !self.policy.must_revalidate
+            && self.age() < self.policy.fresh_for + self.policy.stale_if_error
+    }
+
+    /// Claims the background revalidation, so only one happens at a time
+    pub fn start_revalidating(&self) -> bool {
+        !self.revalidating.swap(true, Ordering::AcqRel)
+    }
+
+    pub fn finish_revalidating(&self) {
+        self.revalidating.store(false, Ordering::Release);
+    }
+
+    fn matches(&self, req: &HeaderMap) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| req.get(name) == value.as_ref())
+    }
+
+    /// The cached response, without its body
+    pub fn response(&self, body: Body) -> Response {
+        let mut res = Response::new(body);
+        *res.status_mut() = self.status;
+        *res.headers_mut() = self.headers.clone();
+        res.headers_mut()
+            .insert(AGE, HeaderValue::from(self.age().as_secs()));
+
+        res
+    }
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    map: HashMap<Key, Vec<Arc<Entry>>>,
+    size: u64,
+}
+
+impl Entries {
+    /// Takes out the entries `remove` picks
+    fn remove(&mut self, mut remove: impl FnMut(&Key, &Entry) -> bool) -> Vec<Arc<Entry>> {
+        let mut removed = Vec::new();
+
+        self.map.retain(|key, variants| {
+            variants.retain(|entry| {
+                if remove(key, entry) {
+                    removed.push(entry.clone());
+                    false
+                } else {
+                    true
+                }
+            });
+
+            !variants.is_empty()
+        });
+
+        self.size -= removed.iter().map(|e| e.size).sum::<u64>();
+
+        removed
+    }
+}
+
+#[derive(Debug)]
+pub struct Cache {
+    config: config::Cache,
+    disk: Option<Disk>,
+    entries: Mutex<Entries>,
+    start: Instant,
+}
+
+impl Cache {
+    /// Creates the cache, loading the disk store's entries if that's where they're kept
+    pub fn new(config: &config::Cache, dir: PathBuf) -> io::Result<Self> {
+        let cache = Self {
+            config: config.clone(),
+            disk: None,
+            entries: Mutex::default(),
+            start: Instant::now(),
+        };
+
+        if config.store == CacheStore::Memory {
+            return Ok(cache);
+        }
+
+        let (disk, loaded) = Disk::open(dir)?;
+        let cache = Self {
+            disk: Some(disk),
+            ..cache
+        };
+
+        for (key, entry) in loaded {
+            cache.insert(key, entry);
+        }
+
+        Ok(cache)
+    }
+
+    pub fn config(&self) -> &config::Cache {
+        &self.config
+    }
+
+    /// The most specific route override for the normalized path
+    pub fn route(&self, path: &str) -> Option<&CacheRoute> {
+        self.config
+            .routes
+            .iter()
+            .filter(|r| path_matches(path, &r.path))
+            .max_by_key(|r| r.path.len())
+    }
+
+    /// The cached response for the request, if there is one
+    pub fn lookup(&self, key: &Key, req: &HeaderMap) -> Option<Arc<Entry>> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.map.get(key)?.iter().find(|e| e.matches(req))?;
+
+        entry.last_used.store(self.now(), Ordering::Relaxed);
+
+        Some(entry.clone())
+    }
+
+    /// The entry's body, read from disk if that's where it is
+    pub async fn body(&self, entry: &Entry) -> io::Result<Bytes> {
+        match (&entry.body, &self.disk) {
+            (Some(body), _) => Ok(body.clone()),
+            (None, Some(disk)) => disk.read(entry.id).await,
+            (None, None) => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    /// Stores a response which has just been received in full
+    pub async fn store(&self, key: Key, mut entry: Entry, body: Bytes) {
+        if entry.size > self.config.max_entry_size {
+            return;
+        }
+
+        if let Some(disk) = &self.disk {
+            if let Err(e) = disk.write(&key, &entry, Some(&body)).await {
+                warn!("failed to cache {}{}: {e}", key.host, key.path);
+                disk.remove(entry.id);
+                return;
+            }
+        } else {
+            entry.body = Some(body);
+        }
+
+        self.insert(key, entry);
+    }
+
+    /// Updates an entry with the headers of a 304 the backend revalidated it with, RFC 9111 4.3.4
+    ///
+    /// Returns the updated entry, which is only kept if it can still be cached
+    pub async fn refresh(
+        &self,
+        key: Key,
+        entry: &Entry,
+        ttl: Option<u64>,
+        req: &HeaderMap,
+        authenticated: bool,
+        res: &HeaderMap,
+    ) -> Arc<Entry> {
+        let mut headers = entry.headers.clone();
+        for name in res.keys() {
+            if *name == CONTENT_LENGTH {
+                continue;
+            }
+
+            headers.remove(name);
+            for value in res.get_all(name) {
+                headers.append(name, value.clone());
+            }
+        }
+
+        let initial_age = policy::age(res);
+        headers.remove(AGE);
+
+        let policy = Policy::new(&self.config, ttl, entry.status, req, authenticated, &headers);
+
+        let mut updated = Entry::new(
+            entry.status,
+            headers,
+            entry.vary.clone(),
+            policy.unwrap_or(entry.policy),
+            initial_age,
+            entry.body.clone(),
+            0,
+        );
+        // the body stays where it is
+        updated.id = entry.id;
+        updated.size = entry.size;
+
+        // the backend no longer allows it to be cached, so it's only good for this once
+        if policy.is_none() {
+            updated.body = self.body(entry).await.ok();
+            self.invalidate(&key);
+            return Arc::new(updated);
+        }
+
+        if let Some(disk) = &self.disk
+            && let Err(e) = disk.write(&key, &updated, None).await
+        {
+            warn!("failed to update cached {}{}: {e}", key.host, key.path);
+        }
+
+        self.insert(key, updated)
+    }
+
+    /// Drops every variant of the key
+    pub fn invalidate(&self, key: &Key) {
+        let removed = self.entries.lock().unwrap().remove(|k, _| k == key);
+        self.remove_files(&removed);
+    }
+
+    /// Drops everything under the path prefix, on `host` or any host. Returns how many were dropped
+    pub fn purge(&self, host: Option<&str>, prefix: &str) -> usize {
+        let removed = self.entries.lock().unwrap().remove(|key, _| {
+            host.is_none_or(|h| key.host.eq_ignore_ascii_case(h)) && key.path.starts_with(prefix)
+        });
+        self.remove_files(&removed);
+
+        removed.len()
+    }
+
+    fn insert(&self, key: Key, mut entry: Entry) -> Arc<Entry> {
+        entry.last_used = AtomicU64::new(self.now());
+        let entry = Arc::new(entry);
+
+        let mut removed = Vec::new();
+        {
+            let mut entries = self.entries.lock().unwrap();
+
+            // the variant is replaced, and so are its files unless they're the same ones
+            removed.extend(entries.remove(|k, e| *k == key && e.vary == entry.vary));
+            removed.retain(|e| e.id != entry.id);
+
+            entries.size += entry.size;
+            entries.map.entry(key).or_default().push(entry.clone());
+
+            // least recently used first, down to a little under the limit so it isn't done every time
+            if entries.size > self.config.max_size {
+                let target = self.config.max_size / 10 * 9;
+
+                let mut by_use = entries
+                    .map
+                    .values()
+                    .flatten()
+                    .map(|e| (e.last_used.load(Ordering::Relaxed), e.id, e.size))
+                    .collect::<Vec<_>>();
+                by_use.sort_unstable();
+
+                let mut size = entries.size;
+                let mut evict = HashSet::new();
+                for (_, id, entry_size) in by_use {
+                    if size <= target {
+                        break;
+                    }
+
+                    size -= entry_size;
+                    evict.insert(id);
+                }
+
+                removed.extend(entries.remove(|_, e| evict.contains(&e.id)));
+            }
+        }
+
+        self.remove_files(&removed);
+
+        entry
+    }
+
+    fn remove_files(&self, removed: &[Arc<Entry>]) {
+        if let Some(disk) = &self.disk {
+            for entry in removed {
+                disk.remove(entry.id);
+            }
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+}
@@ -0,0 +1,77 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+
+use axum::body::{Body, Bytes, HttpBody};
+use hyper::body::Frame;
+
+type Complete = Box<dyn FnOnce(Bytes) + Send>;
+
+/// A response body passed on to the client as it streams, and kept to be cached once it's complete
+///
+/// Nothing is kept once the body grows past `max`, or has trailers
+pub struct TeeBody {
+    body: Body,
+    kept: Option<Vec<u8>>,
+    max: usize,
+    complete: Option<Complete>,
+}
+
+impl TeeBody {
+    pub fn new(body: Body, max: usize, complete: impl FnOnce(Bytes) + Send + 'static) -> Self {
+        Self {
+            body,
+            kept: Some(Vec::new()),
+            max,
+            complete: Some(Box::new(complete)),
+        }
+    }
+}
+
+impl HttpBody for TeeBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = &mut *self;
+
+        match ready!(Pin::new(&mut this.body).poll_frame(cx)) {
+            Some(Ok(frame)) => {
+                match (frame.data_ref(), &mut this.kept) {
+                    (Some(data), Some(kept)) if kept.len() + data.len() <= this.max => {
+                        kept.extend_from_slice(data);
+                    }
+                    _ => this.kept = None,
+                }
+
+                Poll::Ready(Some(Ok(frame)))
+            }
+
+            Some(Err(e)) => {
+                this.kept = None;
+                Poll::Ready(Some(Err(e)))
+            }
+
+            None => {
+                if let (Some(kept), Some(complete)) = (this.kept.take(), this.complete.take()) {
+                    complete(Bytes::from(kept));
+                }
+
+                Poll::Ready(None)
+            }
+        }
+    }
+
+    // polled to the end while there's still something to cache, or it'd never be stored
+    fn is_end_stream(&self) -> bool {
+        self.kept.is_none() && self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> hyper::body::SizeHint {
+        self.body.size_hint()
+    }
+}
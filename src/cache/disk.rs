@@ -0,0 +1,193 @@
+//! Cached responses kept on disk, so they survive restarts
+//!
+//! Each response is a `{id}.body` file with a `{id}.json` next to it holding the rest
+
+use std::{
+    fs, io,
+    path::PathBuf,
+    str::FromStr as _,
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    body::Bytes,
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode},
+};
+use serde::{Deserialize, Serialize};
+use tokio::task;
+use tracing::warn;
+use ulid::Ulid;
+
+use super::{Entry, Key, policy::Policy};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Meta {
+    key: Key,
+    status: u16,
+    headers: Vec<(String, String)>,
+    vary: Vec<(String, Option<String>)>,
+    stored: SystemTime,
+    initial_age: Duration,
+    policy: Policy,
+}
+
+#[derive(Debug, Clone)]
+pub struct Disk {
+    dir: PathBuf,
+}
+
+impl Disk {
+    /// Opens the store, loading whatever was cached before
+    pub fn open(dir: PathBuf) -> io::Result<(Self, Vec<(Key, Entry)>)> {
+        fs::create_dir_all(&dir)?;
+
+        let disk = Self { dir };
+        let mut entries = Vec::new();
+
+        for file in fs::read_dir(&disk.dir)? {
+            let path = file?.path();
+
+            let Some(id) = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| Ulid::from_str(s).ok())
+            else {
+                continue;
+            };
+
+            match path.extension().and_then(|e| e.to_str()) {
+                Some("json") => match disk.load(id) {
+                    Ok(entry) => entries.push(entry),
+                    Err(e) => {
+                        warn!("dropping cached response {}: {e}", path.display());
+                        disk.remove(id);
+                    }
+                },
+
+                // left behind by a write that never finished
+                Some("body") if !disk.path(id, "json").exists() => disk.remove(id),
+
+                _ => {}
+            }
+        }
+
+        Ok((disk, entries))
+    }
+
+    fn load(&self, id: Ulid) -> io::Result<(Key, Entry)> {
+        let meta = fs::read(self.path(id, "json"))?;
+        let meta = serde_json::from_slice::<Meta>(&meta).map_err(io::Error::other)?;
+        let size = fs::metadata(self.path(id, "body"))?.len();
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in &meta.headers {
+            headers.append(header_name(name)?, header_value(value)?);
+        }
+
+        let vary = meta
+            .vary
+            .iter()
+            .map(|(name, value)| {
+                Ok((
+                    header_name(name)?,
+                    value.as_deref().map(header_value).transpose()?,
+                ))
+            })
+            .collect::<io::Result<_>>()?;
+
+        let status = StatusCode::from_u16(meta.status).map_err(io::Error::other)?;
+
+        let mut entry = Entry::new(
+            status,
+            headers,
+            vary,
+            meta.policy,
+            meta.initial_age,
+            None,
+            size,
+        );
+        entry.id = id;
+        entry.stored = meta.stored;
+
+        Ok((meta.key, entry))
+    }
+
+    /// Writes the entry, along with its body unless it's only being updated
+    pub async fn write(&self, key: &Key, entry: &Entry, body: Option<&Bytes>) -> io::Result<()> {
+        let meta = Meta {
+            key: key.clone(),
+            status: entry.status.as_u16(),
+            headers: entry
+                .headers
+                .iter()
+                .map(|(name, value)| Ok((name.to_string(), header_str(value)?)))
+                .collect::<io::Result<_>>()?,
+            vary: entry
+                .vary
+                .iter()
+                .map(|(name, value)| {
+                    Ok((
+                        name.to_string(),
+                        value.as_ref().map(header_str).transpose()?,
+                    ))
+                })
+                .collect::<io::Result<_>>()?,
+            stored: entry.stored,
+            initial_age: entry.initial_age,
+            policy: entry.policy,
+        };
+
+        let meta = serde_json::to_vec(&meta).map_err(io::Error::other)?;
+
+        if let Some(body) = body {
+            tokio::fs::write(self.path(entry.id, "body"), body).await?;
+        }
+
+        // the json is what makes an entry, so it's only there once it's complete
+        let tmp = self.path(entry.id, "json.tmp");
+        tokio::fs::write(&tmp, meta).await?;
+        tokio::fs::rename(tmp, self.path(entry.id, "json")).await
+    }
+
+    pub async fn read(&self, id: Ulid) -> io::Result<Bytes> {
+        tokio::fs::read(self.path(id, "body"))
+            .await
+            .map(Bytes::from)
+    }
+
+    /// Removes the entry's files in the background
+    pub fn remove(&self, id: Ulid) {
+        let paths = [self.path(id, "json"), self.path(id, "body")];
+
+        let remove = move || {
+            for path in paths {
+                if let Err(e) = fs::remove_file(&path)
+                    && e.kind() != io::ErrorKind::NotFound
+                {
+                    warn!("failed to remove {}: {e}", path.display());
+                }
+            }
+        };
+
+        task::spawn_blocking(remove);
+    }
+
+    fn path(&self, id: Ulid, extension: &str) -> PathBuf {
+        self.dir.join(format!("{id}.{extension}"))
+    }
+}
+
+fn header_name(name: &str) -> io::Result<HeaderName> {
+    HeaderName::try_from(name).map_err(io::Error::other)
+}
+
+fn header_value(value: &str) -> io::Result<HeaderValue> {
+    HeaderValue::try_from(value).map_err(io::Error::other)
+}
+
+fn header_str(value: &HeaderValue) -> io::Result<String> {
+    value
+        .to_str()
+        .map(str::to_owned)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
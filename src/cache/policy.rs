@@ -0,0 +1,484 @@
+//! What may be cached and for how long, see RFC 9111
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::http::{
+    HeaderMap, HeaderName, HeaderValue, StatusCode,
+    header::{
+        AGE, AUTHORIZATION, CACHE_CONTROL, DATE, ETAG, EXPIRES, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+        LAST_MODIFIED, PRAGMA, SET_COOKIE, VARY,
+    },
+};
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+
+// statuses which may be cached without explicit freshness, RFC 9110 15.1
+const CACHEABLE: &[u16] = &[200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+
+// heuristic freshness is a tenth of the time since the response last changed, up to this
+const MAX_HEURISTIC: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The Cache-Control directives of a request or response
+#[derive(Debug, Default)]
+pub struct Directives {
+    pub no_store: bool,
+    pub no_cache: bool,
+    pub private: bool,
+    pub public: bool,
+    // proxy-revalidate too, this being a shared cache
+    pub must_revalidate: bool,
+    pub only_if_cached: bool,
+    pub max_age: Option<u64>,
+    pub s_maxage: Option<u64>,
+    pub stale_while_revalidate: Option<u64>,
+    pub stale_if_error: Option<u64>,
+}
+
+impl Directives {
+    pub fn parse(headers: &HeaderMap) -> Self {
+        let mut directives = Self::default();
+        let mut any = false;
+
+        for directive in headers
+            .get_all(CACHE_CONTROL)
+            .iter()
+            .filter_map(|c| c.to_str().ok())
+            .flat_map(|c| c.split(','))
+        {
+            any = true;
+
+            let (name, value) = match directive.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (directive.trim(), None),
+            };
+
+            // an invalid number of seconds is treated as 0, ie: stale straight away
+            let secs = || value.map(|v| v.parse::<u64>().unwrap_or(0));
+
+            match name.to_ascii_lowercase().as_str() {
+                "no-store" => directives.no_store = true,
+                // no-cache="field" and private="field" are handled as if they were unqualified
+                "no-cache" => directives.no_cache = true,
+                "private" => directives.private = true,
+                "public" => directives.public = true,
+                "must-revalidate" | "proxy-revalidate" => directives.must_revalidate = true,
+                "only-if-cached" => directives.only_if_cached = true,
+                "max-age" => directives.max_age = secs(),
+                "s-maxage" => directives.s_maxage = secs(),
+                "stale-while-revalidate" => directives.stale_while_revalidate = secs(),
+                "stale-if-error" => directives.stale_if_error = secs(),
+                _ => {}
+            }
+        }
+
+        // http/1.0 clients only know Pragma
+        if !any
+            && headers
+                .get_all(PRAGMA)
+                .iter()
+                .any(|p| p.as_bytes().eq_ignore_ascii_case(b"no-cache"))
+        {
+            directives.no_cache = true;
+        }
+
+        directives
+    }
+}
+
+/// How a stored response may be used
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Policy {
+    pub fresh_for: Duration,
+    pub stale_while_revalidate: Duration,
+    pub stale_if_error: Duration,
+    // never served stale
+    pub must_revalidate: bool,
+    // revalidated before every use
+    pub no_cache: bool,
+}
+
+impl Policy {
+    /// How the response may be cached, None when it can't be
+    ///
+    /// `ttl` is the route's override of the response's freshness, and `authenticated` whether one
+    /// of our auth layers let the request through
+    pub fn new(
+        config: &config::Cache,
+        ttl: Option<u64>,
+        status: StatusCode,
+        req: &HeaderMap,
+        authenticated: bool,
+        res: &HeaderMap,
+    ) -> Option<Self> {
+        let directives = Directives::parse(res);
+        if !storable(status, req, authenticated, res, &directives) {
+            return None;
+        }
+
+        let fresh_for = match ttl {
+            Some(ttl) => Duration::from_secs(ttl),
+            None => freshness(res, &directives)?,
+        };
+
+        Some(Self {
+            fresh_for,
+            stale_while_revalidate: Duration::from_secs(
+                directives
+                    .stale_while_revalidate
+                    .unwrap_or(config.stale_while_revalidate),
+            ),
+            stale_if_error: Duration::from_secs(
+                directives.stale_if_error.unwrap_or(config.stale_if_error),
+            ),
+            must_revalidate: directives.must_revalidate,
+            no_cache: directives.no_cache && ttl.is_none(),
+        })
+    }
+}
+
+/// Whether a shared cache may store the response at all, RFC 9111 3
+fn storable(
+    status: StatusCode,
+    req: &HeaderMap,
+    authenticated: bool,
+    res: &HeaderMap,
+    directives: &Directives,
+) -> bool {
+    if directives.no_store || directives.private {
+        return false;
+    }
+
+    // the same goes for users we authenticated, whose credentials the backend may never see. It
+    // only gets identity headers, so it has to say outright that the response is for everyone
+    if authenticated && !(directives.public || directives.s_maxage.is_some()) {
+        return false;
+    }
+
+    // one user's authorized response mustn't be handed to another, unless the backend says it can
+    if req.contains_key(AUTHORIZATION)
+        && !(directives.public || directives.s_maxage.is_some() || directives.must_revalidate)
+    {
+        return false;
+    }
+
+    // cookies are almost always meant for one user
+    if res.contains_key(SET_COOKIE) {
+        return false;
+    }
+
+    vary_names(res).is_some() && CACHEABLE.contains(&status.as_u16())
+}
+
+/// How long the response is fresh for, None when it has no explicit or heuristic freshness
+fn freshness(res: &HeaderMap, directives: &Directives) -> Option<Duration> {
+    if let Some(secs) = directives.s_maxage.or(directives.max_age) {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let date = res
+        .get(DATE)
+        .and_then(http_date)
+        .unwrap_or_else(SystemTime::now);
+
+    if let Some(expires) = res.get(EXPIRES) {
+        // an invalid date means it's already expired
+        let expires = http_date(expires).unwrap_or(UNIX_EPOCH);
+        return Some(expires.duration_since(date).unwrap_or_default());
+    }
+
+    let last_modified = res.get(LAST_MODIFIED).and_then(http_date)?;
+    let unchanged_for = date.duration_since(last_modified).unwrap_or_default();
+
+    Some((unchanged_for / 10).min(MAX_HEURISTIC))
+}
+
+/// How old the response already was when the backend sent it
+pub fn age(res: &HeaderMap) -> Duration {
+    let secs = res
+        .get(AGE)
+        .and_then(|a| a.to_str().ok())
+        .and_then(|a| a.trim().parse::<u64>().ok())
+        .unwrap_or(0);
+
+    Duration::from_secs(secs)
+}
+
+/// The request headers the response varies by, None for `Vary: *`
+pub fn vary_names(res: &HeaderMap) -> Option<Vec<HeaderName>> {
+    let mut names = Vec::new();
+
+    for name in res
+        .get_all(VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+    {
+        if name == "*" {
+            return None;
+        }
+
+        if let Ok(name) = HeaderName::try_from(name) {
+            names.push(name);
+        }
+    }
+
+    Some(names)
+}
+
+/// Whether the client's conditional headers match the response, so a 304 will do
+pub fn not_modified(req: &HeaderMap, res: &HeaderMap) -> bool {
+    if let Some(tags) = req.get(IF_NONE_MATCH).and_then(|t| t.to_str().ok()) {
+        let Some(etag) = res.get(ETAG).and_then(|e| e.to_str().ok()) else {
+            return false;
+        };
+
+        return tags
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || weak_eq(tag, etag));
+    }
+
+    match (
+        req.get(IF_MODIFIED_SINCE).and_then(http_date),
+        res.get(LAST_MODIFIED).and_then(http_date),
+    ) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+/// The conditional headers to revalidate the response with
+pub fn validators(res: &HeaderMap) -> HeaderMap {
+    let mut validators = HeaderMap::new();
+
+    if let Some(etag) = res.get(ETAG) {
+        validators.insert(IF_NONE_MATCH, etag.clone());
+    }
+
+    if let Some(last_modified) = res.get(LAST_MODIFIED) {
+        validators.insert(IF_MODIFIED_SINCE, last_modified.clone());
+    }
+
+    validators
+}
+
+fn weak_eq(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+fn http_date(value: &HeaderValue) -> Option<SystemTime> {
+    httpdate::parse_http_date(value.to_str().ok()?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn policy(req: &HeaderMap, authenticated: bool, res: &HeaderMap) -> Option<Policy> {
+        Policy::new(
+            &config::Cache::default(),
+            None,
+            StatusCode::OK,
+            req,
+            authenticated,
+            res,
+        )
+    }
+
+    fn fresh_for(res: &[(&'static str, &'static str)]) -> Option<u64> {
+        policy(&HeaderMap::new(), false, &headers(res)).map(|p| p.fresh_for.as_secs())
+    }
+
+    #[test]
+    fn parses_directives() {
+        let directives = Directives::parse(&headers(&[
+            ("cache-control", "Public, max-age=\"60\""),
+            (
+                "cache-control",
+                "s-maxage=x, stale-while-revalidate=5, proxy-revalidate",
+            ),
+        ]));
+
+        assert!(directives.public);
+        assert!(directives.must_revalidate);
+        assert_eq!(directives.max_age, Some(60));
+        // invalid, so stale straight away
+        assert_eq!(directives.s_maxage, Some(0));
+        assert_eq!(directives.stale_while_revalidate, Some(5));
+        assert!(!directives.no_cache);
+
+        assert!(Directives::parse(&headers(&[("pragma", "no-cache")])).no_cache);
+        // Pragma is only for clients which don't know Cache-Control
+        assert!(
+            !Directives::parse(&headers(&[
+                ("pragma", "no-cache"),
+                ("cache-control", "max-age=1")
+            ]))
+            .no_cache
+        );
+    }
+
+    #[test]
+    fn computes_freshness() {
+        assert_eq!(fresh_for(&[("cache-control", "max-age=60")]), Some(60));
+        assert_eq!(
+            fresh_for(&[("cache-control", "max-age=60, s-maxage=120")]),
+            Some(120)
+        );
+        assert_eq!(
+            fresh_for(&[
+                ("date", "Sun, 06 Nov 1994 08:49:37 GMT"),
+                ("expires", "Sun, 06 Nov 1994 08:50:37 GMT"),
+            ]),
+            Some(60)
+        );
+        assert_eq!(
+            fresh_for(&[("date", "Sun, 06 Nov 1994 08:49:37 GMT"), ("expires", "0"),]),
+            Some(0)
+        );
+        // a tenth of the time since it last changed
+        assert_eq!(
+            fresh_for(&[
+                ("date", "Sun, 06 Nov 1994 08:49:37 GMT"),
+                ("last-modified", "Sun, 06 Nov 1994 08:39:37 GMT"),
+            ]),
+            Some(60)
+        );
+        // up to a day
+        assert_eq!(
+            fresh_for(&[
+                ("date", "Sun, 06 Nov 1994 08:49:37 GMT"),
+                ("last-modified", "Tue, 06 Nov 1984 08:49:37 GMT"),
+            ]),
+            Some(MAX_HEURISTIC.as_secs())
+        );
+        // nothing to go on
+        assert_eq!(fresh_for(&[]), None);
+
+        let ttl = Policy::new(
+            &config::Cache::default(),
+            Some(10),
+            StatusCode::OK,
+            &HeaderMap::new(),
+            false,
+            &headers(&[("cache-control", "no-cache")]),
+        )
+        .unwrap();
+        assert_eq!(ttl.fresh_for.as_secs(), 10);
+        assert!(!ttl.no_cache);
+    }
+
+    #[test]
+    fn refuses_what_mustnt_be_stored() {
+        for res in [
+            &[("cache-control", "max-age=60, no-store")][..],
+            &[("cache-control", "max-age=60, private")],
+            &[("cache-control", "max-age=60"), ("set-cookie", "a=1")],
+            &[("cache-control", "max-age=60"), ("vary", "*")],
+        ] {
+            assert_eq!(fresh_for(res), None, "{res:?}");
+        }
+
+        let res = headers(&[("cache-control", "max-age=60")]);
+        let error = Policy::new(
+            &config::Cache::default(),
+            None,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &HeaderMap::new(),
+            false,
+            &res,
+        );
+        assert!(error.is_none());
+    }
+
+    #[test]
+    fn keeps_authorized_responses_private() {
+        let authorization = headers(&[("authorization", "Basic YTpi")]);
+        let none = HeaderMap::new();
+
+        let max_age = headers(&[("cache-control", "max-age=60")]);
+        let public = headers(&[("cache-control", "public, max-age=60")]);
+        let s_maxage = headers(&[("cache-control", "s-maxage=60")]);
+        let must_revalidate = headers(&[("cache-control", "max-age=60, must-revalidate")]);
+
+        assert!(policy(&none, false, &max_age).is_some());
+
+        assert!(policy(&authorization, false, &max_age).is_none());
+        assert!(policy(&authorization, false, &public).is_some());
+        assert!(policy(&authorization, false, &s_maxage).is_some());
+        assert!(policy(&authorization, false, &must_revalidate).is_some());
+
+        // the backend may only have seen identity headers, so it has to say it's for everyone
+        assert!(policy(&none, true, &max_age).is_none());
+        assert!(policy(&none, true, &must_revalidate).is_none());
+        assert!(policy(&none, true, &public).is_some());
+        assert!(policy(&none, true, &s_maxage).is_some());
+    }
+
+    #[test]
+    fn matches_conditions() {
+        let res = headers(&[
+            ("etag", "W/\"v1\""),
+            ("last-modified", "Sun, 06 Nov 1994 08:49:37 GMT"),
+        ]);
+
+        assert!(not_modified(
+            &headers(&[("if-none-match", "\"v0\", \"v1\"")]),
+            &res
+        ));
+        assert!(not_modified(&headers(&[("if-none-match", "*")]), &res));
+        assert!(!not_modified(
+            &headers(&[("if-none-match", "\"v2\"")]),
+            &res
+        ));
+        // If-None-Match wins when there's both
+        assert!(!not_modified(
+            &headers(&[
+                ("if-none-match", "\"v2\""),
+                ("if-modified-since", "Sun, 06 Nov 1994 08:49:37 GMT"),
+            ]),
+            &res
+        ));
+
+        assert!(not_modified(
+            &headers(&[("if-modified-since", "Sun, 06 Nov 1994 08:49:37 GMT")]),
+            &res
+        ));
+        assert!(!not_modified(
+            &headers(&[("if-modified-since", "Sun, 06 Nov 1994 08:49:36 GMT")]),
+            &res
+        ));
+        assert!(!not_modified(&HeaderMap::new(), &res));
+
+        let validators = validators(&res);
+        assert_eq!(validators[IF_NONE_MATCH], "W/\"v1\"");
+        assert_eq!(
+            validators[IF_MODIFIED_SINCE],
+            "Sun, 06 Nov 1994 08:49:37 GMT"
+        );
+    }
+
+    #[test]
+    fn reads_vary_and_age() {
+        let names = vary_names(&headers(&[
+            ("vary", "Accept-Encoding, cookie"),
+            ("vary", ""),
+        ]));
+        assert_eq!(names.unwrap(), ["accept-encoding", "cookie"]);
+        assert!(vary_names(&headers(&[("vary", "accept, *")])).is_none());
+
+        assert_eq!(age(&headers(&[("age", " 30 ")])).as_secs(), 30);
+        assert_eq!(age(&headers(&[("age", "x")])).as_secs(), 0);
+    }
+}
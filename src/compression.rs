@@ -0,0 +1,537 @@
+//! Response compression, negotiated with Accept-Encoding
+
+use std::{
+    io::{self, Write},
+    mem,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    http::{
+        HeaderMap, HeaderValue, StatusCode,
+        header::{
+            ACCEPT_ENCODING, ACCEPT_RANGES, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH,
+            CONTENT_RANGE, CONTENT_TYPE, ETAG, VARY,
+        },
+    },
+    response::Response,
+};
+use brotli::CompressorWriter;
+use flate2::write::GzEncoder;
+use hyper::body::Frame;
+
+use crate::config::{Compression, Encoding};
+
+// compressed output is sent on once there's this much of it
+const CHUNK_SIZE: usize = 16 * 1024;
+
+// on the fly, so speed matters more than the last few percent
+const BROTLI_QUALITY: u32 = 4;
+const BROTLI_WINDOW: u32 = 22;
+const ZSTD_LEVEL: i32 = 3;
+
+/// Picks the encoding to use from the ones the client accepts, if any
+pub fn negotiate(encodings: &[Encoding], headers: &HeaderMap) -> Option<Encoding> {
+    let accepted = headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|a| a.to_str().ok())
+        .flat_map(|a| a.split(','))
+        .filter_map(|coding| {
+            let mut params = coding.split(';');
+            let name = params.next()?.trim();
+            let q = match params.find_map(|p| p.trim().strip_prefix("q=")) {
+                Some(q) => q.trim().parse::<f32>().ok()?,
+                None => 1.0,
+            };
+
+            Some((name, q))
+        })
+        .collect::<Vec<_>>();
+
+    let quality = |encoding: Encoding| {
+        accepted
+            .iter()
+            .find(|(name, _)| {
+                aliases(encoding)
+                    .iter()
+                    .any(|a| name.eq_ignore_ascii_case(a))
+            })
+            .or_else(|| accepted.iter().find(|(name, _)| *name == "*"))
+            .map_or(0.0, |&(_, q)| q)
+    };
+
+    // ties go to our own preference
+    let mut best: Option<(Encoding, f32)> = None;
+    for &encoding in encodings {
+        let q = quality(encoding);
+        if q > 0.0 && best.is_none_or(|(_, best)| q > best) {
+            best = Some((encoding, q));
+        }
+    }
+
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Whether the response is worth compressing, whatever the client accepts
+pub fn compressible(config: &Compression, res: &Response) -> bool {
+    let status = res.status();
+    if status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::PARTIAL_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+    {
+        return false;
+    }
+
+    let headers = res.headers();
+
+    // already compressed, most likely by the backend
+    if headers
+        .get(CONTENT_ENCODING)
+        .is_some_and(|e| e.as_bytes() != b"identity")
+    {
+        return false;
+    }
+
+    if headers.contains_key(CONTENT_RANGE) {
+        return false;
+    }
+
+    let no_transform = headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|c| c.to_str().ok())
+        .flat_map(|c| c.split(','))
+        .any(|d| d.trim().eq_ignore_ascii_case("no-transform"));
+    if no_transform {
+        return false;
+    }
+
+    let Some(content_type) = headers.get(CONTENT_TYPE).and_then(|c| c.to_str().ok()) else {
+        return false;
+    };
+
+    let mime = content_type.split(';').next().unwrap_or("").trim();
+
+    // events have to reach the client as they're sent, not when a buffer fills up
+    if mime.eq_ignore_ascii_case("text/event-stream") {
+        return false;
+    }
+
+    if !config
+        .content_types
+        .iter()
+        .any(|t| t.eq_ignore_ascii_case(mime))
+    {
+        return false;
+    }
+
+    let len = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|l| l.to_str().ok())
+        .and_then(|l| l.parse::<u64>().ok())
+        .or_else(|| res.body().size_hint().exact());
+
+    len.is_none_or(|len| len >= config.min_size)
+}
+
+/// Marks the response as varying by Accept-Encoding, so caches keep each encoding apart
+pub fn add_vary(headers: &mut HeaderMap) {
+    let listed = headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim() == "*" || v.trim().eq_ignore_ascii_case("accept-encoding"));
+
+    if !listed {
+        headers.append(VARY, HeaderValue::from_static("accept-encoding"));
+    }
+}
+
+/// Compresses the response's body with `encoding`
+pub fn compress(res: Response, encoding: Encoding) -> Response {
+    let encoder = match Encoder::new(encoding) {
+        Ok(encoder) => encoder,
+        Err(_) => return res,
+    };
+
+    let (mut parts, body) = res.into_parts();
+    encoded_headers(&mut parts.headers, encoding);
+
+    Response::from_parts(parts, Body::new(CompressedBody::new(body, encoder)))
+}
+
+/// Gives a HEAD response the headers [`compress`] would have, as there's no body to compress
+pub fn compress_head(res: Response, encoding: Encoding) -> Response {
+    let (mut parts, _) = res.into_parts();
+    encoded_headers(&mut parts.headers, encoding);
+
+    // of unknown length like a compressed one, an empty body would get a Content-Length: 0
+    let body = Body::from_stream(futures::stream::empty::<Result<Bytes, io::Error>>());
+
+    Response::from_parts(parts, body)
+}
+
+/// Sets the headers of a response compressed with `encoding`
+fn encoded_headers(headers: &mut HeaderMap, encoding: Encoding) {
+    headers.remove(CONTENT_LENGTH);
+    // ranges would be of the compressed body, which isn't what the backend meant
+    headers.remove(ACCEPT_RANGES);
+    headers.insert(
+        CONTENT_ENCODING,
+        HeaderValue::from_static(aliases(encoding)[0]),
+    );
+
+    // the compressed body isn't byte for byte the same, so the etag can only be a weak one now
+    if let Some(etag) = headers.get(ETAG)
+        && !etag.as_bytes().starts_with(b"W/")
+    {
+        let mut weak = b"W/".to_vec();
+        weak.extend_from_slice(etag.as_bytes());
+
+        if let Ok(weak) = HeaderValue::from_bytes(&weak) {
+            headers.insert(ETAG, weak);
+        }
+    }
+}
+
+/// The names an encoding goes by in Accept-Encoding, the first being the one to reply with
+fn aliases(encoding: Encoding) -> &'static [&'static str] {
+    match encoding {
+        Encoding::Gzip => &["gzip", "x-gzip"],
+        Encoding::Br => &["br"],
+        Encoding::Zstd => &["zstd"],
+    }
+}
+
+enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Br(Box<CompressorWriter<Vec<u8>>>),
+    Zstd(zstd::Encoder<'static, Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> io::Result<Self> {
+        let encoder = match encoding {
+            Encoding::Gzip => {
+                Self::Gzip(GzEncoder::new(Vec::new(), flate2::Compression::default()))
+            }
+            Encoding::Br => Self::Br(Box::new(CompressorWriter::new(
+                Vec::new(),
+                CHUNK_SIZE,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW,
+            ))),
+            Encoding::Zstd => Self::Zstd(zstd::Encoder::new(Vec::new(), ZSTD_LEVEL)?),
+        };
+
+        Ok(encoder)
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Self::Gzip(e) => e,
+            Self::Br(e) => e,
+            Self::Zstd(e) => e,
+        }
+    }
+
+    fn output(&mut self) -> &mut Vec<u8> {
+        match self {
+            Self::Gzip(e) => e.get_mut(),
+            Self::Br(e) => e.get_mut(),
+            Self::Zstd(e) => e.get_mut(),
+        }
+    }
+
+    /// Takes the output compressed so far
+    fn take(&mut self) -> Bytes {
+        Bytes::from(mem::take(self.output()))
+    }
+
+    fn finish(self) -> io::Result<Bytes> {
+        let output = match self {
+            Self::Gzip(e) => e.finish()?,
+            Self::Br(e) => e.into_inner(),
+            Self::Zstd(e) => e.finish()?,
+        };
+
+        Ok(Bytes::from(output))
+    }
+}
+
+/// A body compressed as it streams
+///
+/// Whatever's been compressed is flushed out whenever the body has nothing more to give yet, so
+/// slow responses aren't held back waiting for a full chunk
+struct CompressedBody {
+    body: Body,
+    encoder: Option<Encoder>,
+    // input written since the last flush
+    unflushed: bool,
+    trailers: Option<HeaderMap>,
+}
+
+impl CompressedBody {
+    fn new(body: Body, encoder: Encoder) -> Self {
+        Self {
+            body,
+            encoder: Some(encoder),
+            unflushed: false,
+            trailers: None,
+        }
+    }
+
+    fn finish(&mut self) -> Option<Result<Frame<Bytes>, axum::Error>> {
+        let output = self.encoder.take()?.finish().map_err(axum::Error::new);
+
+        match output {
+            Ok(output) if output.is_empty() => None,
+            output => Some(output.map(Frame::data)),
+        }
+    }
+}
+
+impl HttpBody for CompressedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = &mut *self;
+
+        loop {
+            let Some(encoder) = this.encoder.as_mut() else {
+                return Poll::Ready(this.trailers.take().map(|t| Ok(Frame::trailers(t))));
+            };
+
+            match Pin::new(&mut this.body).poll_frame(cx) {
+                Poll::Ready(Some(Ok(frame))) => match frame.into_data() {
+                    Ok(data) => {
+                        if let Err(e) = encoder.writer().write_all(&data) {
+                            this.encoder = None;
+                            return Poll::Ready(Some(Err(axum::Error::new(e))));
+                        }
+
+                        this.unflushed = true;
+
+                        if encoder.output().len() >= CHUNK_SIZE {
+                            return Poll::Ready(Some(Ok(Frame::data(encoder.take()))));
+                        }
+                    }
+
+                    // the compressed data has to end before the trailers are sent
+                    Err(frame) => {
+                        this.trailers = frame.into_trailers().ok();
+                        if let Some(output) = this.finish() {
+                            return Poll::Ready(Some(output));
+                        }
+                    }
+                },
+
+                Poll::Ready(Some(Err(e))) => {
+                    this.encoder = None;
+                    return Poll::Ready(Some(Err(e)));
+                }
+
+                Poll::Ready(None) => {
+                    if let Some(output) = this.finish() {
+                        return Poll::Ready(Some(output));
+                    }
+                }
+
+                Poll::Pending => {
+                    if this.unflushed {
+                        this.unflushed = false;
+
+                        if let Err(e) = encoder.writer().flush() {
+                            this.encoder = None;
+                            return Poll::Ready(Some(Err(axum::Error::new(e))));
+                        }
+
+                        let output = encoder.take();
+                        if !output.is_empty() {
+                            return Poll::Ready(Some(Ok(Frame::data(output))));
+                        }
+                    }
+
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.encoder.is_none() && self.trailers.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read as _;
+
+    use super::*;
+
+    const ALL: &[Encoding] = &[Encoding::Zstd, Encoding::Br, Encoding::Gzip];
+
+    fn accept(accept_encoding: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(accept_encoding));
+        headers
+    }
+
+    fn response(content_type: &'static str, body: &'static str) -> Response {
+        let mut res = Response::new(Body::from(body));
+        res.headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        res
+    }
+
+    #[test]
+    fn negotiates_encodings() {
+        // ties go to our preference
+        assert_eq!(
+            negotiate(ALL, &accept("gzip, deflate, br, zstd")),
+            Some(Encoding::Zstd)
+        );
+        assert_eq!(negotiate(ALL, &accept("gzip, br")), Some(Encoding::Br));
+        // otherwise the client's
+        assert_eq!(
+            negotiate(ALL, &accept("zstd;q=0.5, gzip;q=0.8")),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(negotiate(ALL, &accept("X-GZIP")), Some(Encoding::Gzip));
+        assert_eq!(negotiate(ALL, &accept("*")), Some(Encoding::Zstd));
+        assert_eq!(
+            negotiate(ALL, &accept("zstd;q=0, *;q=0.1")),
+            Some(Encoding::Br)
+        );
+        // only what we're configured to use
+        assert_eq!(negotiate(&[Encoding::Gzip], &accept("zstd, br")), None);
+
+        assert_eq!(negotiate(ALL, &accept("identity")), None);
+        assert_eq!(negotiate(ALL, &accept("gzip;q=0")), None);
+        assert_eq!(negotiate(ALL, &accept("gzip;q=x")), None);
+        assert_eq!(negotiate(ALL, &HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn picks_compressible_responses() {
+        let config = Compression {
+            min_size: 4,
+            ..Default::default()
+        };
+
+        assert!(compressible(
+            &config,
+            &response("text/html; charset=utf-8", "hello")
+        ));
+        assert!(!compressible(&config, &response("image/png", "hello")));
+        assert!(!compressible(
+            &config,
+            &response("text/event-stream", "hello")
+        ));
+        assert!(!compressible(&config, &response("text/html", "hi")));
+
+        let mut res = response("text/html", "hello");
+        res.headers_mut()
+            .insert(CONTENT_ENCODING, HeaderValue::from_static("br"));
+        assert!(!compressible(&config, &res));
+
+        let mut res = response("text/html", "hello");
+        res.headers_mut().insert(
+            CACHE_CONTROL,
+            HeaderValue::from_static("max-age=60, No-Transform"),
+        );
+        assert!(!compressible(&config, &res));
+
+        let mut res = response("text/html", "hello");
+        *res.status_mut() = StatusCode::NOT_MODIFIED;
+        assert!(!compressible(&config, &res));
+    }
+
+    #[test]
+    fn adds_vary_once() {
+        let mut headers = HeaderMap::new();
+        headers.insert(VARY, HeaderValue::from_static("Accept-Encoding"));
+        add_vary(&mut headers);
+        assert_eq!(headers.get_all(VARY).iter().count(), 1);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(VARY, HeaderValue::from_static("cookie"));
+        add_vary(&mut headers);
+        assert_eq!(headers.get_all(VARY).iter().count(), 2);
+    }
+
+    #[test]
+    fn head_headers_match_get() {
+        let res = || {
+            let mut res = response("text/html", "");
+            res.headers_mut()
+                .insert(ETAG, HeaderValue::from_static("\"v1\""));
+            res.headers_mut()
+                .insert(CONTENT_LENGTH, HeaderValue::from(5000));
+            res.headers_mut()
+                .insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+            res
+        };
+
+        let get = compress(res(), Encoding::Br);
+
+        let head = compress_head(res(), Encoding::Br);
+
+        assert_eq!(get.headers(), head.headers());
+        assert_eq!(head.headers()[CONTENT_ENCODING], "br");
+        assert!(!head.headers().contains_key(CONTENT_LENGTH));
+        assert_eq!(
+            head.body().size_hint().exact(),
+            get.body().size_hint().exact()
+        );
+    }
+
+    #[tokio::test]
+    async fn compresses_bodies() {
+        let text = "hello compression ".repeat(1000);
+
+        for encoding in ALL {
+            let mut res = response("text/plain", "");
+            *res.body_mut() = Body::from(text.clone());
+            res.headers_mut()
+                .insert(ETAG, HeaderValue::from_static("\"v1\""));
+            res.headers_mut()
+                .insert(CONTENT_LENGTH, HeaderValue::from(text.len()));
+
+            let res = compress(res, *encoding);
+            assert_eq!(res.headers()[CONTENT_ENCODING], aliases(*encoding)[0]);
+            assert_eq!(res.headers()[ETAG], "W/\"v1\"");
+            assert!(!res.headers().contains_key(CONTENT_LENGTH));
+
+            let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert!(body.len() < text.len());
+
+            let mut decoded = String::new();
+            match encoding {
+                Encoding::Gzip => {
+                    flate2::read::GzDecoder::new(&body[..]).read_to_string(&mut decoded)
+                }
+                Encoding::Br => {
+                    brotli::Decompressor::new(&body[..], 4096).read_to_string(&mut decoded)
+                }
+                Encoding::Zstd => zstd::Decoder::new(&body[..])
+                    .unwrap()
+                    .read_to_string(&mut decoded),
+            }
+            .unwrap();
+
+            assert_eq!(decoded, text, "{encoding:?}");
+        }
+    }
+}
@@ -1,11 +1,15 @@
 use std::{
+    collections::HashMap,
     env, fs, io,
     net::{Ipv4Addr, SocketAddr, SocketAddrV4},
 };
 
+use axum::http::StatusCode;
 use serde::{Deserialize, Serialize};
 use snafu::{OptionExt, ResultExt, Snafu, whatever};
 
+use crate::access::Cidr;
+
 #[derive(Debug, Snafu)]
 pub enum ConfigError {
     #[snafu(display("Exe path not found"))]
@@ -31,6 +35,38 @@ pub enum ConfigError {
 pub struct Config {
     pub addresses: Addresses,
     pub options: Options,
+    #[serde(default)]
+    pub proxy: Proxy,
+    #[serde(default)]
+    pub upstream: Upstream,
+    #[serde(default)]
+    pub grpc: Grpc,
+    #[serde(default)]
+    pub compression: Compression,
+    #[serde(default)]
+    pub cache: Cache,
+    #[serde(default)]
+    pub redirect: Redirect,
+    #[serde(default)]
+    pub security_headers: SecurityHeaders,
+    #[serde(default)]
+    pub error_pages: ErrorPages,
+    #[serde(default)]
+    pub request_id: RequestIds,
+    #[serde(default)]
+    pub limits: Limits,
+    #[serde(default)]
+    pub access: Access,
+    #[serde(default)]
+    pub basic_auth: BasicAuth,
+    #[serde(default)]
+    pub forward_auth: ForwardAuth,
+    #[serde(default)]
+    pub oidc: Oidc,
+    #[serde(default)]
+    pub rewrite: Rewrite,
+    #[serde(default)]
+    pub websocket: Websocket,
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
@@ -58,6 +94,763 @@ pub struct Options {
     // enable kavita support
     #[serde(default)]
     pub kavita: bool,
+    // Don't offer HTTP/2 to clients over ALPN, so they all use HTTP/1.1
+    #[serde(default)]
+    pub disable_http2: bool,
+    // Serve HTTP/3 over QUIC (udp) on the https port as well, advertised with Alt-Svc
+    // Websockets still go over tcp
+    #[serde(default)]
+    pub http3: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Proxy {
+    // Forward the Host the client requested to the backend
+    // When disabled, the backend's address is sent as the Host instead
+    pub preserve_host: bool,
+    // Add a Via header with this name to proxied requests and responses
+    //- eg: ssl-ifier
+    pub via: Option<String>,
+}
+
+impl Default for Proxy {
+    fn default() -> Self {
+        Self {
+            preserve_host: true,
+            via: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Upstream {
+    // Seconds to wait for a connection to the backend
+    pub connect_timeout: Option<u64>,
+    // Seconds to wait for the backend's response headers once the request is sent
+    pub response_timeout: Option<u64>,
+    // Seconds the whole request may take, including streaming the response body
+    // Bodies still streaming when it passes are cut off
+    pub total_timeout: Option<u64>,
+    // Times to retry requests when the backend can't be connected to
+    // Only idempotent requests without a body are retried
+    pub retries: u32,
+    // Milliseconds to wait before the first retry, doubling after each one
+    pub retry_backoff: u64,
+    // Max retries, as a fraction of requests
+    //- eg: 0.2 allows 1 retry for every 5 requests
+    pub retry_budget: f64,
+    // Seconds to keep idle backend connections open for reuse
+    pub pool_idle_timeout: Option<u64>,
+    // Max idle backend connections to keep open
+    pub pool_max_idle_per_host: usize,
+    // Seconds between tcp keepalive probes on backend connections
+    pub tcp_keepalive: Option<u64>,
+    // Disable Nagle's algorithm on backend connections
+    pub tcp_nodelay: bool,
+    // Talk HTTP/2 to the backend, without TLS (h2c with prior knowledge)
+    // The backend must support it, eg: gRPC servers
+    pub http2: bool,
+}
+
+impl Default for Upstream {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Some(10),
+            response_timeout: Some(120),
+            total_timeout: None,
+            retries: 2,
+            retry_backoff: 100,
+            retry_budget: 0.2,
+            pool_idle_timeout: Some(90),
+            pool_max_idle_per_host: 32,
+            tcp_keepalive: Some(60),
+            tcp_nodelay: true,
+            http2: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Grpc {
+    // Translate gRPC-Web requests from browsers into gRPC for the backend
+    // Needs upstream.http2, as gRPC backends only speak HTTP/2
+    pub web: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Compression {
+    // Compress responses for clients which accept it
+    pub enabled: bool,
+    // Encodings to use, in order of preference when the client accepts several
+    //- eg: ["zstd", "br", "gzip"]
+    pub encodings: Vec<Encoding>,
+    // Content types to compress
+    pub content_types: Vec<String>,
+    // Responses smaller than this are sent as-is, in bytes
+    // Responses of unknown size are always compressed
+    pub min_size: u64,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            encodings: vec![Encoding::Zstd, Encoding::Br, Encoding::Gzip],
+            content_types: [
+                "text/html",
+                "text/css",
+                "text/plain",
+                "text/xml",
+                "text/javascript",
+                "application/javascript",
+                "application/json",
+                "application/xml",
+                "application/wasm",
+                "image/svg+xml",
+            ]
+            .map(String::from)
+            .to_vec(),
+            min_size: 1024,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Gzip,
+    Br,
+    Zstd,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Cache {
+    // Cache backend responses, as allowed by their Cache-Control and Expires headers
+    // Responses to requests let through by basic_auth, forward_auth or oidc are only cached when
+    // the backend marks them public or gives them an s-maxage
+    pub enabled: bool,
+    // Where cached responses are kept. Disk ones survive restarts
+    //- eg: memory, disk
+    pub store: CacheStore,
+    // Directory for the disk store, relative to the exe
+    pub dir: String,
+    // Max total size of cached responses, in bytes. The least recently used are dropped first
+    pub max_size: u64,
+    // Max size of a single response to cache, in bytes
+    pub max_entry_size: u64,
+    // Seconds a stale response may still be served while it's revalidated in the background
+    // Used when the backend doesn't send stale-while-revalidate itself
+    pub stale_while_revalidate: u64,
+    // Seconds a stale response may still be served when the backend fails (eg: 502)
+    // Used when the backend doesn't send stale-if-error itself
+    pub stale_if_error: u64,
+    // Overrides per path prefix. The longest matching path applies
+    //- eg: [[cache.routes]]
+    //-     path = "/assets/"
+    //-     ttl = 86400
+    pub routes: Vec<CacheRoute>,
+    // Path of the purge endpoint, disabled when unset
+    // POST {purge_path}?path=/assets/ purges everything under /assets/, and ?host= limits it to a host
+    //- eg: /.cache/purge
+    pub purge_path: Option<String>,
+    // Ranges allowed to purge, checked against the real client ip. Only localhost when empty
+    pub purge_allow: AccessList,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            store: CacheStore::Memory,
+            dir: "cache".to_owned(),
+            max_size: 256 * 1024 * 1024,
+            max_entry_size: 8 * 1024 * 1024,
+            stale_while_revalidate: 0,
+            stale_if_error: 0,
+            routes: Vec::new(),
+            purge_path: None,
+            purge_allow: AccessList::default(),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheStore {
+    Memory,
+    Disk,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheRoute {
+    pub path: String,
+    // Never cache responses under this path
+    #[serde(default)]
+    pub bypass: bool,
+    // Seconds responses stay fresh for, whatever the backend says
+    pub ttl: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Redirect {
+    // Serve the http listener which redirects to https
+    pub enabled: bool,
+    // Status code used for redirects
+    //- one of: 301, 302, 307, 308
+    pub status: RedirectStatus,
+    // Always redirect to this host, instead of the one the client requested
+    //- eg: myaddr.com
+    pub canonical_host: Option<String>,
+    // Port to redirect to, if it differs from the ssl port (eg: behind port forwarding)
+    pub canonical_port: Option<u16>,
+    // Add or remove a leading `www.` on the redirect host
+    //- keep, add, remove
+    pub www: Www,
+    // Path prefixes which are proxied to the backend over http instead of redirected
+    // They go through the same auth, access and limits as https requests
+    //- eg: ["/.well-known/acme-challenge/"]
+    pub exclude: Vec<String>,
+}
+
+impl Default for Redirect {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            status: RedirectStatus::default(),
+            canonical_host: None,
+            canonical_port: None,
+            www: Www::default(),
+            exclude: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "u16", into = "u16")]
+pub struct RedirectStatus(pub StatusCode);
+
+impl Default for RedirectStatus {
+    fn default() -> Self {
+        Self(StatusCode::PERMANENT_REDIRECT)
+    }
+}
+
+impl TryFrom<u16> for RedirectStatus {
+    type Error = String;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            301 | 302 | 307 | 308 => Ok(Self(StatusCode::from_u16(value).unwrap())),
+            _ => Err(format!("invalid redirect status {value}, expected 301, 302, 307 or 308")),
+        }
+    }
+}
+
+impl From<RedirectStatus> for u16 {
+    fn from(value: RedirectStatus) -> Self {
+        value.0.as_u16()
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Www {
+    #[default]
+    Keep,
+    Add,
+    Remove,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SecurityHeaders {
+    // Send Strict-Transport-Security on https responses
+    // Browsers will refuse plain http (and invalid certs) for the host until max age passes
+    pub hsts: bool,
+    // In seconds
+    pub hsts_max_age: u64,
+    pub hsts_include_subdomains: bool,
+    // Only enable if the host is (to be) submitted to https://hstspreload.org
+    pub hsts_preload: bool,
+    // Content-Security-Policy
+    //- eg: "default-src 'self'"
+    pub content_security_policy: Option<String>,
+    // X-Content-Type-Options: nosniff
+    pub content_type_nosniff: bool,
+    // Referrer-Policy
+    //- eg: "strict-origin-when-cross-origin"
+    pub referrer_policy: Option<String>,
+    // Permissions-Policy
+    //- eg: "camera=(), microphone=()"
+    pub permissions_policy: Option<String>,
+    // X-Frame-Options
+    //- eg: DENY, SAMEORIGIN
+    pub frame_options: Option<String>,
+    // Keep any of these headers the backend already sets, instead of overriding them
+    pub preserve_backend: bool,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        Self {
+            hsts: false,
+            hsts_max_age: 31536000,
+            hsts_include_subdomains: false,
+            hsts_preload: false,
+            content_security_policy: None,
+            content_type_nosniff: false,
+            referrer_policy: None,
+            permissions_policy: None,
+            frame_options: None,
+            preserve_backend: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ErrorPages {
+    // Directory of custom error page templates, relative to the exe
+    // Missing templates fall back to the built-in pages. See src/error_pages/templates.rs for
+    // the file names and placeholders. Templates are re-read when they change
+    //- eg: error-pages
+    pub dir: Option<String>,
+    // Replace error responses from the backend with our error pages
+    pub intercept_backend: bool,
+    // Backend status codes to replace, all 4xx and 5xx when empty
+    //- eg: [502, 503, 504]
+    pub intercept_statuses: Vec<u16>,
+    // Show what went wrong on error pages (eg: backend connection errors)
+    // When disabled, pages only show the request ID and the error is logged with it instead
+    pub expose_error_details: bool,
+}
+
+impl Default for ErrorPages {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            intercept_backend: false,
+            intercept_statuses: Vec::new(),
+            expose_error_details: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RequestIds {
+    // Header the request ID is read from, sent to the backend in and returned to the client in
+    pub header: String,
+    // Format of generated IDs
+    //- uuid, ulid
+    pub format: RequestIdFormat,
+    // Send the request ID to the backend, including on websocket connections
+    pub forward: bool,
+    // Return the request ID to the client
+    pub response_header: bool,
+}
+
+impl Default for RequestIds {
+    fn default() -> Self {
+        Self {
+            header: "X-Request-Id".to_owned(),
+            format: RequestIdFormat::default(),
+            forward: true,
+            response_header: true,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RequestIdFormat {
+    #[default]
+    Uuid,
+    Ulid,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+    // Token bucket rate limits. Requests must be allowed by every rule matching them
+    // Requests over the limit get a 429 with Retry-After
+    //- eg: [[limits.rate]]
+    //-     key = "ip"
+    //-     rate = 10.0
+    //-     burst = 50
+    pub rate: Vec<RateLimitRule>,
+    // Max open connections, across both listeners
+    pub max_connections: Option<usize>,
+    // Max open connections from a single ip
+    pub max_connections_per_ip: Option<usize>,
+    // Max open websocket sessions
+    pub max_websockets: Option<usize>,
+    // Max open websocket sessions from a single ip
+    pub max_websockets_per_ip: Option<usize>,
+    // Max size of a request body in bytes, bigger ones get a 413
+    //- eg: 104857600
+    pub max_body_size: Option<u64>,
+    // Max size of a request's headers in bytes, bigger ones get a 431
+    pub max_header_size: usize,
+    // Seconds clients have to send a request's headers
+    // Idle http/1 connections waiting for their next request are closed after this too
+    pub header_read_timeout: Option<u64>,
+    // Seconds a request body can go without sending anything
+    // Not applied to gRPC requests, whose streams can be quiet for any length of time
+    pub body_idle_timeout: Option<u64>,
+    // Seconds a connection with no requests in flight is kept open, on both listeners
+    pub keep_alive_timeout: Option<u64>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            rate: Vec::new(),
+            max_connections: None,
+            max_connections_per_ip: None,
+            max_websockets: None,
+            max_websockets_per_ip: None,
+            max_body_size: None,
+            max_header_size: 64 * 1024,
+            header_read_timeout: Some(30),
+            body_idle_timeout: Some(30),
+            keep_alive_timeout: Some(75),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RateLimitRule {
+    // What requests share a bucket
+    //- ip: requests from the same client ip
+    //- header: requests with the same value for `header` (requests without it share one)
+    //- route: all requests matching `path`
+    pub key: RateLimitKey,
+    // Header to key on when key = "header"
+    //- eg: X-Api-Key
+    pub header: Option<String>,
+    // Only limit paths under this
+    //- eg: /api/
+    pub path: Option<String>,
+    // Requests per second, more than 0
+    pub rate: f64,
+    // Requests which can be made at once before being limited to the rate, at least 1
+    pub burst: u32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitKey {
+    Ip,
+    Header,
+    Route,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Access {
+    // Header a proxy in front of us puts the real client ip in
+    // Only read from trusted_proxies. Also used for rate limiting
+    //- eg: X-Forwarded-For, X-Real-IP
+    pub client_ip_header: Option<String>,
+    // Addresses (or ranges) of proxies in front of us
+    // Request IDs they send are used instead of generating one
+    //- eg: ["10.0.0.0/8"]
+    pub trusted_proxies: Vec<Cidr>,
+    // Ranges allowed to use the site, checked against the real client ip. 403 otherwise
+    pub site: AccessList,
+    // Ranges allowed per path prefix, on top of the site's. The longest matching path applies
+    // Like every path setting, it's matched on whole segments of the decoded, normalized path
+    //- eg: [[access.routes]]
+    //-     path = "/admin/"
+    //-     allow = ["192.168.0.0/16"]
+    pub routes: Vec<RouteAccess>,
+    // Ranges allowed to connect at all, checked on accept against the peer address
+    // Connections from anywhere else are dropped before tls
+    pub listener: AccessList,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccessList {
+    // When not empty, only these ranges are allowed
+    //- eg: ["192.168.0.0/16", "::1"]
+    pub allow: Vec<Cidr>,
+    // These ranges are never allowed, even if they're in allow
+    pub deny: Vec<Cidr>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouteAccess {
+    pub path: String,
+    #[serde(flatten)]
+    pub list: AccessList,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BasicAuth {
+    // htpasswd file with the users, relative to the exe. Basic auth is enabled when set
+    // Passwords must be hashed with bcrypt (htpasswd -B) or argon2. See src/auth/htpasswd.rs
+    //- eg: .htpasswd
+    pub htpasswd: Option<String>,
+    // Shown by browsers in the login prompt
+    pub realm: String,
+    // Path prefixes which need a login, everything when empty
+    //- eg: ["/admin/"]
+    pub paths: Vec<String>,
+    // Send the logged in username to the backend in this header
+    //- eg: Remote-User
+    pub user_header: Option<String>,
+    // Don't send the Authorization header (with the password) to the backend
+    pub strip_authorization: bool,
+}
+
+impl Default for BasicAuth {
+    fn default() -> Self {
+        Self {
+            htpasswd: None,
+            realm: "ssl-ifier".to_owned(),
+            paths: Vec::new(),
+            user_header: None,
+            strip_authorization: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ForwardAuth {
+    // Auth service to check every request with first. Forward auth is enabled when set
+    // Requests are let through if it responds 2xx, otherwise its response is sent to the client
+    //- eg: http://127.0.0.1:9091/api/verify
+    pub url: Option<String>,
+    // Path prefixes which need auth, everything when empty
+    pub paths: Vec<String>,
+    // Headers from the auth service's response to send to the backend
+    // Clients can't set these themselves
+    pub copy_headers: Vec<String>,
+    // Seconds to wait for the auth service
+    pub timeout: u64,
+}
+
+impl Default for ForwardAuth {
+    fn default() -> Self {
+        Self {
+            url: None,
+            paths: Vec::new(),
+            copy_headers: ["Remote-User", "Remote-Groups", "Remote-Name", "Remote-Email"]
+                .map(String::from)
+                .to_vec(),
+            timeout: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Oidc {
+    // OpenID Connect issuer to log in with. OIDC login is enabled when set
+    // Its endpoints are discovered from <issuer>/.well-known/openid-configuration
+    //- eg: https://auth.example.com/realms/main
+    pub issuer: Option<String>,
+    pub client_id: String,
+    // Leave unset for public clients, which only use PKCE
+    pub client_secret: Option<String>,
+    pub scopes: Vec<String>,
+    // Path prefixes which need a login, everything when empty
+    // Identity headers are still sent on other paths if the client is logged in
+    //- eg: ["/admin/"]
+    pub paths: Vec<String>,
+    // Where the issuer sends users back to. https://<host><callback_path> must be an allowed redirect uri
+    pub callback_path: String,
+    // Visiting this logs the user out, at the issuer too if it supports it
+    pub logout_path: String,
+    // Name of the session cookie. The login cookie is this with _login appended
+    pub cookie_name: String,
+    // Secret the session cookie is encrypted with
+    // A random one is used when unset, which logs everyone out on restart
+    pub session_secret: Option<String>,
+    // Seconds until users have to log in again, even if their tokens are refreshed
+    pub session_lifetime: u64,
+    // Headers to send to the backend, and the id token claim to fill each with
+    // Clients can't set these themselves
+    //- eg: { "Remote-User" = "preferred_username", "Remote-Groups" = "groups" }
+    pub claim_headers: HashMap<String, String>,
+    // Seconds to wait for the issuer
+    pub timeout: u64,
+}
+
+impl Default for Oidc {
+    fn default() -> Self {
+        Self {
+            issuer: None,
+            client_id: String::new(),
+            client_secret: None,
+            scopes: ["openid", "profile", "email"].map(String::from).to_vec(),
+            paths: Vec::new(),
+            callback_path: "/oauth2/callback".to_owned(),
+            logout_path: "/oauth2/logout".to_owned(),
+            cookie_name: "ssl_ifier_session".to_owned(),
+            session_secret: None,
+            session_lifetime: 7 * 24 * 60 * 60,
+            claim_headers: [
+                ("Remote-User", "preferred_username"),
+                ("Remote-Name", "name"),
+                ("Remote-Email", "email"),
+                ("Remote-Groups", "groups"),
+            ]
+            .map(|(header, claim)| (header.to_owned(), claim.to_owned()))
+            .into(),
+            timeout: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Rewrite {
+    // Rewrite backend urls in Location, Content-Location and Refresh headers to the public https origin
+    pub headers: bool,
+    // Other origins the backend may refer to itself as, besides http://<backend>
+    //- eg: ["http://localhost:5000"]
+    pub backend_aliases: Vec<String>,
+    // Add Secure to cookies set by the backend
+    pub cookie_secure: bool,
+    // SameSite to add to cookies set by the backend which don't have one
+    //- eg: Lax, Strict, None
+    pub cookie_same_site: Option<String>,
+    // Rewrite the Domain of cookies set by the backend, from -> to
+    //- eg: { "localhost" = "myaddr.com" }
+    pub cookie_domains: HashMap<String, String>,
+    // Rewrite the Path prefix of cookies set by the backend, from -> to
+    //- eg: { "/" = "/app/" }
+    pub cookie_paths: HashMap<String, String>,
+    // Also rewrite backend urls inside response bodies
+    // Backends are asked not to compress responses when enabled, so bodies can be rewritten
+    pub body: bool,
+    // Content types whose bodies are rewritten
+    pub body_content_types: Vec<String>,
+    // Max size of a body to rewrite, in bytes
+    pub body_max_size: usize,
+}
+
+impl Default for Rewrite {
+    fn default() -> Self {
+        Self {
+            headers: true,
+            backend_aliases: Vec::new(),
+            cookie_secure: true,
+            cookie_same_site: None,
+            cookie_domains: HashMap::new(),
+            cookie_paths: HashMap::new(),
+            body: false,
+            body_content_types: ["text/html", "text/css", "text/javascript", "application/javascript"]
+                .map(String::from)
+                .to_vec(),
+            body_max_size: 8 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Websocket {
+    // How much of each proxied websocket message to log
+    //- off: nothing is logged per message
+    //- metadata: message type, size and direction
+    //- payload: metadata plus the message body (truncated and redacted)
+    pub log: WebsocketLog,
+    // Max number of payload bytes to log before truncating
+    pub log_max_payload: usize,
+    // Regexes whose matches are redacted from logged payloads
+    //- eg: ['"token":\s*"[^"]*"']
+    pub log_redact: Vec<String>,
+    // Log a summary (duration, message counts, bytes, close code) when a session closes
+    pub log_summary: bool,
+    // Send a ping to both the client and the backend every n seconds
+    pub ping_interval: Option<u64>,
+    // Close the session if neither side sends anything for n seconds
+    // With ping_interval set, a side which stops answering pings for n seconds closes it too
+    pub idle_timeout: Option<u64>,
+    // Close the session after it has been open for n seconds
+    pub max_session_duration: Option<u64>,
+    // Max size in bytes of a single message, in either direction
+    // Sessions sending larger messages are closed with 1009 (Message Too Big)
+    pub max_message_size: Option<usize>,
+    // Max size in bytes of a single frame, in either direction
+    pub max_frame_size: Option<usize>,
+    // Record every session's frames to a file in this directory, relative to the exe
+    // Recordings contain unredacted payloads. See src/websocket/recording.rs for the format
+    // Replay them with `ssl-ifier replay client|server <recording> <target>`
+    pub record_dir: Option<String>,
+    // Max size in bytes of a single recording, after which the rest of the session isn't recorded
+    pub record_max_size: Option<u64>,
+    // permessage-deflate compression between clients and the proxy
+    // This is independent of the backend, messages are transcoded as needed
+    pub deflate: Deflate,
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Deflate {
+    // Accept permessage-deflate from clients which offer it
+    pub enabled: bool,
+    // Window size (2^n bytes) used to compress messages sent to clients, 9-15
+    pub server_max_window_bits: u8,
+    // Window size (2^n bytes) clients are asked to compress with, 9-15
+    pub client_max_window_bits: u8,
+    // Reset the compression context after every message sent to clients
+    // Uses less memory per session at the cost of compression ratio
+    pub server_no_context_takeover: bool,
+    // Ask clients to reset their compression context after every message
+    pub client_no_context_takeover: bool,
+}
+
+impl Default for Deflate {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            server_max_window_bits: 15,
+            client_max_window_bits: 15,
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+        }
+    }
+}
+
+impl Default for Websocket {
+    fn default() -> Self {
+        Self {
+            log: WebsocketLog::default(),
+            log_max_payload: 256,
+            log_redact: Vec::new(),
+            log_summary: true,
+            ping_interval: Some(30),
+            idle_timeout: Some(120),
+            max_session_duration: None,
+            max_message_size: None,
+            max_frame_size: None,
+            record_dir: None,
+            record_max_size: Some(100 * 1024 * 1024),
+            deflate: Deflate::default(),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WebsocketLog {
+    Off,
+    #[default]
+    Metadata,
+    Payload,
 }
 
 impl Config {
@@ -79,7 +872,38 @@ impl Config {
 
         let config = fs::read_to_string(config_path).whatever_context("")?;
 
-        toml::from_str::<Self>(&config).context(TomlDeSnafu)
+        let config = toml::from_str::<Self>(&config).context(TomlDeSnafu)?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Checks for settings which parse, but can't work
+    fn validate(&self) -> Result<(), ConfigError> {
+        for rule in &self.limits.rate {
+            // NaN fails this too
+            if !(rule.rate > 0.0 && rule.rate.is_finite()) {
+                whatever!("limits.rate: rate must be more than 0, not {}", rule.rate);
+            }
+
+            if rule.burst == 0 {
+                whatever!("limits.rate: burst must be at least 1");
+            }
+        }
+
+        // NaN fails this too
+        if !(self.upstream.retry_budget >= 0.0 && self.upstream.retry_budget.is_finite()) {
+            whatever!(
+                "upstream.retry_budget must be 0 or more, not {}",
+                self.upstream.retry_budget
+            );
+        }
+
+        if self.grpc.web && !self.upstream.http2 {
+            whatever!("grpc.web needs upstream.http2, as gRPC backends only speak HTTP/2");
+        }
+
+        Ok(())
     }
 
     pub fn proxy_addr(&self) -> Result<ProxyAddr, ConfigError> {
@@ -1,35 +1,197 @@
-use std::fmt::Display;
+use std::{borrow::Cow, fmt::Display};
 
 use axum::http::{header, StatusCode};
 use axum::{
+    Extension,
     body::Body,
     response::{IntoResponse, Response},
 };
 use const_format::{formatcp, str_replace};
+use serde_json::json;
+
+use crate::utils::html_escape;
+
+mod templates;
+
+pub use templates::Templates;
+
+/// The error message of a page made by [`error_page`], for re-rendering it per request
+#[derive(Debug, Clone)]
+pub struct ErrorDetails(pub String);
+
+/// Values to fill an error page in with
+#[derive(Debug)]
+pub struct PageVars<'a> {
+    pub status: StatusCode,
+    pub details: &'a str,
+    pub request_id: Option<&'a str>,
+    pub host: Option<&'a str>,
+}
+
+/// The kinds of error response a client can ask for with `Accept`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ErrorFormat {
+    Html,
+    Json,
+    Text,
+}
+
+impl ErrorFormat {
+    /// Picks the format the client prefers, browsers and anything unrecognised get html
+    pub fn negotiate(accept: Option<&str>) -> Self {
+        let Some(accept) = accept else {
+            return Self::Html;
+        };
+
+        // (format, q, whether it was an exact type rather than a wildcard)
+        let mut best = (Self::Html, 0.0, false);
+
+        for range in accept.split(',') {
+            let mut params = range.split(';');
+            let mime = params.next().unwrap_or("").trim().to_ascii_lowercase();
+
+            let q = params
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            // q=0 means not acceptable at all
+            if q <= 0.0 || q.is_nan() {
+                continue;
+            }
+
+            let format = match mime.as_str() {
+                "text/html" | "application/xhtml+xml" | "*/*" | "text/*" => Self::Html,
+                "application/json" | "application/problem+json" => Self::Json,
+                "text/plain" => Self::Text,
+                _ if mime.ends_with("+json") => Self::Json,
+                _ => continue,
+            };
+
+            // exact types beat wildcards of the same q
+            let exact = !mime.contains('*');
+            if q > best.1 || (q == best.1 && exact && !best.2) {
+                best = (format, q, exact);
+            }
+        }
+
+        best.0
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Html => "text/html",
+            Self::Json => "application/problem+json",
+            Self::Text => "text/plain; charset=utf-8",
+        }
+    }
+}
 
 pub fn error_page<E: Display>(status_code: StatusCode, e: E) -> Response<Body> {
-    let (code, page) = match status_code.as_u16() {
-        400 => (status_code, E400),
-        500 => (status_code, E500),
-        502 => (status_code, E502),
-        _ => (StatusCode::INTERNAL_SERVER_ERROR, E500),
+    let details = e.to_string();
+
+    let vars = PageVars {
+        status: status_code,
+        details: &details,
+        request_id: None,
+        host: None,
     };
+    let page = builtin_page(&vars);
 
     (
-        code,
+        status_code,
         [(header::CONTENT_TYPE, "text/html")],
-        page.replace("<!--REPLACE-->", &format!("<p>Error: {e}</p>")),
+        Extension(ErrorDetails(details)),
+        page,
     )
         .into_response()
 }
 
+/// Renders the built-in html page for `vars.status`
+pub fn builtin_page(vars: &PageVars) -> String {
+    let status_code = vars.status;
+
+    let page = match status_code.as_u16() {
+        400 => E400,
+        403 => E403,
+        404 => E404,
+        408 => E408,
+        413 => E413,
+        429 => E429,
+        431 => E431,
+        500 => E500,
+        502 => E502,
+        503 => E503,
+        504 => E504,
+        _ => "",
+    };
+
+    // anything without its own page still keeps its status code
+    let page = if page.is_empty() {
+        let reason = status_code.canonical_reason().unwrap_or("Unknown Error");
+        let header = format!("Error {} - {reason}", status_code.as_u16());
+
+        Cow::Owned(
+            TEMPLATE
+                .replace("<!--ERROR HEADER-->", &header)
+                .replace("<!--ERROR DESCRIPTION-->", ""),
+        )
+    } else {
+        Cow::Borrowed(page)
+    };
+
+    let mut extra = String::new();
+    if !vars.details.is_empty() {
+        extra.push_str(&format!("<p>Error: {}</p>", html_escape(vars.details)));
+    }
+    if let Some(request_id) = vars.request_id {
+        extra.push_str(&format!("<p>Request ID: {}</p>", html_escape(request_id)));
+    }
+
+    page.replace("<!--REPLACE-->", &extra)
+}
+
+/// Renders an RFC 9457 problem details body
+pub fn problem_details(vars: &PageVars) -> String {
+    let mut problem = json!({
+        "type": "about:blank",
+        "title": vars.status.canonical_reason().unwrap_or("Unknown Error"),
+        "status": vars.status.as_u16(),
+    });
+
+    if !vars.details.is_empty() {
+        problem["detail"] = vars.details.into();
+    }
+    if let Some(request_id) = vars.request_id {
+        problem["request_id"] = request_id.into();
+    }
+
+    problem.to_string()
+}
+
+/// Renders a short plain text message
+pub fn plain_text(vars: &PageVars) -> String {
+    let reason = vars.status.canonical_reason().unwrap_or("Unknown Error");
+    let mut text = format!("{} {reason}", vars.status.as_u16());
+
+    if !vars.details.is_empty() {
+        text.push_str(&format!(": {}", vars.details));
+    }
+    if let Some(request_id) = vars.request_id {
+        text.push_str(&format!("\nRequest ID: {request_id}"));
+    }
+    text.push('\n');
+
+    text
+}
+
 pub const TEMPLATE: &str = formatcp!(
     r#"
 <!DOCTYPE html>
 <html>
 <head>
 	<style>{ERROR_CSS}</style>
-	<title>Error 502</title>
+	<title><!--ERROR HEADER--></title>
 	<style>
                 html{{
                         background-color: #f1c40f;
@@ -59,10 +221,46 @@ pub const E400: &str = str_replace!(
     "The 400 (Bad Request) status code indicates that the server cannot or will not process the request due to something that is perceived to be a client error (e.g., malformed request syntax, invalid request message framing, or deceptive request routing)."
 );
 
+pub const E403: &str = str_replace!(
+    str_replace!(TEMPLATE, "<!--ERROR HEADER-->", "Error 403 - Forbidden"),
+    "<!--ERROR DESCRIPTION-->",
+    "The 403 (Forbidden) status code indicates that the server understood the request but refuses to fulfill it."
+);
+
+pub const E404: &str = str_replace!(
+    str_replace!(TEMPLATE, "<!--ERROR HEADER-->", "Error 404 - Not Found"),
+    "<!--ERROR DESCRIPTION-->",
+    "The 404 (Not Found) status code indicates that the origin server did not find a current representation for the target resource or is not willing to disclose that one exists."
+);
+
+pub const E408: &str = str_replace!(
+    str_replace!(TEMPLATE, "<!--ERROR HEADER-->", "Error 408 - Request Timeout"),
+    "<!--ERROR DESCRIPTION-->",
+    "The 408 (Request Timeout) status code indicates that the server did not receive a complete request message within the time that it was prepared to wait."
+);
+
+pub const E413: &str = str_replace!(
+    str_replace!(TEMPLATE, "<!--ERROR HEADER-->", "Error 413 - Content Too Large"),
+    "<!--ERROR DESCRIPTION-->",
+    "The 413 (Content Too Large) status code indicates that the server is refusing to process a request because the request content is larger than the server is willing or able to process."
+);
+
+pub const E429: &str = str_replace!(
+    str_replace!(TEMPLATE, "<!--ERROR HEADER-->", "Error 429 - Too Many Requests"),
+    "<!--ERROR DESCRIPTION-->",
+    "The 429 (Too Many Requests) status code indicates that the user has sent too many requests in a given amount of time (\"rate limiting\")."
+);
+
+pub const E431: &str = str_replace!(
+    str_replace!(TEMPLATE, "<!--ERROR HEADER-->", "Error 431 - Request Header Fields Too Large"),
+    "<!--ERROR DESCRIPTION-->",
+    "The 431 (Request Header Fields Too Large) status code indicates that the server is unwilling to process the request because its header fields are too large."
+);
+
 pub const E500: &str = str_replace!(
-    str_replace!(TEMPLATE, "<!--ERROR HEADER-->", "Error 5400 - Internal Server Error"),
+    str_replace!(TEMPLATE, "<!--ERROR HEADER-->", "Error 500 - Internal Server Error"),
     "<!--ERROR DESCRIPTION-->",
-    "The 500(Internal Server Error) status code indicates that the server encountered an error and could not complete the request."
+    "The 500 (Internal Server Error) status code indicates that the server encountered an error and could not complete the request."
 );
 
 pub const E502: &str = str_replace!(
@@ -71,6 +269,18 @@ pub const E502: &str = str_replace!(
     "The 502 (Bad Gateway) status code indicates that the server, while acting as a gateway or proxy, received an invalid response from an inbound server it accessed while attempting to fulfill the request."
 );
 
+pub const E503: &str = str_replace!(
+    str_replace!(TEMPLATE, "<!--ERROR HEADER-->", "Error 503 - Service Unavailable"),
+    "<!--ERROR DESCRIPTION-->",
+    "The 503 (Service Unavailable) status code indicates that the server is currently unable to handle the request due to a temporary overload or scheduled maintenance, which will likely be alleviated after some delay."
+);
+
+pub const E504: &str = str_replace!(
+    str_replace!(TEMPLATE, "<!--ERROR HEADER-->", "Error 504 - Gateway Timeout"),
+    "<!--ERROR DESCRIPTION-->",
+    "The 504 (Gateway Timeout) status code indicates that the server, while acting as a gateway or proxy, did not receive a timely response from an upstream server it needed to access in order to complete the request."
+);
+
 pub const ERROR_CSS: &str = r#"
 html{background-color:#fefefe}body{font-family:Open Sans,Arial;color:#454545;font-size:16px;margin:2em auto;max-width:800px;padding:1em;line-height:1.4;text-align:justify}html.contrast body{color:#050505}html.contrast blockquote{color:#11151a}html.contrast blockquote:before{color:#262626}html.contrast a{color:#0051c9}html.contrast a:visited{color:#7d013e}html.contrast span.wr{color:#800}html.contrast span.mfw{color:#117e69}html.inverted{background-color:#010101}html.inverted body{color:#bababa}html.inverted div#invmode{color:#fff;background-color:#000}html.inverted blockquote{color:#dad0c7}html.inverted blockquote:before{color:#bfbfbf}html.inverted a{color:#07a}html.inverted a:visited{color:#ac5a82}html.inverted span.wr{color:#c0392b}html.inverted span.mfw{color:#19b496}a{color:#07a}a:visited{color:#941352}.noselect{-webkit-touch-callout:none;-webkit-user-select:none;-moz-user-select:none;-ms-user-select:none;user-select:none}span.citneed{vertical-align:top;font-size:.7em;padding-left:.3em}small{font-size:.4em}p.st{margin-top:-1em}div.fancyPositioning div.picture-left{float:left;width:40%;overflow:hidden;margin-right:1em}div.fancyPositioning div.picture-left img{width:100%}div.fancyPositioning div.picture-left p.caption{font-size:.7em}div.fancyPositioning div.tleft{float:left;width:55%}div.fancyPositioning div.tleft p:first-child{margin-top:0}div.fancyPositioning:after{display:block;content:"";clear:both}ul li img{height:1em}blockquote{color:#456;margin-left:0;margin-top:2em;margin-bottom:2em}blockquote span{float:left;margin-left:1rem;padding-top:1rem}blockquote author{display:block;clear:both;font-size:.6em;margin-left:2.4rem;font-style:oblique}blockquote author:before{content:"- ";margin-right:1em}blockquote:before{font-family:Times New Roman,Times,Arial;color:#666;content:open-quote;font-size:2.2em;font-weight:600;float:left;margin-top:0;margin-right:.2rem;width:1.2rem}blockquote:after{content:"";display:block;clear:both}@media screen and (max-width:500px){body{text-align:left}div.fancyPositioning div.picture-left,div.fancyPositioning div.tleft{float:none;width:inherit}blockquote span{width:80%}blockquote author{padding-top:1em;width:80%;margin-left:15%}blockquote author:before{content:"";margin-right:inherit}}span.visited{color:#941352}span.visited-maroon{color:#85144b}span.wr{color:#c0392b;font-weight:600;text-decoration:underline}div#contrast{color:#000;top:10px}div#contrast,div#invmode{cursor:pointer;position:absolute;right:10px;font-size:.8em;text-decoration:underline;-webkit-touch-callout:none;-webkit-user-select:none;-moz-user-select:none;-ms-user-select:none;user-select:none}div#invmode{color:#fff;background-color:#000;top:34px;padding:2px 5px}span.sb{color:#00e}span.sb,span.sv{cursor:not-allowed}span.sv{color:#551a8b}span.foufoufou{color:#444;font-weight:700}span.foufoufou:before{content:"";display:inline-block;width:1em;height:1em;margin-left:.2em;margin-right:.2em;background-color:#444}span.foufivfoufivfoufiv{color:#454545;font-weight:700}span.foufivfoufivfoufiv:before{content:"";display:inline-block;width:1em;height:1em;margin-left:.2em;margin-right:.2em;background-color:#454545}span.mfw{color:#16a085}a.kopimi,a.kopimi img.kopimi{display:block;margin-left:auto;margin-right:auto}a.kopimi img.kopimi{height:2em}p.fakepre{font-family:monospace;font-size:.9em}
 
@@ -92,3 +302,97 @@ html{
 	vertical-align: middle;
 }
 "#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NASTY: &str = r#"<script>alert("x")</script> & 'quoted'"#;
+
+    fn vars(details: &'static str, request_id: Option<&'static str>) -> PageVars<'static> {
+        PageVars {
+            status: StatusCode::BAD_GATEWAY,
+            details,
+            request_id,
+            host: None,
+        }
+    }
+
+    #[test]
+    fn negotiates_formats() {
+        for (accept, expected) in [
+            (None, ErrorFormat::Html),
+            (Some(""), ErrorFormat::Html),
+            (Some("application/problem+json"), ErrorFormat::Json),
+            (Some("application/json"), ErrorFormat::Json),
+            (Some("application/vnd.api+json"), ErrorFormat::Json),
+            (Some("text/plain"), ErrorFormat::Text),
+            (Some("text/plain;q=0.9, text/html"), ErrorFormat::Html),
+            (Some("text/html;q=0.5, TEXT/PLAIN"), ErrorFormat::Text),
+            (Some("*/*"), ErrorFormat::Html),
+            (Some("*/*, application/json"), ErrorFormat::Json),
+            (Some("application/json, */*"), ErrorFormat::Json),
+            (Some("image/png"), ErrorFormat::Html),
+            (
+                Some("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"),
+                ErrorFormat::Html,
+            ),
+            (Some("application/json;q=0"), ErrorFormat::Html),
+            (Some("text/plain;q=0, */*;q=0.1"), ErrorFormat::Html),
+            (
+                Some("application/json; q=0.0, text/plain;q=0.2"),
+                ErrorFormat::Text,
+            ),
+        ] {
+            assert_eq!(ErrorFormat::negotiate(accept), expected, "{accept:?}");
+        }
+    }
+
+    #[test]
+    fn escapes_html_pages() {
+        let page = builtin_page(&vars(NASTY, Some(NASTY)));
+
+        assert!(!page.contains("<script>"));
+        assert!(!page.contains(NASTY));
+        let escaped = "&lt;script&gt;alert(&quot;x&quot;)&lt;/script&gt; &amp; &#39;quoted&#39;";
+        assert!(page.contains(&format!("<p>Error: {escaped}</p>")));
+        assert!(page.contains(&format!("<p>Request ID: {escaped}</p>")));
+    }
+
+    #[test]
+    fn escapes_pages_without_their_own_template() {
+        let vars = PageVars {
+            status: StatusCode::IM_A_TEAPOT,
+            ..vars(NASTY, None)
+        };
+        let page = builtin_page(&vars);
+
+        assert!(page.contains("Error 418 - I'm a teapot"));
+        assert!(!page.contains("<script>"));
+    }
+
+    #[test]
+    fn problem_details_are_valid_json() {
+        let problem = problem_details(&vars(NASTY, Some(NASTY)));
+        let problem: serde_json::Value = serde_json::from_str(&problem).unwrap();
+
+        assert_eq!(problem["status"], 502);
+        assert_eq!(problem["title"], "Bad Gateway");
+        assert_eq!(problem["detail"], NASTY);
+        assert_eq!(problem["request_id"], NASTY);
+
+        let problem = problem_details(&vars("", None));
+        let problem: serde_json::Value = serde_json::from_str(&problem).unwrap();
+        assert!(problem.get("detail").is_none());
+        assert!(problem.get("request_id").is_none());
+    }
+
+    #[test]
+    fn plain_text_is_left_as_is() {
+        assert_eq!(
+            plain_text(&vars(NASTY, Some("abc"))),
+            format!("502 Bad Gateway: {NASTY}\nRequest ID: abc\n")
+        );
+        assert_eq!(plain_text(&vars("", None)), "502 Bad Gateway\n");
+    }
+}
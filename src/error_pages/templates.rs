@@ -0,0 +1,111 @@
+//! Custom error pages, loaded from a directory of templates
+//!
+//! Templates are looked up by status code (`404.html`), then by class (`4xx.html`), then
+//! `error.html`. These placeholders are filled in:
+//!
+//! - `{{status}}`: eg: `404`
+//! - `{{reason}}`: eg: `Not Found`
+//! - `{{details}}`: what went wrong, empty for intercepted backend errors
+//! - `{{request_id}}`
+//! - `{{host}}`: the host the client requested
+//! - `{{timestamp}}`: eg: `Sun, 06 Nov 1994 08:49:37 GMT`
+//!
+//! Values are html escaped.
+//!
+//! Templates are re-read when they change on disk, so they can be edited without a restart.
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use axum::http::StatusCode;
+use tokio::fs;
+use tracing::error;
+
+use super::PageVars;
+use crate::utils::html_escape;
+
+#[derive(Debug)]
+struct Cached {
+    modified: Option<SystemTime>,
+    template: Arc<str>,
+}
+
+#[derive(Debug)]
+pub struct Templates {
+    dir: PathBuf,
+    cache: Mutex<HashMap<PathBuf, Cached>>,
+}
+
+impl Templates {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            cache: Mutex::default(),
+        }
+    }
+
+    /// Renders the custom page for `vars.status`, if there's a template for it
+    pub async fn render(&self, vars: &PageVars<'_>) -> Option<String> {
+        let template = self.get(vars.status).await?;
+
+        let status = vars.status.as_u16().to_string();
+        let timestamp = httpdate::fmt_http_date(SystemTime::now());
+
+        let page = template
+            .replace("{{status}}", &status)
+            .replace("{{reason}}", vars.status.canonical_reason().unwrap_or(""))
+            .replace("{{request_id}}", &html_escape(vars.request_id.unwrap_or("")))
+            .replace("{{host}}", &html_escape(vars.host.unwrap_or("")))
+            .replace("{{timestamp}}", &timestamp)
+            // last, so placeholders in the details aren't filled in
+            .replace("{{details}}", &html_escape(vars.details));
+
+        Some(page)
+    }
+
+    async fn get(&self, status: StatusCode) -> Option<Arc<str>> {
+        let code = status.as_u16();
+        let names = [
+            format!("{code}.html"),
+            format!("{}xx.html", code / 100),
+            "error.html".to_owned(),
+        ];
+
+        for name in names {
+            let path = self.dir.join(name);
+
+            let Ok(meta) = fs::metadata(&path).await else {
+                continue;
+            };
+            let modified = meta.modified().ok();
+
+            if let Some(cached) = self.cache.lock().unwrap().get(&path)
+                && cached.modified.is_some()
+                && cached.modified == modified
+            {
+                return Some(cached.template.clone());
+            }
+
+            match fs::read_to_string(&path).await {
+                Ok(template) => {
+                    let template = Arc::<str>::from(template);
+                    let cached = Cached {
+                        modified,
+                        template: template.clone(),
+                    };
+
+                    self.cache.lock().unwrap().insert(path, cached);
+                    return Some(template);
+                }
+
+                Err(e) => error!("failed to read error template {}: {e}", path.display()),
+            }
+        }
+
+        None
+    }
+}
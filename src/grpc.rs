@@ -0,0 +1,129 @@
+//! gRPC support. Errors become gRPC statuses instead of pages, and gRPC-Web is translated to gRPC
+
+mod web;
+
+use std::fmt::Write as _;
+
+use axum::{
+    body::Body,
+    http::{
+        HeaderMap, HeaderName, HeaderValue, StatusCode,
+        header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE},
+        response::Parts,
+    },
+    response::Response,
+};
+
+pub use web::Web;
+
+pub const GRPC_STATUS: HeaderName = HeaderName::from_static("grpc-status");
+pub const GRPC_MESSAGE: HeaderName = HeaderName::from_static("grpc-message");
+
+/// The request's content type, if it's a gRPC or gRPC-Web request
+pub fn content_type(headers: &HeaderMap) -> Option<&HeaderValue> {
+    headers
+        .get(CONTENT_TYPE)
+        .filter(|c| c.as_bytes().starts_with(b"application/grpc"))
+}
+
+/// Turns an error response into a trailers-only gRPC response with the matching status
+///
+/// `content_type` is the request's, so gRPC-Web clients get a gRPC-Web response
+pub fn error_response(mut parts: Parts, content_type: HeaderValue, message: &str) -> Response {
+    let code = code(parts.status);
+
+    let message = if message.is_empty() {
+        parts.status.canonical_reason().unwrap_or_default()
+    } else {
+        message
+    };
+
+    parts.status = StatusCode::OK;
+    parts.headers.remove(CONTENT_LENGTH);
+    parts.headers.remove(CONTENT_ENCODING);
+    parts.headers.insert(CONTENT_TYPE, content_type);
+    parts.headers.insert(GRPC_STATUS, HeaderValue::from(code));
+
+    if let Ok(message) = HeaderValue::from_str(&encode_message(message)) {
+        parts.headers.insert(GRPC_MESSAGE, message);
+    }
+
+    Response::from_parts(parts, Body::empty())
+}
+
+/// The gRPC status code for an http status
+///
+/// See https://github.com/grpc/grpc/blob/master/doc/http-grpc-status-mapping.md
+fn code(status: StatusCode) -> u16 {
+    match status.as_u16() {
+        // INTERNAL
+        400 => 13,
+        // UNAUTHENTICATED
+        401 => 16,
+        // PERMISSION_DENIED
+        403 => 7,
+        // UNIMPLEMENTED
+        404 => 12,
+        // DEADLINE_EXCEEDED, the proxy's own timeouts
+        408 | 504 => 4,
+        // RESOURCE_EXHAUSTED
+        413 | 431 => 8,
+        // UNAVAILABLE
+        429 | 502 | 503 => 14,
+        // UNKNOWN
+        _ => 2,
+    }
+}
+
+/// Percent encodes grpc-message, as the spec requires for anything but printable ascii
+fn encode_message(message: &str) -> String {
+    let mut encoded = String::with_capacity(message.len());
+
+    for b in message.bytes() {
+        if (0x20..=0x7e).contains(&b) && b != b'%' {
+            encoded.push(b as char);
+        } else {
+            _ = write!(encoded, "%{b:02X}");
+        }
+    }
+
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Response as HttpResponse;
+
+    use super::*;
+
+    #[test]
+    fn maps_statuses() {
+        assert_eq!(code(StatusCode::UNAUTHORIZED), 16);
+        assert_eq!(code(StatusCode::REQUEST_TIMEOUT), 4);
+        assert_eq!(code(StatusCode::TOO_MANY_REQUESTS), 14);
+        assert_eq!(code(StatusCode::IM_A_TEAPOT), 2);
+    }
+
+    #[test]
+    fn encodes_messages() {
+        assert_eq!(encode_message("not found"), "not found");
+        assert_eq!(encode_message("100% dé"), "100%25 d%C3%A9");
+        assert_eq!(encode_message("a\nb"), "a%0Ab");
+    }
+
+    #[test]
+    fn makes_trailers_only_responses() {
+        let (mut parts, _) = HttpResponse::new(()).into_parts();
+        parts.status = StatusCode::PAYLOAD_TOO_LARGE;
+        parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(10));
+
+        let content_type = HeaderValue::from_static("application/grpc-web+proto");
+        let res = error_response(parts, content_type.clone(), "");
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CONTENT_TYPE], content_type);
+        assert_eq!(res.headers()[GRPC_STATUS], "8");
+        assert_eq!(res.headers()[GRPC_MESSAGE], "Payload Too Large");
+        assert!(!res.headers().contains_key(CONTENT_LENGTH));
+    }
+}
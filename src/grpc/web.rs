@@ -0,0 +1,438 @@
+//! gRPC-Web to gRPC translation, see https://github.com/grpc/grpc/blob/master/doc/PROTOCOL-WEB.md
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::Request,
+    http::{
+        HeaderMap, HeaderValue,
+        header::{CONTENT_LENGTH, CONTENT_TYPE, TE},
+    },
+    response::Response,
+};
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use hyper::body::Frame;
+
+// marks the message holding the trailers
+const TRAILERS_FLAG: u8 = 0x80;
+
+/// A gRPC-Web request, and how to translate it
+#[derive(Debug, Clone)]
+pub struct Web {
+    // base64 encoded, ie: application/grpc-web-text
+    text: bool,
+    // what follows the content type, eg: +proto
+    suffix: String,
+}
+
+impl Web {
+    /// Checks whether the request is a gRPC-Web one
+    pub fn detect(headers: &HeaderMap) -> Option<Self> {
+        let content_type = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+        let rest = content_type.strip_prefix("application/grpc-web")?;
+
+        let (text, suffix) = match rest.strip_prefix("-text") {
+            Some(suffix) => (true, suffix),
+            None => (false, rest),
+        };
+
+        if !(suffix.is_empty() || suffix.starts_with(['+', ';'])) {
+            return None;
+        }
+
+        Some(Self {
+            text,
+            suffix: suffix.to_owned(),
+        })
+    }
+
+    /// Turns the request into a gRPC one for the backend
+    pub fn request(&self, req: Request) -> Request {
+        let (mut parts, body) = req.into_parts();
+
+        if let Ok(content_type) = HeaderValue::from_str(&format!("application/grpc{}", self.suffix))
+        {
+            parts.headers.insert(CONTENT_TYPE, content_type);
+        }
+
+        // browsers can't send it, but gRPC backends need it
+        parts
+            .headers
+            .insert(TE, HeaderValue::from_static("trailers"));
+
+        let body = if self.text {
+            parts.headers.remove(CONTENT_LENGTH);
+            Body::new(TextBody::new(body))
+        } else {
+            body
+        };
+
+        Request::from_parts(parts, body)
+    }
+
+    /// Turns the backend's gRPC response into a gRPC-Web one, moving its trailers into the body
+    ///
+    /// Anything else, eg: an error page, is left alone
+    pub fn response(&self, res: Response) -> Response {
+        let Some(suffix) = res
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|c| c.to_str().ok())
+            .and_then(|c| c.strip_prefix("application/grpc"))
+            .filter(|s| !s.starts_with("-web"))
+        else {
+            return res;
+        };
+
+        let text = if self.text { "-text" } else { "" };
+        let content_type = HeaderValue::from_str(&format!("application/grpc-web{text}{suffix}"));
+
+        let (mut parts, body) = res.into_parts();
+        if let Ok(content_type) = content_type {
+            parts.headers.insert(CONTENT_TYPE, content_type);
+        }
+        parts.headers.remove(CONTENT_LENGTH);
+
+        Response::from_parts(parts, Body::new(WebBody::new(body, self.text)))
+    }
+}
+
+/// A gRPC response body in gRPC-Web's format, with the trailers sent as a final message
+struct WebBody {
+    body: Body,
+    text: bool,
+    // base64 works in groups of 3 bytes, so the rest waits for the next chunk
+    pending: Vec<u8>,
+    done: bool,
+}
+
+impl WebBody {
+    fn new(body: Body, text: bool) -> Self {
+        Self {
+            body,
+            text,
+            pending: Vec::new(),
+            done: false,
+        }
+    }
+
+    fn encode(&mut self, data: Bytes, last: bool) -> Bytes {
+        if !self.text {
+            return data;
+        }
+
+        self.pending.extend_from_slice(&data);
+
+        let len = if last {
+            self.pending.len()
+        } else {
+            self.pending.len() - self.pending.len() % 3
+        };
+
+        let encoded = STANDARD.encode(&self.pending[..len]);
+        self.pending.drain(..len);
+
+        Bytes::from(encoded)
+    }
+}
+
+impl HttpBody for WebBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        loop {
+            if self.done {
+                return Poll::Ready(None);
+            }
+
+            let data = match ready!(Pin::new(&mut self.body).poll_frame(cx)) {
+                Some(Ok(frame)) => match frame.into_data() {
+                    Ok(data) => self.encode(data, false),
+                    Err(frame) => match frame.into_trailers() {
+                        Ok(trailers) => self.encode(trailers_message(&trailers), false),
+                        Err(_) => continue,
+                    },
+                },
+
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+
+                None => {
+                    self.done = true;
+                    self.encode(Bytes::new(), true)
+                }
+            };
+
+            if !data.is_empty() {
+                return Poll::Ready(Some(Ok(Frame::data(data))));
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.done
+    }
+}
+
+/// Encodes trailers as the message gRPC-Web sends them in
+fn trailers_message(trailers: &HeaderMap) -> Bytes {
+    let mut block = Vec::new();
+    for (name, value) in trailers {
+        block.extend_from_slice(name.as_str().as_bytes());
+        block.extend_from_slice(b": ");
+        block.extend_from_slice(value.as_bytes());
+        block.extend_from_slice(b"\r\n");
+    }
+
+    let mut message = Vec::with_capacity(5 + block.len());
+    message.push(TRAILERS_FLAG);
+    message.extend_from_slice(&(block.len() as u32).to_be_bytes());
+    message.extend_from_slice(&block);
+
+    Bytes::from(message)
+}
+
+/// A gRPC-Web-Text request body, decoded from base64
+struct TextBody {
+    body: Body,
+    // base64 works in groups of 4 characters, so the rest waits for the next chunk
+    pending: Vec<u8>,
+}
+
+impl TextBody {
+    fn new(body: Body) -> Self {
+        Self {
+            body,
+            pending: Vec::new(),
+        }
+    }
+
+    fn decode(&mut self) -> Result<Vec<u8>, base64::DecodeError> {
+        let len = self.pending.len() - self.pending.len() % 4;
+
+        // padded chunks may be sent one after another, so each group is decoded on its own
+        let mut decoded = Vec::with_capacity(len / 4 * 3);
+        for group in self.pending[..len].chunks(4) {
+            STANDARD.decode_vec(group, &mut decoded)?;
+        }
+
+        self.pending.drain(..len);
+
+        Ok(decoded)
+    }
+}
+
+impl HttpBody for TextBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        loop {
+            match ready!(Pin::new(&mut self.body).poll_frame(cx)) {
+                Some(Ok(frame)) => {
+                    let data = match frame.into_data() {
+                        Ok(data) => data,
+                        Err(frame) => return Poll::Ready(Some(Ok(frame))),
+                    };
+
+                    self.pending
+                        .extend(data.iter().filter(|b| !b.is_ascii_whitespace()));
+
+                    match self.decode() {
+                        Ok(decoded) if decoded.is_empty() => continue,
+                        Ok(decoded) => return Poll::Ready(Some(Ok(Frame::data(decoded.into())))),
+                        Err(e) => return Poll::Ready(Some(Err(invalid(e)))),
+                    }
+                }
+
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+
+                None if self.pending.is_empty() => return Poll::Ready(None),
+
+                None => {
+                    self.pending.clear();
+                    return Poll::Ready(Some(Err(invalid("truncated base64"))));
+                }
+            }
+        }
+    }
+}
+
+fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> axum::Error {
+    axum::Error::new(io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use axum::http::HeaderName;
+
+    use super::*;
+
+    /// A body made of the given frames
+    struct Frames(VecDeque<Frame<Bytes>>);
+
+    impl HttpBody for Frames {
+        type Data = Bytes;
+        type Error = axum::Error;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+            Poll::Ready(self.0.pop_front().map(Ok))
+        }
+    }
+
+    fn chunks(chunks: &[&'static str]) -> Body {
+        let frames = chunks
+            .iter()
+            .map(|c| Frame::data(Bytes::from_static(c.as_bytes())));
+        Body::new(Frames(frames.collect()))
+    }
+
+    fn headers(content_type: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        headers
+    }
+
+    fn trailers() -> HeaderMap {
+        let mut trailers = HeaderMap::new();
+        trailers.insert(HeaderName::from_static("grpc-status"), HeaderValue::from(0));
+        trailers.insert(
+            HeaderName::from_static("grpc-message"),
+            HeaderValue::from_static("ok"),
+        );
+        trailers
+    }
+
+    fn grpc_response(message: &'static [u8]) -> Response {
+        let frames = [
+            Frame::data(Bytes::from_static(message)),
+            Frame::trailers(trailers()),
+        ];
+
+        let mut res = Response::new(Body::new(Frames(frames.into())));
+        *res.headers_mut() = headers("application/grpc+proto");
+        res.headers_mut()
+            .insert(CONTENT_LENGTH, HeaderValue::from(6));
+        res
+    }
+
+    async fn read(body: Body) -> Result<Bytes, axum::Error> {
+        axum::body::to_bytes(body, usize::MAX).await
+    }
+
+    #[test]
+    fn detects_web_requests() {
+        let web = Web::detect(&headers("application/grpc-web")).unwrap();
+        assert!(!web.text);
+        assert_eq!(web.suffix, "");
+
+        let web = Web::detect(&headers("application/grpc-web-text+proto")).unwrap();
+        assert!(web.text);
+        assert_eq!(web.suffix, "+proto");
+
+        let web = Web::detect(&headers("application/grpc-web;charset=utf-8")).unwrap();
+        assert_eq!(web.suffix, ";charset=utf-8");
+
+        assert!(Web::detect(&headers("application/grpc")).is_none());
+        assert!(Web::detect(&headers("application/grpc-webx")).is_none());
+        assert!(Web::detect(&HeaderMap::new()).is_none());
+    }
+
+    #[test]
+    fn encodes_trailers() {
+        let message = trailers_message(&trailers());
+        let block = b"grpc-status: 0\r\ngrpc-message: ok\r\n";
+
+        assert_eq!(message[0], TRAILERS_FLAG);
+        assert_eq!(message[1..5], (block.len() as u32).to_be_bytes());
+        assert_eq!(&message[5..], block);
+    }
+
+    #[tokio::test]
+    async fn translates_requests() {
+        let web = Web::detect(&headers("application/grpc-web-text+proto")).unwrap();
+
+        let mut req = Request::new(chunks(&["AAAA", "AAJo", "aQ=="]));
+        *req.headers_mut() = headers("application/grpc-web-text+proto");
+        req.headers_mut()
+            .insert(CONTENT_LENGTH, HeaderValue::from(12));
+
+        let req = web.request(req);
+        assert_eq!(req.headers()[CONTENT_TYPE], "application/grpc+proto");
+        assert_eq!(req.headers()[TE], "trailers");
+        assert!(!req.headers().contains_key(CONTENT_LENGTH));
+
+        let body = read(req.into_body()).await.unwrap();
+        assert_eq!(body, b"\0\0\0\0\x02hi"[..]);
+    }
+
+    #[tokio::test]
+    async fn decodes_text_across_chunks() {
+        // split mid group, with whitespace, and padded chunks one after another
+        let body = TextBody::new(chunks(&["aG", "k=\r\n", "aQ", "==", "aGk="]));
+        assert_eq!(read(Body::new(body)).await.unwrap(), "hiihi");
+
+        let truncated = TextBody::new(chunks(&["aGk"]));
+        assert!(read(Body::new(truncated)).await.is_err());
+
+        let invalid = TextBody::new(chunks(&["a!k="]));
+        assert!(read(Body::new(invalid)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn moves_trailers_into_the_body() {
+        let web = Web::detect(&headers("application/grpc-web+proto")).unwrap();
+
+        let res = web.response(grpc_response(b"\0\0\0\0\x01x"));
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/grpc-web+proto");
+        assert!(!res.headers().contains_key(CONTENT_LENGTH));
+
+        let mut expected = b"\0\0\0\0\x01x".to_vec();
+        expected.extend_from_slice(&trailers_message(&trailers()));
+        assert_eq!(read(res.into_body()).await.unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn encodes_text_responses() {
+        let web = Web::detect(&headers("application/grpc-web-text")).unwrap();
+
+        let res = web.response(grpc_response(b"\0\0\0\0\x01x"));
+        assert_eq!(
+            res.headers()[CONTENT_TYPE],
+            "application/grpc-web-text+proto"
+        );
+
+        let mut expected = b"\0\0\0\0\x01x".to_vec();
+        expected.extend_from_slice(&trailers_message(&trailers()));
+        let body = read(res.into_body()).await.unwrap();
+        assert_eq!(STANDARD.decode(&body).unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn leaves_other_responses_alone() {
+        let web = Web::detect(&headers("application/grpc-web")).unwrap();
+
+        let mut res = Response::new(Body::from("<h1>502</h1>"));
+        *res.headers_mut() = headers("text/html");
+
+        let res = web.response(res);
+        assert_eq!(res.headers()[CONTENT_TYPE], "text/html");
+        assert_eq!(read(res.into_body()).await.unwrap(), "<h1>502</h1>");
+    }
+}
@@ -0,0 +1,220 @@
+//! HTTP/3 over QUIC, served on the https port next to the tcp listener and routed the same way
+
+use std::{
+    future::poll_fn,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, ready},
+};
+
+use axum::{
+    Router,
+    body::{Body, Bytes, HttpBody},
+    extract::ConnectInfo,
+    http::{Request, Response},
+};
+use h3::{
+    error::{ConnectionError, StreamError},
+    server::{RequestResolver, RequestStream},
+};
+use hyper::body::{Buf as _, Frame};
+use quinn::{
+    Endpoint, Incoming,
+    crypto::rustls::{NoInitialCipherSuite, QuicServerConfig},
+};
+use rustls::ServerConfig;
+use snafu::{ResultExt, Snafu};
+use tokio::task;
+use tower_service::Service as _;
+use tracing::{debug, info};
+
+use crate::{
+    StateData,
+    listener::{self, Acceptor},
+};
+
+#[derive(Debug, Snafu)]
+pub enum Http3Error {
+    #[snafu(display("certificate can't be used for quic: {source}"))]
+    Tls { source: NoInitialCipherSuite },
+    #[snafu(display("{source}"))]
+    Io { source: io::Error },
+    #[snafu(display("{source}"))]
+    Quic { source: quinn::ConnectionError },
+    #[snafu(display("{source}"))]
+    Connection { source: ConnectionError },
+    #[snafu(display("{source}"))]
+    Stream { source: StreamError },
+    #[snafu(display("{source}"))]
+    Body { source: axum::Error },
+}
+
+pub async fn serve_http3(
+    data: Arc<StateData>,
+    router: Router,
+    tls: Arc<ServerConfig>,
+    acceptor: Acceptor,
+) -> Result<(), Http3Error> {
+    let mut tls = (*tls).clone();
+    tls.alpn_protocols = vec![b"h3".to_vec()];
+
+    let crypto = QuicServerConfig::try_from(tls).context(TlsSnafu)?;
+    let config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+
+    let addr = data.proxy_addr.ssl_addr();
+    let endpoint = Endpoint::server(config, addr).context(IoSnafu)?;
+
+    info!("Listening for http/3 on udp {addr}");
+
+    let max_header_size = listener::max_header_size(&data.config.limits) as u64;
+
+    while let Some(incoming) = endpoint.accept().await {
+        let ip = incoming.remote_address().ip();
+
+        // refused before the handshake, like tcp connections are
+        let Ok(permit) = acceptor.admit(ip) else {
+            incoming.refuse();
+            continue;
+        };
+
+        let router = router.clone();
+        task::spawn(async move {
+            if let Err(e) = connection(incoming, router, max_header_size).await {
+                debug!("http/3 connection from {ip} closed: {e}");
+            }
+
+            drop(permit);
+        });
+    }
+
+    Ok(())
+}
+
+async fn connection(
+    incoming: Incoming,
+    router: Router,
+    max_header_size: u64,
+) -> Result<(), Http3Error> {
+    let conn = incoming.await.context(QuicSnafu)?;
+    let remote = conn.remote_address();
+
+    let mut conn = h3::server::builder()
+        .max_field_section_size(max_header_size)
+        .build(h3_quinn::Connection::new(conn))
+        .await
+        .context(ConnectionSnafu)?;
+
+    loop {
+        match conn.accept().await {
+            Ok(Some(resolver)) => {
+                let router = router.clone();
+                task::spawn(async move {
+                    if let Err(e) = request(resolver, router, remote).await {
+                        debug!("http/3 request from {remote} failed: {e}");
+                    }
+                });
+            }
+
+            Ok(None) => return Ok(()),
+            Err(e) if e.is_h3_no_error() => return Ok(()),
+            Err(e) => return Err(e).context(ConnectionSnafu),
+        }
+    }
+}
+
+async fn request(
+    resolver: RequestResolver<h3_quinn::Connection, Bytes>,
+    mut router: Router,
+    remote: SocketAddr,
+) -> Result<(), Http3Error> {
+    let (req, stream) = resolver.resolve_request().await.context(StreamSnafu)?;
+    let (mut send, recv) = stream.split();
+
+    let (parts, ()) = req.into_parts();
+    let mut req = Request::from_parts(parts, Body::new(RecvBody::new(recv)));
+    req.extensions_mut().insert(ConnectInfo(remote));
+
+    // the router is always ready
+    let res = match router.call(req).await {
+        Ok(res) => res,
+        Err(never) => match never {},
+    };
+
+    let (parts, mut body) = res.into_parts();
+    send.send_response(Response::from_parts(parts, ()))
+        .await
+        .context(StreamSnafu)?;
+
+    while let Some(frame) = poll_fn(|cx| Pin::new(&mut body).poll_frame(cx)).await {
+        let frame = frame.context(BodySnafu)?;
+
+        match frame.into_data() {
+            Ok(data) => send.send_data(data).await.context(StreamSnafu)?,
+            Err(frame) => {
+                if let Ok(trailers) = frame.into_trailers() {
+                    send.send_trailers(trailers).await.context(StreamSnafu)?;
+                }
+            }
+        }
+    }
+
+    send.finish().await.context(StreamSnafu)
+}
+
+/// A request body read from an http/3 stream, trailers included
+struct RecvBody {
+    stream: RequestStream<h3_quinn::RecvStream, Bytes>,
+    data_done: bool,
+    done: bool,
+}
+
+impl RecvBody {
+    fn new(stream: RequestStream<h3_quinn::RecvStream, Bytes>) -> Self {
+        Self {
+            stream,
+            data_done: false,
+            done: false,
+        }
+    }
+}
+
+impl HttpBody for RecvBody {
+    type Data = Bytes;
+    type Error = StreamError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+
+        if !self.data_done {
+            match ready!(self.stream.poll_recv_data(cx)) {
+                Ok(Some(mut data)) => {
+                    let data = data.copy_to_bytes(data.remaining());
+                    return Poll::Ready(Some(Ok(Frame::data(data))));
+                }
+
+                Ok(None) => self.data_done = true,
+
+                Err(e) => {
+                    self.done = true;
+                    return Poll::Ready(Some(Err(e)));
+                }
+            }
+        }
+
+        let trailers = ready!(self.stream.poll_recv_trailers(cx));
+        self.done = true;
+
+        Poll::Ready(trailers.transpose().map(|t| t.map(Frame::trailers)))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.done
+    }
+}
@@ -0,0 +1,7 @@
+mod body;
+mod concurrency;
+mod rate;
+
+pub use body::{BodyLimitError, LimitedBody};
+pub use concurrency::{Concurrency, LimitError, Permit};
+pub use rate::RateLimiter;
@@ -0,0 +1,190 @@
+//! Request bodies which can't be bigger or slower than the configured limits
+
+use std::{
+    error::Error,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    http::StatusCode,
+};
+use hyper::body::{Frame, SizeHint};
+use snafu::Snafu;
+use tokio::time::{self, Instant, Sleep};
+
+#[derive(Debug, Snafu)]
+pub enum BodyLimitError {
+    #[snafu(display("request body is bigger than {max} bytes"))]
+    TooLarge { max: u64 },
+    #[snafu(display("request body sent nothing for {}s", timeout.as_secs()))]
+    Idle { timeout: Duration },
+}
+
+impl BodyLimitError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::Idle { .. } => StatusCode::REQUEST_TIMEOUT,
+        }
+    }
+
+    /// Finds a body limit error in the chain of errors it caused, eg: a failed backend request
+    pub fn find<'a>(error: &'a (dyn Error + 'static)) -> Option<&'a Self> {
+        let mut source = Some(error);
+        while let Some(e) = source {
+            if let Some(e) = e.downcast_ref::<Self>() {
+                return Some(e);
+            }
+
+            source = e.source();
+        }
+
+        None
+    }
+}
+
+pub struct LimitedBody {
+    body: Body,
+    max_size: Option<u64>,
+    read: u64,
+    idle_timeout: Option<Duration>,
+    // started on the first poll, so time before anyone reads the body doesn't count
+    idle: Option<Pin<Box<Sleep>>>,
+}
+
+impl LimitedBody {
+    pub fn new(body: Body, max_size: Option<u64>, idle_timeout: Option<Duration>) -> Self {
+        Self {
+            body,
+            max_size,
+            read: 0,
+            idle_timeout,
+            idle: None,
+        }
+    }
+}
+
+impl HttpBody for LimitedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = match Pin::new(&mut self.body).poll_frame(cx) {
+            Poll::Ready(frame) => frame,
+
+            Poll::Pending => {
+                let Some(timeout) = self.idle_timeout else {
+                    return Poll::Pending;
+                };
+
+                let sleep = self
+                    .idle
+                    .get_or_insert_with(|| Box::pin(time::sleep(timeout)));
+                if sleep.as_mut().poll(cx).is_ready() {
+                    let error = IdleSnafu { timeout }.build();
+                    return Poll::Ready(Some(Err(axum::Error::new(error))));
+                }
+
+                return Poll::Pending;
+            }
+        };
+
+        if let (Some(timeout), Some(sleep)) = (self.idle_timeout, &mut self.idle) {
+            sleep.as_mut().reset(Instant::now() + timeout);
+        }
+
+        if let Some(Ok(frame)) = &frame
+            && let Some(data) = frame.data_ref()
+        {
+            self.read += data.len() as u64;
+
+            if let Some(max) = self.max_size
+                && self.read > max
+            {
+                return Poll::Ready(Some(Err(axum::Error::new(TooLargeSnafu { max }.build()))));
+            }
+        }
+
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, io};
+
+    use futures::{StreamExt as _, stream};
+
+    use super::*;
+
+    async fn read(body: LimitedBody) -> Result<Bytes, axum::Error> {
+        axum::body::to_bytes(Body::new(body), usize::MAX).await
+    }
+
+    fn limit_error(error: &axum::Error) -> &BodyLimitError {
+        BodyLimitError::find(error).expect("a body limit error")
+    }
+
+    #[tokio::test]
+    async fn limits_size() {
+        let body = LimitedBody::new(Body::from("hello"), Some(5), None);
+        assert_eq!(read(body).await.unwrap(), "hello");
+
+        let body = LimitedBody::new(Body::from("hello world"), Some(5), None);
+        let error = read(body).await.unwrap_err();
+        assert!(matches!(
+            limit_error(&error),
+            BodyLimitError::TooLarge { max: 5 }
+        ));
+        assert_eq!(limit_error(&error).status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn times_out_idle_bodies() {
+        let quiet = stream::pending::<Result<Bytes, io::Error>>();
+        let body = LimitedBody::new(
+            Body::from_stream(quiet),
+            None,
+            Some(Duration::from_millis(50)),
+        );
+
+        let error = read(body).await.unwrap_err();
+        assert!(matches!(limit_error(&error), BodyLimitError::Idle { .. }));
+        assert_eq!(limit_error(&error).status(), StatusCode::REQUEST_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn idle_time_starts_when_read() {
+        let chunks = stream::iter([Ok::<_, Infallible>("a"), Ok("b")]);
+        let slow = chunks.then(|chunk| async move {
+            time::sleep(Duration::from_millis(30)).await;
+            chunk
+        });
+
+        let body = LimitedBody::new(
+            Body::from_stream(slow),
+            None,
+            Some(Duration::from_millis(50)),
+        );
+
+        // longer than the timeout, but nobody was reading yet
+        time::sleep(Duration::from_millis(100)).await;
+
+        // and each chunk arrives before it runs out
+        assert_eq!(read(body).await.unwrap(), "ab");
+    }
+}
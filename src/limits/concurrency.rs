@@ -0,0 +1,86 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+use snafu::Snafu;
+
+#[derive(Debug, Snafu)]
+pub enum LimitError {
+    #[snafu(display("{max} are already open"))]
+    Full { max: usize },
+    #[snafu(display("{max} are already open from {ip}"))]
+    FullForIp { ip: IpAddr, max: usize },
+}
+
+#[derive(Debug, Default)]
+struct Open {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Counts open connections (or sessions), globally and per client ip
+#[derive(Debug)]
+pub struct Concurrency {
+    max: Option<usize>,
+    max_per_ip: Option<usize>,
+    open: Mutex<Open>,
+}
+
+impl Concurrency {
+    pub fn new(max: Option<usize>, max_per_ip: Option<usize>) -> Arc<Self> {
+        Arc::new(Self {
+            max,
+            max_per_ip,
+            open: Mutex::default(),
+        })
+    }
+
+    /// Counts a new connection from `ip`, which stays open until the permit is dropped
+    pub fn acquire(self: &Arc<Self>, ip: IpAddr) -> Result<Permit, LimitError> {
+        let mut open = self.open.lock().unwrap();
+
+        if let Some(max) = self.max
+            && open.total >= max
+        {
+            return FullSnafu { max }.fail();
+        }
+
+        let from_ip = open.per_ip.entry(ip).or_default();
+        if let Some(max) = self.max_per_ip
+            && *from_ip >= max
+        {
+            return FullForIpSnafu { ip, max }.fail();
+        }
+
+        *from_ip += 1;
+        open.total += 1;
+
+        Ok(Permit {
+            owner: self.clone(),
+            ip,
+        })
+    }
+}
+
+/// An open connection, counted until dropped
+#[derive(Debug)]
+pub struct Permit {
+    owner: Arc<Concurrency>,
+    ip: IpAddr,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut open = self.owner.open.lock().unwrap();
+        open.total -= 1;
+
+        if let Some(from_ip) = open.per_ip.get_mut(&self.ip) {
+            *from_ip -= 1;
+            if *from_ip == 0 {
+                open.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::http::HeaderMap;
+
+use crate::{
+    config::{RateLimitKey, RateLimitRule},
+    utils::path_matches,
+};
+
+// once this many buckets exist, full ones are dropped, since they're the same as a new bucket
+// the threshold then grows with what's left, so pruning's cost is spread over the inserts
+const PRUNE_AT: usize = 10_000;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug)]
+struct Buckets {
+    map: HashMap<String, Bucket>,
+    prune_at: usize,
+}
+
+impl Default for Buckets {
+    fn default() -> Self {
+        Self {
+            map: HashMap::new(),
+            prune_at: PRUNE_AT,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Limit {
+    rule: RateLimitRule,
+    buckets: Mutex<Buckets>,
+}
+
+impl Limit {
+    fn capacity(&self) -> f64 {
+        f64::from(self.rule.burst)
+    }
+
+    /// Takes a token from `key`'s bucket, or returns how long until one is available
+    fn take(&self, key: String) -> Result<(), Duration> {
+        let now = Instant::now();
+        let capacity = self.capacity();
+        let rate = self.rule.rate;
+
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.map.len() >= buckets.prune_at {
+            buckets.map.retain(|_, b| {
+                b.tokens + now.duration_since(b.updated).as_secs_f64() * rate < capacity
+            });
+
+            buckets.prune_at = PRUNE_AT.max(buckets.map.len() * 2);
+        }
+
+        let bucket = buckets.map.entry(key).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            // a tiny rate can mean longer than a Duration holds
+            let wait = (1.0 - bucket.tokens) / rate;
+            Err(Duration::try_from_secs_f64(wait).unwrap_or(Duration::MAX))
+        }
+    }
+}
+
+/// Token bucket rate limits
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: Vec<Limit>,
+}
+
+impl RateLimiter {
+    pub fn new(rules: &[RateLimitRule]) -> Self {
+        let limits = rules
+            .iter()
+            .map(|rule| Limit {
+                rule: rule.clone(),
+                buckets: Mutex::default(),
+            })
+            .collect();
+
+        Self { limits }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.limits.is_empty()
+    }
+
+    /// Counts a request against every rule it matches
+    ///
+    /// Returns how long the client should wait if any of them are exhausted
+    pub fn check(&self, ip: IpAddr, path: &str, headers: &HeaderMap) -> Result<(), Duration> {
+        let mut retry_after = None;
+
+        for limit in &self.limits {
+            let rule = &limit.rule;
+
+            if rule.path.as_ref().is_some_and(|p| !path_matches(path, p)) {
+                continue;
+            }
+
+            let key = match rule.key {
+                RateLimitKey::Ip => ip.to_string(),
+                RateLimitKey::Header => rule
+                    .header
+                    .as_ref()
+                    .and_then(|h| headers.get(h))
+                    .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+                    .unwrap_or_default(),
+                RateLimitKey::Route => String::new(),
+            };
+
+            if let Err(wait) = limit.take(key) {
+                retry_after = retry_after.max(Some(wait));
+            }
+        }
+
+        match retry_after {
+            Some(wait) => Err(wait),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn rule(key: RateLimitKey, path: Option<&str>, rate: f64, burst: u32) -> RateLimitRule {
+        RateLimitRule {
+            key,
+            header: Some("x-api-key".to_owned()),
+            path: path.map(str::to_owned),
+            rate,
+            burst,
+        }
+    }
+
+    fn limit(rate: f64, burst: u32) -> Limit {
+        Limit {
+            rule: rule(RateLimitKey::Ip, None, rate, burst),
+            buckets: Mutex::default(),
+        }
+    }
+
+    #[test]
+    fn burst_then_limited() {
+        let limit = limit(1.0, 3);
+
+        for _ in 0..3 {
+            assert!(limit.take("a".to_owned()).is_ok());
+        }
+
+        let wait = limit.take("a".to_owned()).unwrap_err();
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+
+        // other keys have their own bucket
+        assert!(limit.take("b".to_owned()).is_ok());
+    }
+
+    #[test]
+    fn refills_at_rate() {
+        let limit = limit(1000.0, 1);
+
+        assert!(limit.take("a".to_owned()).is_ok());
+        assert!(limit.take("a".to_owned()).is_err());
+
+        thread::sleep(Duration::from_millis(5));
+        assert!(limit.take("a".to_owned()).is_ok());
+    }
+
+    #[test]
+    fn tiny_rate_does_not_overflow() {
+        let limit = limit(f64::MIN_POSITIVE, 1);
+
+        assert!(limit.take("a".to_owned()).is_ok());
+        assert_eq!(limit.take("a".to_owned()), Err(Duration::MAX));
+    }
+
+    #[test]
+    fn prune_threshold_grows() {
+        let limit = limit(1.0, 1);
+
+        // every bucket is empty, so none can be pruned
+        for i in 0..PRUNE_AT + 1 {
+            _ = limit.take(i.to_string());
+        }
+
+        let buckets = limit.buckets.lock().unwrap();
+        assert_eq!(buckets.map.len(), PRUNE_AT + 1);
+        assert_eq!(buckets.prune_at, PRUNE_AT * 2);
+    }
+
+    #[test]
+    fn prunes_full_buckets() {
+        let limit = limit(1_000_000.0, 1);
+
+        for i in 0..PRUNE_AT {
+            _ = limit.take(i.to_string());
+        }
+
+        // they've all refilled by now
+        thread::sleep(Duration::from_millis(5));
+        _ = limit.take("last".to_owned());
+
+        let buckets = limit.buckets.lock().unwrap();
+        assert_eq!(buckets.map.len(), 1);
+        assert_eq!(buckets.prune_at, PRUNE_AT);
+    }
+
+    #[test]
+    fn check_matches_rules() {
+        let limiter = RateLimiter::new(&[
+            rule(RateLimitKey::Route, Some("/api/"), 1.0, 1),
+            rule(RateLimitKey::Header, None, 1.0, 2),
+        ]);
+        let ip = IpAddr::from([127, 0, 0, 1]);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-key", "k1".parse().unwrap());
+
+        assert!(limiter.check(ip, "/api/a", &headers).is_ok());
+        // the route's bucket is empty
+        assert!(limiter.check(ip, "/api/b", &HeaderMap::new()).is_err());
+        // only the header rule applies here, and k1 has a token left
+        assert!(limiter.check(ip, "/other", &headers).is_ok());
+        assert!(limiter.check(ip, "/other", &headers).is_err());
+    }
+}
@@ -0,0 +1,201 @@
+//! Checks done on connections as they're accepted, before any tls or http, and their timeouts
+
+mod idle;
+
+use std::{
+    future::{Ready, ready},
+    io,
+    net::IpAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum_server::accept::Accept;
+use hyper_util::{
+    rt::{TokioExecutor, TokioTimer},
+    server::conn::auto::Builder,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tracing::debug;
+
+use crate::{
+    config::{AccessList, Limits},
+    limits::{Concurrency, Permit},
+};
+use idle::{Activity, IdleTimer, Tracked};
+
+#[derive(Debug, Clone)]
+pub struct Acceptor {
+    access: Arc<AccessList>,
+    connections: Arc<Concurrency>,
+    keep_alive_timeout: Option<Duration>,
+}
+
+impl Acceptor {
+    pub fn new(
+        access: AccessList,
+        connections: Arc<Concurrency>,
+        keep_alive_timeout: Option<Duration>,
+    ) -> Self {
+        Self {
+            access: Arc::new(access),
+            connections,
+            keep_alive_timeout,
+        }
+    }
+
+    /// Checks a client may connect, returning the permit to hold for as long as it's connected
+    pub fn admit(&self, ip: IpAddr) -> io::Result<Permit> {
+        if !self.access.permits(ip) {
+            debug!("refused connection from {ip}: not allowed");
+            return Err(io::Error::from(io::ErrorKind::PermissionDenied));
+        }
+
+        self.connections.acquire(ip).map_err(|e| {
+            debug!("refused connection from {ip}: {e}");
+            io::Error::other(e)
+        })
+    }
+}
+
+impl<S> Accept<TcpStream, S> for Acceptor {
+    type Stream = Counted;
+    type Service = Tracked<S>;
+    type Future = Ready<io::Result<(Counted, Tracked<S>)>>;
+
+    fn accept(&self, stream: TcpStream, service: S) -> Self::Future {
+        let ip = match stream.peer_addr() {
+            Ok(addr) => addr.ip(),
+            Err(e) => return ready(Err(e)),
+        };
+
+        // an error drops the connection
+        let permit = match self.admit(ip) {
+            Ok(permit) => permit,
+            Err(e) => return ready(Err(e)),
+        };
+
+        let activity = Activity::new();
+
+        ready(Ok((
+            Counted {
+                stream,
+                _permit: permit,
+                activity: activity.clone(),
+                idle: self.keep_alive_timeout.map(IdleTimer::new),
+            },
+            Tracked::new(service, activity),
+        )))
+    }
+}
+
+/// Sets the http timeouts and size limits which hyper enforces itself
+pub fn configure(builder: &mut Builder<TokioExecutor>, limits: &Limits) {
+    let max_header_size = max_header_size(limits);
+
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(limits.header_read_timeout.map(Duration::from_secs))
+        .max_buf_size(max_header_size);
+
+    builder
+        .http2()
+        .max_header_list_size(max_header_size.try_into().unwrap_or(u32::MAX));
+}
+
+/// Bigger headers than the limit are still read, so they get a proper 431 page. This is the hard
+/// limit for ones so big they aren't worth reading
+pub fn max_header_size(limits: &Limits) -> usize {
+    limits.max_header_size.saturating_mul(2).max(8192)
+}
+
+/// A connection which is counted until it closes, and closed once it's idle for too long
+#[derive(Debug)]
+pub struct Counted {
+    stream: TcpStream,
+    _permit: Permit,
+    activity: Arc<Activity>,
+    idle: Option<IdleTimer>,
+}
+
+impl AsyncRead for Counted {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+
+        match Pin::new(&mut self.stream).poll_read(cx, buf) {
+            Poll::Ready(res) => {
+                if buf.filled().len() > filled {
+                    self.activity.touch();
+                }
+
+                Poll::Ready(res)
+            }
+
+            Poll::Pending => {
+                let this = &mut *self;
+                let expired = this
+                    .idle
+                    .as_mut()
+                    .is_some_and(|idle| idle.poll_expired(&this.activity, cx).is_ready());
+
+                if expired {
+                    // reading nothing ends the connection
+                    debug!("closing idle connection");
+                    return Poll::Ready(Ok(()));
+                }
+
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl AsyncWrite for Counted {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = Pin::new(&mut self.stream).poll_write(cx, buf);
+        if let Poll::Ready(Ok(1..)) = written {
+            self.activity.touch();
+        }
+
+        written
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let written = Pin::new(&mut self.stream).poll_write_vectored(cx, bufs);
+        if let Poll::Ready(Ok(1..)) = written {
+            self.activity.touch();
+        }
+
+        written
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+}
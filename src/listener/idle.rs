@@ -0,0 +1,179 @@
+//! Keep-alive idle timeouts. A connection is idle when it has no requests in flight and hasn't
+//! sent or received anything for a while
+
+use std::{
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    BoxError,
+    body::{Body, Bytes, HttpBody},
+    http::{Request, Response, StatusCode},
+};
+use futures::future::BoxFuture;
+use hyper::body::{Frame, SizeHint};
+use tokio::time::{self, Instant, Sleep};
+use tower_service::Service;
+
+/// What's happening on a connection, shared by its stream and service
+#[derive(Debug)]
+pub struct Activity {
+    start: Instant,
+    /// when it last sent or received anything, in millis since start
+    last: AtomicU64,
+    in_flight: AtomicUsize,
+    /// upgraded connections (websockets) have their own timeouts
+    upgraded: AtomicBool,
+}
+
+impl Activity {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+            in_flight: AtomicUsize::new(0),
+            upgraded: AtomicBool::new(false),
+        })
+    }
+
+    pub fn touch(&self) {
+        let now = self.start.elapsed().as_millis() as u64;
+        self.last.store(now, Ordering::Relaxed);
+    }
+
+    /// When the connection times out, if it's idle
+    fn idle_deadline(&self, timeout: Duration) -> Option<Instant> {
+        if self.in_flight.load(Ordering::Acquire) > 0 || self.upgraded.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        let last = Duration::from_millis(self.last.load(Ordering::Relaxed));
+        Some(self.start + last + timeout)
+    }
+}
+
+/// Closes a connection's reads once it's been idle too long
+#[derive(Debug)]
+pub struct IdleTimer {
+    timeout: Duration,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl IdleTimer {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            sleep: Box::pin(time::sleep(timeout)),
+        }
+    }
+
+    /// Polled when a read is pending. Ready when the connection should be closed
+    pub fn poll_expired(&mut self, activity: &Activity, cx: &mut Context<'_>) -> Poll<()> {
+        // requests in flight will finish with a write or read, which polls this again
+        let Some(deadline) = activity.idle_deadline(self.timeout) else {
+            return Poll::Pending;
+        };
+
+        self.sleep.as_mut().reset(deadline);
+        self.sleep.as_mut().poll(cx)
+    }
+}
+
+/// Counts a connection's requests as in flight until their responses are sent
+#[derive(Debug, Clone)]
+pub struct Tracked<S> {
+    inner: S,
+    activity: Arc<Activity>,
+}
+
+impl<S> Tracked<S> {
+    pub fn new(inner: S, activity: Arc<Activity>) -> Self {
+        Self { inner, activity }
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for Tracked<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+    ResBody: HttpBody<Data = Bytes> + Send + 'static,
+    ResBody::Error: Into<BoxError>,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let guard = InFlight::new(self.activity.clone());
+        let future = self.inner.call(req);
+
+        Box::pin(async move {
+            let res = future.await?;
+
+            if res.status() == StatusCode::SWITCHING_PROTOCOLS {
+                guard.activity.upgraded.store(true, Ordering::Relaxed);
+            }
+
+            Ok(res.map(|body| {
+                Body::new(InFlightBody {
+                    body: Body::new(body),
+                    _guard: guard,
+                })
+            }))
+        })
+    }
+}
+
+struct InFlight {
+    activity: Arc<Activity>,
+}
+
+impl InFlight {
+    fn new(activity: Arc<Activity>) -> Self {
+        activity.in_flight.fetch_add(1, Ordering::AcqRel);
+        Self { activity }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.activity.touch();
+        self.activity.in_flight.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// A response body, which keeps its request in flight until it's sent
+struct InFlightBody {
+    body: Body,
+    _guard: InFlight,
+}
+
+impl HttpBody for InFlightBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        Pin::new(&mut self.body).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
@@ -1,33 +1,72 @@
+mod access;
+mod auth;
+mod cache;
+mod compression;
 mod config;
 mod error_pages;
+mod grpc;
+mod http3;
+mod limits;
+mod listener;
 mod middleware;
 mod proxy;
 mod redirect;
+mod request_id;
+mod rewrite;
 mod utils;
 mod websocket;
 
-use std::{env, net::SocketAddr, sync::Arc};
+use std::{env, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
-use axum::{Router, body::Body, middleware as amiddleware, routing::get};
-use axum_server::tls_rustls::RustlsConfig;
-use hyper_util::{
-    client::legacy::{Client, connect::HttpConnector},
-    rt::TokioExecutor,
+use axum::{
+    Router,
+    http::{
+        HeaderName, HeaderValue,
+        header::{InvalidHeaderName, InvalidHeaderValue},
+        uri::InvalidUri,
+    },
+    middleware as amiddleware,
+    routing::get,
 };
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
 use snafu::{OptionExt, ResultExt, Snafu};
 use tokio::task;
 use tracing::{error, info, level_filters::LevelFilter};
 use tracing_subscriber::{EnvFilter, fmt, prelude::*};
 use url::{ParseError, Url};
 
-use crate::config::{Config, ConfigError, ProxyAddr};
+use crate::{
+    auth::{AuthClient, ForwardAuth, ForwardAuthError, Htpasswd, Oidc, OidcError},
+    cache::Cache,
+    config::{Config, ConfigError, ProxyAddr},
+    error_pages::Templates,
+    limits::{Concurrency, RateLimiter},
+    listener::Acceptor,
+};
+use proxy::Upstream;
 use redirect::redirect_http;
+use rewrite::Rewriter;
+use websocket::{ReplayError, WsLogger};
 
 #[derive(Debug)]
 pub struct StateData {
-    client: Client<HttpConnector, Body>,
     config: Config,
+    proxy_addr: ProxyAddr,
+    upstream: Upstream,
+    security_headers: Vec<(HeaderName, HeaderValue)>,
+    error_templates: Option<Templates>,
+    htpasswd: Option<Htpasswd>,
+    auth_client: AuthClient,
+    forward_auth: Option<ForwardAuth>,
+    oidc: Option<Oidc>,
+    request_id_header: HeaderName,
+    rate_limiter: RateLimiter,
+    websocket_sessions: Arc<Concurrency>,
+    rewrite: Rewriter,
+    cache: Option<Cache>,
     websocket_destination: Option<Url>,
+    websocket_log: WsLogger,
+    websocket_record_dir: Option<PathBuf>,
 }
 
 #[derive(Snafu, Debug)]
@@ -44,6 +83,22 @@ enum AppError {
     NoCurrentExe,
     #[snafu(display("{source}"))]
     Config { source: ConfigError },
+    #[snafu(display("invalid websocket redact regex: {source}"))]
+    Regex { source: regex::Error },
+    #[snafu(display("{source}"))]
+    Replay { source: ReplayError },
+    #[snafu(display("invalid security header: {source}"))]
+    SecurityHeader { source: InvalidHeaderValue },
+    #[snafu(display("{source}"))]
+    ForwardAuth { source: ForwardAuthError },
+    #[snafu(display("{source}"))]
+    Oidc { source: OidcError },
+    #[snafu(display("invalid backend address: {source}"))]
+    Backend { source: InvalidUri },
+    #[snafu(display("invalid request id header: {source}"))]
+    RequestIdHeader { source: InvalidHeaderName },
+    #[snafu(display("failed to open cache: {source}"))]
+    Cache { source: std::io::Error },
 
     #[snafu(whatever, display("{message}"))]
     Whatever {
@@ -75,25 +130,71 @@ fn setup() -> Result<(), AppError> {
 async fn main() -> Result<(), AppError> {
     setup()?;
 
-    let client = Client::builder(TokioExecutor::new()).build_http();
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if let Some(("replay", args)) = args.split_first().map(|(c, a)| (c.as_str(), a)) {
+        return websocket::replay(args).await.context(ReplaySnafu);
+    }
 
     let config = config::Config::get_config().context(ConfigSnafu)?;
+
+    // get server config for rust
+    let exe_path = env::current_exe().map_err(|_| AppError::NoCurrentExe)?;
+    let exe_path = exe_path.parent().context(NoParentSnafu)?;
+
     let data = Arc::new(StateData {
-        client,
         websocket_destination: if let Some(path) = &config.addresses.websocket_path {
             let addr = format!("ws://{}{path}", config.addresses.backend);
             Some(Url::parse(&addr).context(ParseFailureSnafu)?)
         } else {
             None
         },
+        websocket_log: WsLogger::new(&config.websocket).context(RegexSnafu)?,
+        websocket_record_dir: config
+            .websocket
+            .record_dir
+            .as_ref()
+            .map(|dir| exe_path.join(dir)),
+        proxy_addr: config.proxy_addr().context(ConfigSnafu)?,
+        security_headers: middleware::build_security_headers(&config.security_headers)
+            .context(SecurityHeaderSnafu)?,
+        error_templates: config
+            .error_pages
+            .dir
+            .as_ref()
+            .map(|dir| Templates::new(exe_path.join(dir))),
+        htpasswd: config
+            .basic_auth
+            .htpasswd
+            .as_ref()
+            .map(|path| Htpasswd::new(exe_path.join(path))),
+        auth_client: auth::auth_client(),
+        forward_auth: match &config.forward_auth.url {
+            Some(url) => Some(ForwardAuth::new(&config.forward_auth, url).context(ForwardAuthSnafu)?),
+            None => None,
+        },
+        oidc: match &config.oidc.issuer {
+            Some(issuer) => Some(Oidc::new(&config.oidc, issuer).context(OidcSnafu)?),
+            None => None,
+        },
+        request_id_header: HeaderName::try_from(&config.request_id.header)
+            .context(RequestIdHeaderSnafu)?,
+        rate_limiter: RateLimiter::new(&config.limits.rate),
+        websocket_sessions: Concurrency::new(
+            config.limits.max_websockets,
+            config.limits.max_websockets_per_ip,
+        ),
+        rewrite: Rewriter::new(&config),
+        cache: if config.cache.enabled {
+            Some(Cache::new(&config.cache, exe_path.join(&config.cache.dir)).context(CacheSnafu)?)
+        } else {
+            None
+        },
+        upstream: Upstream::new(&config.upstream, &config.addresses.backend)
+            .context(BackendSnafu)?,
         config,
     });
 
-    let proxy_addr = data.config.proxy_addr().context(ConfigSnafu)?;
-
-    // get server config for rust
-    let exe_path = env::current_exe().map_err(|_| AppError::NoCurrentExe)?;
-    let exe_path = exe_path.parent().context(NoParentSnafu)?;
+    let proxy_addr = data.proxy_addr;
 
     let ssl_config = RustlsConfig::from_pem_file(
         exe_path.join(&data.config.addresses.ssl_cert),
@@ -102,27 +203,69 @@ async fn main() -> Result<(), AppError> {
     .await
     .context(IoSnafu)?;
 
-    //
+    if data.config.options.disable_http2 {
+        let mut tls = (*ssl_config.get_inner()).clone();
+        tls.alpn_protocols = vec![b"http/1.1".to_vec()];
+        ssl_config.reload_from_config(Arc::new(tls));
+    }
 
-    info!(
-        "Listening on http://{proxy_addr}:{proxy_port} and https://{proxy_addr}:{ssl_port} for service http://{backend}",
-        backend = data.config.addresses.backend,
-        proxy_addr = proxy_addr.addr,
-        proxy_port = proxy_addr.http_port,
-        ssl_port = proxy_addr.ssl_port
+    // shared by both listeners, so the limits apply to a client's connections across them
+    let acceptor = Acceptor::new(
+        data.config.access.listener.clone(),
+        Concurrency::new(
+            data.config.limits.max_connections,
+            data.config.limits.max_connections_per_ip,
+        ),
+        data.config.limits.keep_alive_timeout.map(Duration::from_secs),
     );
 
     let router = make_route(proxy_addr, data.clone());
 
-    // serve http endpoint which redirects to https
-    task::spawn(async move {
-        if let Err(e) = redirect_http(proxy_addr).await {
-            error!("{e}");
-        }
-    });
+    if data.config.redirect.enabled {
+        info!(
+            "Listening on http://{proxy_addr}:{proxy_port} and https://{proxy_addr}:{ssl_port} for service http://{backend}",
+            backend = data.config.addresses.backend,
+            proxy_addr = proxy_addr.addr,
+            proxy_port = proxy_addr.http_port,
+            ssl_port = proxy_addr.ssl_port
+        );
+
+        // serve http endpoint which redirects to https
+        let data = data.clone();
+        let router = router.clone();
+        let acceptor = acceptor.clone();
+        task::spawn(async move {
+            if let Err(e) = redirect_http(data, router, acceptor).await {
+                error!("{e}");
+            }
+        });
+    } else {
+        info!(
+            "Listening on https://{proxy_addr}:{ssl_port} for service http://{backend}",
+            backend = data.config.addresses.backend,
+            proxy_addr = proxy_addr.addr,
+            ssl_port = proxy_addr.ssl_port
+        );
+    }
+
+    if data.config.options.http3 {
+        let data = data.clone();
+        let router = router.clone();
+        let tls = ssl_config.get_inner();
+        let acceptor = acceptor.clone();
+        task::spawn(async move {
+            if let Err(e) = http3::serve_http3(data, router, tls, acceptor).await {
+                error!("{e}");
+            }
+        });
+    }
 
     // ssl
-    axum_server::bind_rustls(proxy_addr.ssl_addr(), ssl_config)
+    let mut server = axum_server::bind(proxy_addr.ssl_addr())
+        .acceptor(RustlsAcceptor::new(ssl_config).acceptor(acceptor));
+    listener::configure(server.http_builder(), &data.config.limits);
+
+    server
         .serve(router.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .context(IoSnafu)?;
@@ -143,6 +286,14 @@ fn make_route(addr: ProxyAddr, data: Arc<StateData>) -> Router {
         router = router.route(path, get(websocket::handler));
     }
 
+    // closest to the backend, so auth and access still apply to cached responses
+    if data.cache.is_some() {
+        router = router.layer(amiddleware::from_fn_with_state(
+            data.clone(),
+            middleware::cache,
+        ));
+    }
+
     if data.config.options.kavita {
         router = router.layer(amiddleware::from_fn_with_state(
             data.clone(),
@@ -150,5 +301,84 @@ fn make_route(addr: ProxyAddr, data: Arc<StateData>) -> Router {
         ));
     }
 
+    if data.config.grpc.web {
+        router = router.layer(amiddleware::from_fn(middleware::grpc_web));
+    }
+
+    if data.oidc.is_some() {
+        router = router.layer(amiddleware::from_fn_with_state(
+            data.clone(),
+            middleware::oidc,
+        ));
+    }
+
+    if data.forward_auth.is_some() {
+        router = router.layer(amiddleware::from_fn_with_state(
+            data.clone(),
+            middleware::forward_auth,
+        ));
+    }
+
+    if data.htpasswd.is_some() {
+        router = router.layer(amiddleware::from_fn_with_state(
+            data.clone(),
+            middleware::basic_auth,
+        ));
+    }
+
+    // outside auth, so password guessing is rate limited too
+    if !data.rate_limiter.is_empty() {
+        router = router.layer(amiddleware::from_fn_with_state(
+            data.clone(),
+            middleware::rate_limit,
+        ));
+    }
+
+    if !data.config.access.site.is_empty() || !data.config.access.routes.is_empty() {
+        router = router.layer(amiddleware::from_fn_with_state(
+            data.clone(),
+            middleware::access,
+        ));
+    }
+
+    if data.config.options.http3 {
+        router = router.layer(amiddleware::from_fn_with_state(
+            data.clone(),
+            middleware::alt_svc,
+        ));
+    }
+
+    router = router.layer(amiddleware::from_fn_with_state(
+        data.clone(),
+        middleware::request_limits,
+    ));
+
+    // outside the other middleware, so it sees the Host the client sent
+    router = router.layer(amiddleware::from_fn_with_state(
+        data.clone(),
+        middleware::error_pages,
+    ));
+
+    // outside error_pages, so its pages are compressed too
+    if data.config.compression.enabled {
+        router = router.layer(amiddleware::from_fn_with_state(
+            data.clone(),
+            middleware::compression,
+        ));
+    }
+
+    // outside everything which makes its own responses, so error pages get the headers too
+    if !data.security_headers.is_empty() {
+        router = router.layer(amiddleware::from_fn_with_state(
+            data.clone(),
+            middleware::security_headers,
+        ));
+    }
+
+    router = router.layer(amiddleware::from_fn_with_state(
+        data.clone(),
+        middleware::request_id,
+    ));
+
     router.with_state(data)
 }
@@ -1,2 +1,28 @@
+mod access;
+mod alt_svc;
+mod basic_auth;
+mod cache;
+mod compression;
+mod error_pages;
+mod forward_auth;
+mod grpc_web;
 mod kavita;
+mod oidc;
+mod rate_limit;
+mod request_id;
+mod request_limits;
+mod security_headers;
+pub use access::access;
+pub use alt_svc::alt_svc;
+pub use basic_auth::basic_auth;
+pub use cache::cache;
+pub use compression::compression;
+pub use error_pages::error_pages;
+pub use forward_auth::forward_auth;
+pub use grpc_web::grpc_web;
 pub use kavita::kavita;
+pub use oidc::oidc;
+pub use rate_limit::rate_limit;
+pub use request_id::request_id;
+pub use request_limits::request_limits;
+pub use security_headers::{build_security_headers, security_headers};
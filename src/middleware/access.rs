@@ -0,0 +1,45 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use tracing::info;
+
+use crate::{
+    StateData,
+    access::client_ip,
+    error_pages::error_page,
+    utils::{format_req, normalize_path, path_matches},
+};
+
+/// Applies the site and route ip allow/deny lists
+pub async fn access(
+    conn: ConnectInfo<SocketAddr>,
+    State(data): State<Arc<StateData>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let config = &data.config.access;
+    let ip = client_ip(conn.ip(), req.headers(), config);
+
+    // the most specific route applies, on top of the site's lists
+    let path = normalize_path(req.uri().path());
+    let route = config
+        .routes
+        .iter()
+        .filter(|r| path_matches(&path, &r.path))
+        .max_by_key(|r| r.path.len());
+
+    if config.site.permits(ip) && route.is_none_or(|r| r.list.permits(ip)) {
+        return next.run(req).await;
+    }
+
+    info!(
+        "{} 403 Forbidden ({ip})",
+        format_req(req.method(), req.uri())
+    );
+    error_page(StatusCode::FORBIDDEN, format_args!("{ip} is not allowed"))
+}
@@ -0,0 +1,28 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{HeaderValue, Version, header::ALT_SVC},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::StateData;
+
+// how long clients may remember http/3 is available, in seconds
+const MAX_AGE: u32 = 86400;
+
+/// Advertises the http/3 listener to clients connected over tcp
+pub async fn alt_svc(State(data): State<Arc<StateData>>, req: Request, next: Next) -> Response {
+    let version = req.version();
+    let mut res = next.run(req).await;
+
+    if version != Version::HTTP_3 {
+        let value = format!("h3=\":{}\"; ma={MAX_AGE}", data.proxy_addr.ssl_port);
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            res.headers_mut().insert(ALT_SVC, value);
+        }
+    }
+
+    res
+}
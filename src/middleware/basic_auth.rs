@@ -0,0 +1,88 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{
+        HeaderName, HeaderValue, StatusCode,
+        header::{AUTHORIZATION, WWW_AUTHENTICATE},
+    },
+    middleware::Next,
+    response::Response,
+};
+use axum_extra::{
+    TypedHeader,
+    headers::{Authorization, authorization::Basic},
+};
+use tracing::info;
+
+use crate::{
+    StateData,
+    auth::Authenticated,
+    error_pages::error_page,
+    utils::{format_req, normalize_path, path_matches},
+};
+
+pub async fn basic_auth(
+    State(data): State<Arc<StateData>>,
+    auth: Option<TypedHeader<Authorization<Basic>>>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let config = &data.config.basic_auth;
+
+    let Some(htpasswd) = &data.htpasswd else {
+        return next.run(req).await;
+    };
+
+    // removed everywhere, so clients can't claim to be someone else on unprotected paths either
+    let user_header = config
+        .user_header
+        .as_ref()
+        .and_then(|h| HeaderName::try_from(h).ok());
+    if let Some(header) = &user_header {
+        req.headers_mut().remove(header);
+    }
+
+    let path = normalize_path(req.uri().path());
+    if !config.paths.is_empty() && !config.paths.iter().any(|p| path_matches(&path, p)) {
+        return next.run(req).await;
+    }
+
+    let user = match auth {
+        Some(TypedHeader(Authorization(basic)))
+            if htpasswd.verify(basic.username(), basic.password()).await =>
+        {
+            basic.username().to_owned()
+        }
+
+        _ => {
+            info!("{} 401 Unauthorized", format_req(req.method(), req.uri()));
+
+            let realm = config.realm.replace(['"', '\\'], "");
+            let challenge = format!(r#"Basic realm="{realm}", charset="UTF-8""#);
+
+            let mut res = error_page(StatusCode::UNAUTHORIZED, "authentication required");
+            if let Ok(challenge) = HeaderValue::from_str(&challenge) {
+                res.headers_mut().insert(WWW_AUTHENTICATE, challenge);
+            }
+
+            return res;
+        }
+    };
+
+    req.extensions_mut().insert(Authenticated);
+
+    let headers = req.headers_mut();
+
+    if config.strip_authorization {
+        headers.remove(AUTHORIZATION);
+    }
+
+    if let Some(header) = user_header
+        && let Ok(user) = HeaderValue::from_str(&user)
+    {
+        headers.insert(header, user);
+    }
+
+    next.run(req).await
+}
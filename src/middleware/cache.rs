@@ -0,0 +1,439 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use axum::{
+    Json,
+    body::Body,
+    extract::{ConnectInfo, Request, State},
+    http::{
+        HeaderMap, HeaderName, HeaderValue, Method, StatusCode,
+        header::{AGE, ALLOW, CONTENT_LENGTH, IF_MODIFIED_SINCE, IF_NONE_MATCH, UPGRADE},
+    },
+    middleware::Next,
+    response::{IntoResponse as _, Response},
+};
+use serde_json::json;
+use tokio::task;
+use tracing::{info, warn};
+use url::form_urlencoded;
+
+use crate::{
+    StateData,
+    access::client_ip,
+    auth::Authenticated,
+    cache::{self, Cache, Directives, Entry, Key, Policy, TeeBody},
+    error_pages::error_page,
+    utils::{format_req, normalize_path},
+};
+
+// how the response came about, see RFC 9211
+const CACHE_STATUS: HeaderName = HeaderName::from_static("cache-status");
+
+/// Serves responses from the cache, and stores the backend's when they can be cached
+pub async fn cache(
+    conn: ConnectInfo<SocketAddr>,
+    State(data): State<Arc<StateData>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(cache) = &data.cache else {
+        return next.run(req).await;
+    };
+
+    if cache
+        .config()
+        .purge_path
+        .as_ref()
+        .is_some_and(|p| req.uri().path() == p)
+    {
+        return purge(&data, cache, conn.ip(), &req);
+    }
+
+    let key = Key::new(req.headers(), req.uri());
+    let method = req.method().clone();
+    let authenticated = req.extensions().get::<Authenticated>().is_some();
+
+    if method != Method::GET && method != Method::HEAD {
+        let res = next.run(req).await;
+
+        // whatever was cached for the url is out of date now, RFC 9111 4.4
+        if !method.is_safe() && (res.status().is_success() || res.status().is_redirection()) {
+            cache.invalidate(&key);
+        }
+
+        return res;
+    }
+
+    let route = cache.route(&normalize_path(req.uri().path()));
+    let request = Directives::parse(req.headers());
+
+    // websockets, and whatever the route or client wants kept out of the cache
+    if route.is_some_and(|r| r.bypass) || req.headers().contains_key(UPGRADE) || request.no_store {
+        return with_status(next.run(req).await, "fwd=bypass");
+    }
+
+    let ttl = route.and_then(|r| r.ttl);
+    let mut entry = cache.lookup(&key, req.headers());
+
+    let acceptable = |entry: &Entry| {
+        !request.no_cache
+            && request
+                .max_age
+                .is_none_or(|secs| entry.age().as_secs() <= secs)
+    };
+
+    if let Some(cached) = entry.clone()
+        && cached.fresh()
+        && acceptable(&cached)
+    {
+        match serve(cache, &cached, &method, req.headers(), "hit").await {
+            Ok(res) => return res,
+            Err(e) => {
+                warn!(
+                    "cached response for {} is gone: {e}",
+                    format_req(&method, req.uri())
+                );
+                cache.invalidate(&key);
+                entry = None;
+            }
+        }
+    }
+
+    // only GETs are stored, so anything else about HEADs is up to the backend
+    if method == Method::HEAD {
+        return next.run(req).await;
+    }
+
+    // served stale straight away, and brought up to date in the background
+    if let Some(cached) = entry.clone()
+        && cached.stale_while_revalidate()
+        && acceptable(&cached)
+    {
+        if cached.start_revalidating() {
+            task::spawn(revalidate(
+                data.clone(),
+                next.clone(),
+                key.clone(),
+                cached.clone(),
+                ttl,
+                req.headers().clone(),
+                authenticated,
+                conditional(&req, &cached),
+            ));
+        }
+
+        let status = format!("hit; ttl={}", cached.ttl());
+        match serve(cache, &cached, &method, req.headers(), &status).await {
+            Ok(res) => return res,
+            Err(e) => {
+                warn!(
+                    "cached response for {} is gone: {e}",
+                    format_req(&method, req.uri())
+                );
+                cache.invalidate(&key);
+                entry = None;
+            }
+        }
+    }
+
+    let headers = req.headers().clone();
+
+    let Some(entry) = entry else {
+        if request.only_if_cached {
+            return error_page(StatusCode::GATEWAY_TIMEOUT, "the response isn't cached");
+        }
+
+        let res = next.run(req).await;
+        return store(
+            &data,
+            cache,
+            key,
+            ttl,
+            &headers,
+            authenticated,
+            res,
+            "fwd=miss",
+        );
+    };
+
+    let fwd = if request.no_cache {
+        "fwd=request"
+    } else {
+        "fwd=stale"
+    };
+    let res = next.run(conditional(&req, &entry)).await;
+
+    revalidated(
+        &data,
+        cache,
+        key,
+        &entry,
+        ttl,
+        &headers,
+        authenticated,
+        res,
+        fwd,
+    )
+    .await
+}
+
+/// Revalidates a stale response which has already been served
+#[allow(clippy::too_many_arguments)]
+async fn revalidate(
+    data: Arc<StateData>,
+    next: Next,
+    key: Key,
+    entry: Arc<Entry>,
+    ttl: Option<u64>,
+    headers: HeaderMap,
+    authenticated: bool,
+    req: Request,
+) {
+    let res = next.run(req).await;
+
+    if let Some(cache) = &data.cache {
+        let res = revalidated(
+            &data,
+            cache,
+            key,
+            &entry,
+            ttl,
+            &headers,
+            authenticated,
+            res,
+            "fwd=stale",
+        )
+        .await;
+
+        // nobody's reading it, but a new response is only stored once its body has been
+        _ = axum::body::to_bytes(res.into_body(), usize::MAX).await;
+    }
+
+    entry.finish_revalidating();
+}
+
+/// What the client gets once the backend has answered a revalidation
+#[allow(clippy::too_many_arguments)]
+async fn revalidated(
+    data: &Arc<StateData>,
+    cache: &Cache,
+    key: Key,
+    entry: &Entry,
+    ttl: Option<u64>,
+    headers: &HeaderMap,
+    authenticated: bool,
+    res: Response,
+    fwd: &str,
+) -> Response {
+    let status = res.status();
+
+    // still the same, so the cached one is good for a while longer
+    if status == StatusCode::NOT_MODIFIED {
+        let updated = cache
+            .refresh(
+                key.clone(),
+                entry,
+                ttl,
+                headers,
+                authenticated,
+                res.headers(),
+            )
+            .await;
+
+        return match serve(
+            cache,
+            &updated,
+            &Method::GET,
+            headers,
+            &format!("{fwd}; fwd-status=304"),
+        )
+        .await
+        {
+            Ok(res) => res,
+            Err(e) => {
+                warn!("cached response for {}{} is gone: {e}", key.host, key.path);
+                cache.invalidate(&key);
+                error_page(StatusCode::BAD_GATEWAY, "the cached response is gone")
+            }
+        };
+    }
+
+    if matches!(status.as_u16(), 500 | 502 | 503 | 504) && entry.stale_if_error() {
+        let cache_status = format!("{fwd}; fwd-status={}; ttl={}", status.as_u16(), entry.ttl());
+
+        if let Ok(res) = serve(cache, entry, &Method::GET, headers, &cache_status).await {
+            return res;
+        }
+    }
+
+    store(data, cache, key, ttl, headers, authenticated, res, fwd)
+}
+
+/// Sends the cached response, or a 304 if it's what the client already has
+async fn serve(
+    cache: &Cache,
+    entry: &Entry,
+    method: &Method,
+    headers: &HeaderMap,
+    status: &str,
+) -> std::io::Result<Response> {
+    let res = if cache::not_modified(headers, &entry.headers) {
+        let mut res = entry.response(Body::empty());
+        *res.status_mut() = StatusCode::NOT_MODIFIED;
+        res.headers_mut().remove(CONTENT_LENGTH);
+        res
+    } else if method == Method::HEAD {
+        entry.response(Body::empty())
+    } else {
+        entry.response(Body::from(cache.body(entry).await?))
+    };
+
+    Ok(with_status(res, status))
+}
+
+/// Stores the backend's response as it streams to the client, if it can be cached
+#[allow(clippy::too_many_arguments)]
+fn store(
+    data: &Arc<StateData>,
+    cache: &Cache,
+    key: Key,
+    ttl: Option<u64>,
+    headers: &HeaderMap,
+    authenticated: bool,
+    res: Response,
+    fwd: &str,
+) -> Response {
+    let config = cache.config();
+
+    let policy = Policy::new(
+        config,
+        ttl,
+        res.status(),
+        headers,
+        authenticated,
+        res.headers(),
+    );
+    let Some(policy) = policy else {
+        return with_status(res, fwd);
+    };
+
+    let too_big = res
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|l| l.to_str().ok())
+        .and_then(|l| l.parse::<u64>().ok())
+        .is_some_and(|len| len > config.max_entry_size);
+    if too_big {
+        return with_status(res, fwd);
+    }
+
+    let vary = Entry::vary_values(
+        headers,
+        cache::vary_names(res.headers()).unwrap_or_default(),
+    );
+    let initial_age = cache::age(res.headers());
+
+    let (parts, body) = res.into_parts();
+    let status = parts.status;
+    let mut stored = parts.headers.clone();
+    stored.remove(AGE);
+
+    let data = data.clone();
+    let body = TeeBody::new(body, config.max_entry_size as usize, move |body| {
+        let entry = Entry::new(
+            status,
+            stored,
+            vary,
+            policy,
+            initial_age,
+            None,
+            body.len() as u64,
+        );
+
+        task::spawn(async move {
+            if let Some(cache) = &data.cache {
+                cache.store(key, entry, body).await;
+            }
+        });
+    });
+
+    let res = Response::from_parts(parts, Body::new(body));
+    with_status(res, &format!("{fwd}; stored"))
+}
+
+/// The request to revalidate the entry with, conditional when it has validators
+fn conditional(req: &Request, entry: &Entry) -> Request {
+    let mut conditional = Request::new(Body::empty());
+    *conditional.method_mut() = Method::GET;
+    *conditional.uri_mut() = req.uri().clone();
+    *conditional.version_mut() = req.version();
+    *conditional.headers_mut() = req.headers().clone();
+    *conditional.extensions_mut() = req.extensions().clone();
+
+    // the client's own conditions are for the client, the cache answers them itself
+    let headers = conditional.headers_mut();
+    headers.remove(IF_NONE_MATCH);
+    headers.remove(IF_MODIFIED_SINCE);
+    headers.extend(cache::validators(&entry.headers));
+
+    conditional
+}
+
+/// Drops cached responses under a path prefix
+fn purge(data: &StateData, cache: &Cache, peer: IpAddr, req: &Request) -> Response {
+    let config = cache.config();
+    let ip = client_ip(peer, req.headers(), &data.config.access);
+
+    let allowed = if config.purge_allow.is_empty() {
+        ip.is_loopback()
+    } else {
+        config.purge_allow.permits(ip)
+    };
+
+    if !allowed {
+        info!(
+            "{} 403 Forbidden ({ip})",
+            format_req(req.method(), req.uri())
+        );
+        return error_page(
+            StatusCode::FORBIDDEN,
+            format_args!("{ip} is not allowed to purge"),
+        );
+    }
+
+    if req.method() != Method::POST {
+        let mut res = error_page(StatusCode::METHOD_NOT_ALLOWED, "purging needs a POST");
+        res.headers_mut()
+            .insert(ALLOW, HeaderValue::from_static("POST"));
+        return res;
+    }
+
+    let query = req.uri().query().unwrap_or_default();
+    let mut path = "/".to_owned();
+    let mut host = None;
+    for (name, value) in form_urlencoded::parse(query.as_bytes()) {
+        match &*name {
+            "path" => path = value.into_owned(),
+            "host" => host = Some(value.into_owned()),
+            _ => {}
+        }
+    }
+
+    let purged = cache.purge(host.as_deref(), &path);
+    info!(
+        "purged {purged} cached responses under {}{path} ({ip})",
+        host.as_deref().unwrap_or("")
+    );
+
+    Json(json!({ "purged": purged })).into_response()
+}
+
+fn with_status(mut res: Response, status: &str) -> Response {
+    if let Ok(status) = HeaderValue::from_str(&format!("ssl-ifier; {status}")) {
+        res.headers_mut().insert(CACHE_STATUS, status);
+    }
+
+    res
+}
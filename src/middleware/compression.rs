@@ -0,0 +1,33 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::Method,
+    middleware::Next,
+    response::Response,
+};
+
+use crate::{StateData, compression};
+
+/// Compresses responses with the best encoding the client accepts
+pub async fn compression(State(data): State<Arc<StateData>>, req: Request, next: Next) -> Response {
+    let config = &data.config.compression;
+
+    let head = req.method() == Method::HEAD;
+    let encoding = compression::negotiate(&config.encodings, req.headers());
+
+    let mut res = next.run(req).await;
+
+    if !compression::compressible(config, &res) {
+        return res;
+    }
+
+    compression::add_vary(res.headers_mut());
+
+    match encoding {
+        // nothing to compress, but its headers have to match what GET would've sent
+        Some(encoding) if head => compression::compress_head(res, encoding),
+        Some(encoding) => compression::compress(res, encoding),
+        None => res,
+    }
+}
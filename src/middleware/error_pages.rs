@@ -0,0 +1,194 @@
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{
+        HeaderValue, StatusCode,
+        header::{ACCEPT, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, HOST},
+    },
+    middleware::Next,
+    response::Response,
+};
+use tracing::error;
+
+use crate::{
+    StateData,
+    config::ErrorPages,
+    error_pages::{ErrorDetails, ErrorFormat, PageVars, builtin_page, plain_text, problem_details},
+    grpc::{self, GRPC_STATUS},
+    request_id::RequestId,
+};
+
+/// Renders error pages in the format the client asked for, with the custom templates for html
+///
+/// Also replaces backend error pages, if enabled
+pub async fn error_pages(
+    State(data): State<Arc<StateData>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    // http/2 requests carry the host in the uri instead
+    let host = req
+        .headers()
+        .get(HOST)
+        .and_then(|h| h.to_str().ok())
+        .or_else(|| req.uri().host())
+        .map(str::to_owned);
+
+    let accept = req.headers().get(ACCEPT).and_then(|a| a.to_str().ok());
+    let format = ErrorFormat::negotiate(accept);
+
+    let request_id = req.extensions().get::<RequestId>().cloned();
+    let grpc = grpc::content_type(req.headers()).cloned();
+
+    let res = next.run(req).await;
+    let status = res.status();
+
+    let details = match res.extensions().get::<ErrorDetails>() {
+        Some(ErrorDetails(details)) => details.clone(),
+        // grpc clients can't read pages, so any error without a grpc-status gets one instead
+        None if grpc.is_some() => {
+            if status == StatusCode::OK || res.headers().contains_key(GRPC_STATUS) {
+                return res;
+            }
+
+            String::new()
+        }
+        // the backend's own page is thrown away
+        None if intercepts(&data.config.error_pages, status) => String::new(),
+        None => return res,
+    };
+
+    let request_id =
+        request_id.unwrap_or_else(|| RequestId::generate(data.config.request_id.format));
+
+    let details = shown_details(&data.config.error_pages, status, &request_id, details);
+
+    if let Some(content_type) = grpc {
+        let (parts, _) = res.into_parts();
+        return grpc::error_response(parts, content_type, &details);
+    }
+
+    let vars = PageVars {
+        status,
+        details: &details,
+        request_id: Some(&request_id.0),
+        host: host.as_deref(),
+    };
+
+    let page = match format {
+        ErrorFormat::Json => problem_details(&vars),
+        ErrorFormat::Text => plain_text(&vars),
+        ErrorFormat::Html => {
+            let custom = match &data.error_templates {
+                Some(templates) => templates.render(&vars).await,
+                None => None,
+            };
+
+            custom.unwrap_or_else(|| builtin_page(&vars))
+        }
+    };
+
+    // the backend's headers are kept, they may matter (eg: WWW-Authenticate, Retry-After)
+    let (mut parts, _) = res.into_parts();
+    parts.headers.remove(CONTENT_LENGTH);
+    parts.headers.remove(CONTENT_ENCODING);
+    parts
+        .headers
+        .insert(CONTENT_TYPE, HeaderValue::from_static(format.content_type()));
+
+    Response::from_parts(parts, Body::from(page))
+}
+
+/// What of `details` the client gets to see, the rest is only logged
+fn shown_details(
+    config: &ErrorPages,
+    status: StatusCode,
+    request_id: &RequestId,
+    details: String,
+) -> String {
+    if config.expose_error_details {
+        return details;
+    }
+
+    if !details.is_empty() {
+        error!("{status} for request {}: {details}", request_id.0);
+    }
+
+    String::new()
+}
+
+fn intercepts(config: &ErrorPages, status: StatusCode) -> bool {
+    if !config.intercept_backend || !(status.is_client_error() || status.is_server_error()) {
+        return false;
+    }
+
+    config.intercept_statuses.is_empty() || config.intercept_statuses.contains(&status.as_u16())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DETAILS: &str = "connection refused to 10.0.0.5:5000";
+
+    fn pages(expose_error_details: bool) -> [String; 3] {
+        let config = ErrorPages {
+            expose_error_details,
+            ..Default::default()
+        };
+        let status = StatusCode::BAD_GATEWAY;
+        let request_id = RequestId("01HZREQUEST".to_owned());
+        let details = shown_details(&config, status, &request_id, DETAILS.to_owned());
+
+        let vars = PageVars {
+            status,
+            details: &details,
+            request_id: Some(&request_id.0),
+            host: None,
+        };
+
+        [
+            builtin_page(&vars),
+            problem_details(&vars),
+            plain_text(&vars),
+        ]
+    }
+
+    #[test]
+    fn hides_details_unless_exposed() {
+        for page in pages(false) {
+            assert!(page.contains("01HZREQUEST"), "{page}");
+            assert!(!page.contains("10.0.0.5"), "{page}");
+        }
+
+        let [html, json, text] = pages(false);
+        assert!(!html.contains("Error: "));
+        assert!(!json.contains("detail"));
+        assert_eq!(text, "502 Bad Gateway\nRequest ID: 01HZREQUEST\n");
+
+        for page in pages(true) {
+            assert!(
+                page.contains("01HZREQUEST") && page.contains(DETAILS),
+                "{page}"
+            );
+        }
+    }
+
+    #[test]
+    fn intercepts_configured_statuses() {
+        let mut config = ErrorPages::default();
+        assert!(!intercepts(&config, StatusCode::BAD_GATEWAY));
+
+        config.intercept_backend = true;
+        assert!(intercepts(&config, StatusCode::NOT_FOUND));
+        assert!(intercepts(&config, StatusCode::BAD_GATEWAY));
+        assert!(!intercepts(&config, StatusCode::OK));
+        assert!(!intercepts(&config, StatusCode::FOUND));
+
+        config.intercept_statuses = vec![502];
+        assert!(intercepts(&config, StatusCode::BAD_GATEWAY));
+        assert!(!intercepts(&config, StatusCode::NOT_FOUND));
+    }
+}
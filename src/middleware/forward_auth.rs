@@ -0,0 +1,71 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use tracing::{error, info};
+
+use crate::{
+    StateData,
+    access::client_ip,
+    auth::{Authenticated, Verdict},
+    error_pages::error_page,
+    utils::{format_req, normalize_path, path_matches},
+};
+
+pub async fn forward_auth(
+    conn: ConnectInfo<SocketAddr>,
+    State(data): State<Arc<StateData>>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let Some(auth) = &data.forward_auth else {
+        return next.run(req).await;
+    };
+
+    // only the auth service gets to set these, even on paths it isn't asked about
+    for name in auth.copy_headers() {
+        req.headers_mut().remove(name);
+    }
+
+    let paths = &data.config.forward_auth.paths;
+    let path = normalize_path(req.uri().path());
+    if !paths.is_empty() && !paths.iter().any(|p| path_matches(&path, p)) {
+        return next.run(req).await;
+    }
+
+    let ip = client_ip(conn.ip(), req.headers(), &data.config.access);
+    let verdict = auth
+        .check(
+            &data.auth_client,
+            req.method(),
+            req.uri(),
+            req.headers(),
+            ip,
+        )
+        .await;
+
+    match verdict {
+        Ok(Verdict::Allow(headers)) => {
+            req.headers_mut().extend(headers);
+            req.extensions_mut().insert(Authenticated);
+            next.run(req).await
+        }
+
+        Ok(Verdict::Deny(res)) => {
+            info!(
+                "{} {} (forward auth)",
+                format_req(req.method(), req.uri()),
+                res.status()
+            );
+            res
+        }
+
+        Err(e) => {
+            error!("forward auth failed: {e}");
+            error_page(e.status(), e)
+        }
+    }
+}
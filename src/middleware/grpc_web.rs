@@ -0,0 +1,13 @@
+use axum::{extract::Request, middleware::Next, response::Response};
+
+use crate::grpc::Web;
+
+/// Translates gRPC-Web requests from browsers into gRPC for the backend, and its responses back
+pub async fn grpc_web(req: Request, next: Next) -> Response {
+    let Some(web) = Web::detect(req.headers()) else {
+        return next.run(req).await;
+    };
+
+    let res = next.run(web.request(req)).await;
+    web.response(res)
+}
@@ -0,0 +1,257 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    extract::{Request, State},
+    http::{
+        HeaderMap, HeaderValue, Method, StatusCode, Uri,
+        header::{CACHE_CONTROL, HOST, LOCATION, SET_COOKIE, UPGRADE},
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tracing::{info, warn};
+use url::form_urlencoded;
+
+use crate::{
+    StateData,
+    auth::{Authenticated, Identity, Oidc, clear_cookie, get_cookie, strip_cookies},
+    error_pages::error_page,
+    utils::{format_req, normalize_path, path_matches},
+};
+
+pub async fn oidc(State(data): State<Arc<StateData>>, mut req: Request, next: Next) -> Response {
+    let Some(oidc) = &data.oidc else {
+        return next.run(req).await;
+    };
+
+    let config = &data.config.oidc;
+    let session_cookie = config.cookie_name.as_str();
+    let login_cookie = format!("{session_cookie}_login");
+
+    let path = req.uri().path();
+    if path == config.callback_path {
+        return callback(&data, oidc, req.uri(), req.headers(), &login_cookie).await;
+    }
+
+    if path == config.logout_path {
+        return logout(&data, oidc, req.uri(), req.headers(), session_cookie).await;
+    }
+
+    // only we get to set these
+    for header in oidc.claim_headers() {
+        req.headers_mut().remove(header);
+    }
+
+    let mut session = get_cookie(req.headers(), session_cookie)
+        .and_then(|cookie| oidc.open_session(session_cookie, cookie));
+
+    // set when the session cookie changed
+    let mut set_session = None;
+    if let Some(expired) = session.take_if(|s| s.expired()) {
+        session = oidc.refresh(&data.auth_client, &expired).await;
+        set_session = match &session {
+            Some(session) => oidc.seal_session(session_cookie, session),
+            None => clear_cookie(session_cookie),
+        };
+    }
+
+    let mut res = match session {
+        Some(session) => {
+            let identity = oidc.headers(&session);
+
+            let headers = req.headers_mut();
+            strip_cookies(headers, &[session_cookie, &login_cookie]);
+            headers.extend(identity.clone());
+            req.extensions_mut().insert(Identity(identity));
+            req.extensions_mut().insert(Authenticated);
+
+            next.run(req).await
+        }
+
+        None if !is_protected(&config.paths, &normalize_path(req.uri().path())) => {
+            strip_cookies(req.headers_mut(), &[session_cookie, &login_cookie]);
+            next.run(req).await
+        }
+
+        None => {
+            let (parts, _) = req.into_parts();
+            login(
+                &data,
+                oidc,
+                &parts.method,
+                &parts.uri,
+                &parts.headers,
+                &login_cookie,
+            )
+            .await
+        }
+    };
+
+    if let Some(cookie) = set_session {
+        res.headers_mut().append(SET_COOKIE, cookie);
+    }
+
+    res
+}
+
+fn is_protected(paths: &[String], path: &str) -> bool {
+    paths.is_empty() || paths.iter().any(|p| path_matches(path, p))
+}
+
+/// Sends browsers to the issuer to log in. Other clients can't follow that, so they're refused
+async fn login(
+    data: &StateData,
+    oidc: &Oidc,
+    method: &Method,
+    uri: &Uri,
+    headers: &HeaderMap,
+    login_cookie: &str,
+) -> Response {
+    let is_navigation =
+        matches!(*method, Method::GET | Method::HEAD) && !headers.contains_key(UPGRADE);
+
+    let Some(host) = host(uri, headers).filter(|_| is_navigation) else {
+        info!("{} 401 Unauthorized", format_req(method, uri));
+        return error_page(StatusCode::UNAUTHORIZED, "login required");
+    };
+
+    let redirect_uri = format!("https://{host}{}", data.config.oidc.callback_path);
+
+    // relative only, so the callback can't be used to redirect elsewhere
+    let return_to = uri
+        .path_and_query()
+        .map(|p| p.as_str())
+        .filter(|p| !p.starts_with("//") && !p.starts_with("/\\"))
+        .unwrap_or("/")
+        .to_owned();
+
+    match oidc.login(&data.auth_client, redirect_uri, return_to).await {
+        Ok((location, login)) => {
+            let Ok(location) = HeaderValue::try_from(location) else {
+                return error_page(StatusCode::BAD_GATEWAY, "bad authorization endpoint");
+            };
+
+            let mut res = redirect(location);
+            if let Some(cookie) = oidc.seal_login(login_cookie, &login) {
+                res.headers_mut().append(SET_COOKIE, cookie);
+            }
+
+            res
+        }
+
+        Err(e) => {
+            warn!("failed to start oidc login: {e}");
+            error_page(e.status(), e)
+        }
+    }
+}
+
+async fn callback(
+    data: &StateData,
+    oidc: &Oidc,
+    uri: &Uri,
+    headers: &HeaderMap,
+    login_cookie: &str,
+) -> Response {
+    let query = uri.query().unwrap_or_default();
+    let params = form_urlencoded::parse(query.as_bytes()).collect::<HashMap<_, _>>();
+
+    let login = get_cookie(headers, login_cookie)
+        .and_then(|cookie| oidc.open_login(login_cookie, cookie))
+        .filter(|login| params.get("state").is_some_and(|s| *s == login.state));
+
+    let mut res = match (login, params.get("code"), params.get("error")) {
+        (None, ..) => error_page(
+            StatusCode::BAD_REQUEST,
+            "login expired or wasn't started here",
+        ),
+
+        (Some(_), _, Some(error)) => {
+            let description = params.get("error_description").cloned().unwrap_or_default();
+            info!("oidc login failed: {error}: {description}");
+            error_page(StatusCode::FORBIDDEN, format_args!("login failed: {error}"))
+        }
+
+        (Some(_), None, None) => error_page(StatusCode::BAD_REQUEST, "missing code"),
+
+        (Some(login), Some(code), None) => {
+            match oidc.finish_login(&data.auth_client, code, &login).await {
+                Ok(session) => {
+                    let session_cookie = &data.config.oidc.cookie_name;
+
+                    let mut res = match HeaderValue::try_from(login.return_to) {
+                        Ok(location) => redirect(location),
+                        Err(_) => redirect(HeaderValue::from_static("/")),
+                    };
+
+                    if let Some(cookie) = oidc.seal_session(session_cookie, &session) {
+                        if cookie.len() > 4096 {
+                            warn!("oidc session cookie is too big for some browsers");
+                        }
+
+                        res.headers_mut().append(SET_COOKIE, cookie);
+                    }
+
+                    res
+                }
+
+                Err(e) => {
+                    warn!("failed to finish oidc login: {e}");
+                    error_page(e.status(), e)
+                }
+            }
+        }
+    };
+
+    if let Some(cookie) = clear_cookie(login_cookie) {
+        res.headers_mut().append(SET_COOKIE, cookie);
+    }
+
+    res
+}
+
+async fn logout(
+    data: &StateData,
+    oidc: &Oidc,
+    uri: &Uri,
+    headers: &HeaderMap,
+    session_cookie: &str,
+) -> Response {
+    let logout_url = match host(uri, headers) {
+        Some(host) => {
+            oidc.logout_url(&data.auth_client, &format!("https://{host}/"))
+                .await
+        }
+        None => None,
+    };
+
+    let location = logout_url
+        .and_then(|url| HeaderValue::try_from(url).ok())
+        .unwrap_or(HeaderValue::from_static("/"));
+
+    let mut res = redirect(location);
+    if let Some(cookie) = clear_cookie(session_cookie) {
+        res.headers_mut().append(SET_COOKIE, cookie);
+    }
+
+    res
+}
+
+fn redirect(location: HeaderValue) -> Response {
+    (
+        StatusCode::FOUND,
+        [
+            (LOCATION, location),
+            (CACHE_CONTROL, HeaderValue::from_static("no-store")),
+        ],
+    )
+        .into_response()
+}
+
+/// http/2 requests carry the host in the uri instead
+fn host<'a>(uri: &'a Uri, headers: &'a HeaderMap) -> Option<&'a str> {
+    headers
+        .get(HOST)
+        .and_then(|h| h.to_str().ok())
+        .or_else(|| uri.authority().map(|a| a.as_str()))
+}
@@ -0,0 +1,47 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{StatusCode, header::RETRY_AFTER},
+    middleware::Next,
+    response::Response,
+};
+use tracing::info;
+
+use crate::{
+    StateData,
+    access::client_ip,
+    error_pages::error_page,
+    utils::{format_req, normalize_path},
+};
+
+pub async fn rate_limit(
+    conn: ConnectInfo<SocketAddr>,
+    State(data): State<Arc<StateData>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let ip = client_ip(conn.ip(), req.headers(), &data.config.access);
+    let checked = data
+        .rate_limiter
+        .check(ip, &normalize_path(req.uri().path()), req.headers());
+
+    let Err(wait) = checked else {
+        return next.run(req).await;
+    };
+
+    info!(
+        "{} 429 Too Many Requests",
+        format_req(req.method(), req.uri())
+    );
+
+    // whole seconds, rounded up so clients don't come back too early
+    let secs = wait
+        .as_secs()
+        .saturating_add(u64::from(wait.subsec_nanos() > 0));
+
+    let mut res = error_page(StatusCode::TOO_MANY_REQUESTS, "rate limit exceeded");
+    res.headers_mut().insert(RETRY_AFTER, secs.into());
+
+    res
+}
@@ -0,0 +1,60 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::HeaderValue,
+    middleware::Next,
+    response::Response,
+};
+use tracing::{Instrument as _, info_span};
+
+use crate::{StateData, access::trusted_proxy, request_id::RequestId};
+
+/// Gives every request an id, which is logged with it, sent to the backend and returned
+pub async fn request_id(
+    State(data): State<Arc<StateData>>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let config = &data.config.request_id;
+    let header = &data.request_id_header;
+
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+
+    // anyone else could pick an id to pollute or confuse the logs with
+    let trusted = peer.is_some_and(|ip| trusted_proxy(ip, &data.config.access));
+
+    let incoming = req
+        .headers()
+        .get(header)
+        .and_then(|id| id.to_str().ok())
+        .filter(|_| trusted)
+        .and_then(RequestId::parse);
+
+    let id = incoming.unwrap_or_else(|| RequestId::generate(config.format));
+    let value = HeaderValue::from_str(&id.0).ok();
+
+    let headers = req.headers_mut();
+    headers.remove(header);
+    if config.forward
+        && let Some(value) = &value
+    {
+        headers.insert(header.clone(), value.clone());
+    }
+
+    let span = info_span!("request", id = %id.0);
+    req.extensions_mut().insert(id);
+
+    let mut res = next.run(req).instrument(span).await;
+
+    if config.response_header
+        && let Some(value) = value
+    {
+        res.headers_mut().insert(header.clone(), value);
+    }
+
+    res
+}
@@ -0,0 +1,73 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{StatusCode, header::CONTENT_LENGTH},
+    middleware::Next,
+    response::Response,
+};
+use tracing::info;
+
+use crate::{StateData, error_pages::error_page, grpc, limits::LimitedBody, utils::format_req};
+
+pub async fn request_limits(
+    State(data): State<Arc<StateData>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let limits = &data.config.limits;
+
+    // roughly how it was sent, http/2 compresses them but the decoded size is what costs memory
+    let header_size = req
+        .headers()
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len() + 4)
+        .sum::<usize>()
+        + req.uri().path_and_query().map_or(0, |p| p.as_str().len());
+
+    if header_size > limits.max_header_size {
+        info!(
+            "{} 431 Request Header Fields Too Large",
+            format_req(req.method(), req.uri())
+        );
+
+        return error_page(
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            format_args!(
+                "request headers are bigger than {} bytes",
+                limits.max_header_size
+            ),
+        );
+    }
+
+    // refused up front when the client says how big it is, otherwise when it gets too big
+    let content_length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse::<u64>().ok());
+
+    if let Some(max) = limits.max_body_size
+        && content_length.is_some_and(|len| len > max)
+    {
+        info!(
+            "{} 413 Payload Too Large",
+            format_req(req.method(), req.uri())
+        );
+
+        return error_page(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format_args!("request body is bigger than {max} bytes"),
+        );
+    }
+
+    // streaming gRPC calls can go quiet for as long as they like
+    let idle_timeout = limits
+        .body_idle_timeout
+        .filter(|_| grpc::content_type(req.headers()).is_none())
+        .map(Duration::from_secs);
+
+    let req = req.map(|body| Body::new(LimitedBody::new(body, limits.max_body_size, idle_timeout)));
+
+    next.run(req).await
+}
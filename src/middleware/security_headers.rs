@@ -0,0 +1,79 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{
+        HeaderName, HeaderValue,
+        header::InvalidHeaderValue,
+        header::{
+            CONTENT_SECURITY_POLICY, REFERRER_POLICY, STRICT_TRANSPORT_SECURITY,
+            X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+        },
+    },
+    middleware::Next,
+    response::Response,
+};
+
+use crate::{StateData, config::SecurityHeaders};
+
+/// Builds the headers to add to every https response
+pub fn build_security_headers(
+    config: &SecurityHeaders,
+) -> Result<Vec<(HeaderName, HeaderValue)>, InvalidHeaderValue> {
+    let mut headers = Vec::new();
+
+    if config.hsts {
+        let mut hsts = format!("max-age={}", config.hsts_max_age);
+        if config.hsts_include_subdomains {
+            hsts.push_str("; includeSubDomains");
+        }
+        if config.hsts_preload {
+            hsts.push_str("; preload");
+        }
+
+        headers.push((STRICT_TRANSPORT_SECURITY, HeaderValue::from_str(&hsts)?));
+    }
+
+    if config.content_type_nosniff {
+        headers.push((X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff")));
+    }
+
+    let optional = [
+        (CONTENT_SECURITY_POLICY, &config.content_security_policy),
+        (REFERRER_POLICY, &config.referrer_policy),
+        (
+            HeaderName::from_static("permissions-policy"),
+            &config.permissions_policy,
+        ),
+        (X_FRAME_OPTIONS, &config.frame_options),
+    ];
+
+    for (name, value) in optional {
+        if let Some(value) = value {
+            headers.push((name, HeaderValue::from_str(value)?));
+        }
+    }
+
+    Ok(headers)
+}
+
+pub async fn security_headers(
+    State(data): State<Arc<StateData>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let mut res = next.run(req).await;
+
+    let preserve = data.config.security_headers.preserve_backend;
+    let headers = res.headers_mut();
+
+    for (name, value) in &data.security_headers {
+        if preserve && headers.contains_key(name) {
+            continue;
+        }
+
+        headers.insert(name.clone(), value.clone());
+    }
+
+    res
+}
@@ -3,23 +3,48 @@ use std::{convert::Infallible, sync::Arc};
 use axum::{
     body::Body,
     extract::{Request, State},
-    http::{HeaderMap, HeaderValue, Method, StatusCode, Uri},
+    http::{HeaderMap, HeaderValue, Method, StatusCode, Uri, Version, header::HOST},
     response::Response,
 };
 use tracing::{error, info};
 
 use crate::{StateData, error_pages::error_page, utils::format_req};
 
+mod headers;
+mod upstream;
+
+pub use headers::strip_hop_by_hop;
+pub use upstream::Upstream;
+
 pub async fn proxy(
     State(state): State<Arc<StateData>>,
     uri: Uri,
     method: Method,
-    headers: HeaderMap<HeaderValue>,
+    version: Version,
+    mut headers: HeaderMap<HeaderValue>,
     body: Body,
 ) -> Result<Response<Body>, Infallible> {
     let path = uri.path_and_query().map(|i| i.as_str()).unwrap_or("/");
+    let backend = &state.config.addresses.backend;
+
+    if let Some(res) = headers::max_forwards(&method, &uri, version, &mut headers) {
+        info!("{} {}", format_req(&method, &uri), res.status());
+        return Ok(res);
+    }
 
-    let url = format!("http://{}{path}", state.config.addresses.backend);
+    // http/2 requests carry the host in the uri instead
+    let host = headers
+        .get(HOST)
+        .and_then(|h| h.to_str().ok())
+        .or_else(|| uri.authority().map(|a| a.as_str()))
+        .unwrap_or(&state.config.addresses.host)
+        .to_owned();
+    let public_origin = format!("https://{host}");
+
+    headers::forward_request(&state.config.proxy, &mut headers, version, &host, backend);
+    state.rewrite.request(&mut headers);
+
+    let url = format!("http://{backend}{path}");
     let mut builder = Request::builder().method(&method).uri(url);
     match builder.headers_mut() {
         Some(h) => *h = headers,
@@ -39,16 +64,26 @@ pub async fn proxy(
         }
     };
 
-    match state.client.request(req).await {
-        Ok(res) => {
+    match state.upstream.send(req).await {
+        Ok(mut res) => {
             info!("{} {}", format_req(&method, &uri), res.status());
-            let res = res.map(Body::new);
-            Ok(res)
+
+            let version = res.version();
+            headers::forward_response(&state.config.proxy, res.headers_mut(), version);
+
+            match state.rewrite.response(&method, &public_origin, res).await {
+                Ok(res) => Ok(res),
+                Err(err) => {
+                    error!("Bad Gateway: {err}");
+                    Ok(error_page(StatusCode::BAD_GATEWAY, err))
+                }
+            }
         }
 
         Err(err) => {
-            error!("Bad Gateway: {err}");
-            let page = error_page(StatusCode::BAD_GATEWAY, err);
+            let status = err.status();
+            error!("{status}: {err}");
+            let page = error_page(status, err);
             Ok(page)
         }
     }
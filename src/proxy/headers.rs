@@ -0,0 +1,285 @@
+//! Header handling for forwarded messages, see RFC 9110 section 7.6
+
+use axum::{
+    body::Body,
+    http::{
+        HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri, Version,
+        header::{
+            ALLOW, AUTHORIZATION, CONNECTION, CONTENT_TYPE, COOKIE, HOST, MAX_FORWARDS,
+            PROXY_AUTHORIZATION, TE, TRANSFER_ENCODING, UPGRADE, VIA,
+        },
+    },
+    response::{IntoResponse as _, Response},
+};
+
+use crate::config::Proxy;
+
+const KEEP_ALIVE: HeaderName = HeaderName::from_static("keep-alive");
+const PROXY_CONNECTION: HeaderName = HeaderName::from_static("proxy-connection");
+
+/// Headers which only apply to a single connection, and must not be forwarded
+const HOP_BY_HOP: [HeaderName; 6] = [
+    CONNECTION,
+    KEEP_ALIVE,
+    PROXY_CONNECTION,
+    TE,
+    TRANSFER_ENCODING,
+    UPGRADE,
+];
+
+/// Prepares the client's request headers to be sent to the backend
+///
+/// `host` is the host the client requested, `backend` the backend's address
+pub fn forward_request(
+    config: &Proxy,
+    headers: &mut HeaderMap,
+    version: Version,
+    host: &str,
+    backend: &str,
+) {
+    // the only TE worth passing on, and the backend needs it to send trailers (eg: grpc)
+    let trailers = headers
+        .get_all(TE)
+        .iter()
+        .filter_map(|te| te.to_str().ok())
+        .flat_map(|te| te.split(','))
+        .any(|te| te.trim().eq_ignore_ascii_case("trailers"));
+
+    strip_hop_by_hop(headers);
+
+    if trailers {
+        headers.insert(TE, HeaderValue::from_static("trailers"));
+    }
+
+    // set explicitly, since http/2 clients don't send a Host header at all
+    let host = if config.preserve_host { host } else { backend };
+    if let Ok(host) = HeaderValue::from_str(host) {
+        headers.insert(HOST, host);
+    }
+
+    add_via(config, headers, version);
+}
+
+/// Prepares the backend's response headers to be sent to the client
+pub fn forward_response(config: &Proxy, headers: &mut HeaderMap, version: Version) {
+    strip_hop_by_hop(headers);
+    add_via(config, headers, version);
+}
+
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    // anything listed in Connection is hop-by-hop as well
+    let listed = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|c| c.to_str().ok())
+        .flat_map(|c| c.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect::<Vec<_>>();
+
+    // Proxy-Authorization, Proxy-Authenticate etc. are meant for us, not the other side
+    let proxy = headers
+        .keys()
+        .filter(|name| name.as_str().starts_with("proxy-"))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    for name in HOP_BY_HOP.into_iter().chain(listed).chain(proxy) {
+        headers.remove(name);
+    }
+}
+
+fn add_via(config: &Proxy, headers: &mut HeaderMap, version: Version) {
+    let Some(pseudonym) = &config.via else {
+        return;
+    };
+
+    let protocol = match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    };
+
+    if let Ok(via) = HeaderValue::from_str(&format!("{protocol} {pseudonym}")) {
+        headers.append(VIA, via);
+    }
+}
+
+/// Applies Max-Forwards to TRACE and OPTIONS requests
+///
+/// Returns the response to send when the request stops here, otherwise decrements the header
+pub fn max_forwards(
+    method: &Method,
+    uri: &Uri,
+    version: Version,
+    headers: &mut HeaderMap,
+) -> Option<Response<Body>> {
+    if method != Method::TRACE && method != Method::OPTIONS {
+        return None;
+    }
+
+    let remaining = headers
+        .get(MAX_FORWARDS)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<u32>()
+        .ok()?;
+
+    if remaining > 0 {
+        headers.insert(MAX_FORWARDS, (remaining - 1).into());
+        return None;
+    }
+
+    if method == Method::OPTIONS {
+        let allow = "GET, HEAD, POST, PUT, DELETE, PATCH, OPTIONS, TRACE";
+        return Some((StatusCode::OK, [(ALLOW, allow)]).into_response());
+    }
+
+    // echo the request back, minus anything sensitive
+    let mut message = format!("{method} {uri} {version:?}\r\n");
+    for (name, value) in headers.iter() {
+        if [AUTHORIZATION, PROXY_AUTHORIZATION, COOKIE].contains(name) {
+            continue;
+        }
+
+        message.push_str(&format!(
+            "{name}: {}\r\n",
+            String::from_utf8_lossy(value.as_bytes())
+        ));
+    }
+
+    Some((StatusCode::OK, [(CONTENT_TYPE, "message/http")], message).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn strips_hop_by_hop_headers() {
+        let mut headers = headers(&[
+            ("connection", "keep-alive, X-Secret , close"),
+            ("connection", "x-other"),
+            ("keep-alive", "timeout=5"),
+            ("x-secret", "1"),
+            ("x-other", "1"),
+            ("transfer-encoding", "chunked"),
+            ("upgrade", "websocket"),
+            ("proxy-authorization", "Basic abc"),
+            ("proxy-connection", "keep-alive"),
+            ("te", "trailers"),
+            ("x-kept", "1"),
+            ("authorization", "Basic abc"),
+        ]);
+
+        strip_hop_by_hop(&mut headers);
+
+        let mut names = headers.keys().map(HeaderName::as_str).collect::<Vec<_>>();
+        names.sort_unstable();
+        assert_eq!(names, ["authorization", "x-kept"]);
+    }
+
+    #[test]
+    fn forwards_only_trailers_te() {
+        let forward = |te: &'static str| {
+            let mut headers = headers(&[("te", te)]);
+            forward_request(&Proxy::default(), &mut headers, Version::HTTP_11, "a", "b");
+            headers.get(TE).cloned()
+        };
+
+        assert_eq!(forward("trailers").unwrap(), "trailers");
+        assert_eq!(forward("gzip, Trailers").unwrap(), "trailers");
+        assert_eq!(forward("gzip, trailers").unwrap(), "trailers");
+        assert_eq!(forward("gzip"), None);
+        assert_eq!(forward("deflate;q=0.5"), None);
+    }
+
+    #[test]
+    fn sets_host_and_via() {
+        let config = Proxy {
+            preserve_host: false,
+            via: Some("ssl-ifier".to_owned()),
+        };
+        let mut headers = headers(&[("host", "example.com"), ("via", "1.1 other")]);
+        forward_request(
+            &config,
+            &mut headers,
+            Version::HTTP_2,
+            "example.com",
+            "127.0.0.1:5000",
+        );
+
+        assert_eq!(headers[HOST], "127.0.0.1:5000");
+        let via = headers.get_all(VIA).iter().collect::<Vec<_>>();
+        assert_eq!(via, ["1.1 other", "2 ssl-ifier"]);
+    }
+
+    fn forwards(method: Method, max: &'static str) -> (Option<Response<Body>>, HeaderMap) {
+        let mut headers = headers(&[
+            ("max-forwards", max),
+            ("authorization", "Basic abc"),
+            ("cookie", "a=1"),
+            ("x-kept", "1"),
+        ]);
+        let uri = Uri::from_static("/a?b");
+        let res = max_forwards(&method, &uri, Version::HTTP_11, &mut headers);
+        (res, headers)
+    }
+
+    #[tokio::test]
+    async fn answers_trace_when_out_of_forwards() {
+        let (res, _) = forwards(Method::TRACE, "0");
+        let res = res.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CONTENT_TYPE], "message/http");
+
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.starts_with("TRACE /a?b HTTP/1.1\r\n"));
+        assert!(body.contains("x-kept: 1\r\n"));
+        assert!(!body.contains("authorization") && !body.contains("cookie"));
+    }
+
+    #[test]
+    fn answers_options_when_out_of_forwards() {
+        let (res, _) = forwards(Method::OPTIONS, " 0 ");
+        let res = res.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().contains_key(ALLOW));
+    }
+
+    #[test]
+    fn decrements_max_forwards() {
+        for method in [Method::TRACE, Method::OPTIONS] {
+            let (res, headers) = forwards(method, "3");
+            assert!(res.is_none());
+            assert_eq!(headers[MAX_FORWARDS], "2");
+        }
+    }
+
+    #[test]
+    fn ignores_max_forwards_for_other_methods() {
+        for method in [Method::GET, Method::POST, Method::DELETE] {
+            let (res, headers) = forwards(method, "0");
+            assert!(res.is_none());
+            assert_eq!(headers[MAX_FORWARDS], "0");
+        }
+
+        // and when it isn't a number
+        let (res, headers) = forwards(Method::TRACE, "none");
+        assert!(res.is_none());
+        assert_eq!(headers[MAX_FORWARDS], "none");
+    }
+}
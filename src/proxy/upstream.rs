@@ -0,0 +1,516 @@
+//! Connections, timeouts and retries for requests to the backend
+
+use std::{
+    error::Error as _,
+    io,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    BoxError,
+    body::{Body, Bytes, HttpBody},
+    http::{Request, Response, StatusCode, Uri, request::Parts, uri::InvalidUri},
+};
+use futures::future::BoxFuture;
+use hyper::body::{Frame, SizeHint};
+use hyper_util::{
+    client::legacy::{self, Client, connect::HttpConnector},
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+};
+use snafu::{ResultExt, Snafu};
+use tokio::{
+    net::TcpStream,
+    time::{self, Instant, Sleep},
+};
+use tower_service::Service;
+use tracing::warn;
+
+use crate::{config, limits::BodyLimitError};
+
+// retries that can be banked while the backend is healthy, in thousandths of a retry
+const MAX_BUDGET: u64 = 10 * 1000;
+
+#[derive(Debug, Snafu)]
+pub enum UpstreamError {
+    #[snafu(display("backend didn't respond in time"))]
+    Timeout,
+    #[snafu(display("{source}"))]
+    Request { source: legacy::Error },
+}
+
+impl UpstreamError {
+    /// The status to reply to the client with
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            // the client's fault, not the backend's
+            Self::Request { source } if let Some(e) = BodyLimitError::find(source) => e.status(),
+            Self::Request { source } if is_timeout(source) => StatusCode::GATEWAY_TIMEOUT,
+            Self::Request { .. } => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    fn is_connect(&self) -> bool {
+        matches!(self, Self::Request { source } if source.is_connect())
+    }
+}
+
+/// Sends requests to the backend
+#[derive(Debug)]
+pub struct Upstream {
+    client: Client<BackendConnector, Body>,
+    config: config::Upstream,
+    budget: RetryBudget,
+}
+
+impl Upstream {
+    /// `backend` is the backend's address, which every connection is made to
+    pub fn new(config: &config::Upstream, backend: &str) -> Result<Self, InvalidUri> {
+        let secs = |s: Option<u64>| s.map(Duration::from_secs);
+
+        let mut connector = HttpConnector::new();
+        connector.set_connect_timeout(secs(config.connect_timeout));
+        connector.set_keepalive(secs(config.tcp_keepalive));
+        connector.set_nodelay(config.tcp_nodelay);
+
+        let connector = BackendConnector {
+            inner: connector,
+            backend: Uri::try_from(format!("http://{backend}"))?,
+        };
+
+        let client = Client::builder(TokioExecutor::new())
+            .pool_timer(TokioTimer::new())
+            .pool_idle_timeout(secs(config.pool_idle_timeout))
+            .pool_max_idle_per_host(config.pool_max_idle_per_host)
+            .http2_only(config.http2)
+            .build(connector);
+
+        Ok(Self {
+            client,
+            config: config.clone(),
+            budget: RetryBudget::new(config.retry_budget),
+        })
+    }
+
+    /// Sends `req`, retrying it if it's safe to and the backend couldn't be reached
+    pub async fn send(&self, req: Request<Body>) -> Result<Response<Body>, UpstreamError> {
+        let deadline = self
+            .config
+            .total_timeout
+            .map(|t| Instant::now() + Duration::from_secs(t));
+
+        self.budget.deposit();
+
+        // the body is gone once sent, so only requests without one can be sent again
+        let retryable = req.method().is_idempotent() && req.body().size_hint().exact() == Some(0);
+
+        // the uri keeps naming the backend so every request shares its pooled connections, while
+        // the client's Host header goes along as-is, http/2 included
+        let (parts, body) = req.into_parts();
+
+        let mut body = Some(body);
+        let mut attempt = 0;
+
+        loop {
+            let req = rebuild(&parts, body.take().unwrap_or_default());
+
+            match self.attempt(req, deadline).await {
+                Err(e) if retryable && e.is_connect() && attempt < self.config.retries => {
+                    let backoff = self.backoff(attempt);
+
+                    // waiting would use up the rest of the total timeout anyway
+                    if deadline.is_some_and(|d| Instant::now() + backoff >= d) {
+                        return Err(UpstreamError::Timeout);
+                    }
+
+                    if !self.budget.withdraw() {
+                        return Err(e);
+                    }
+
+                    warn!(
+                        "retrying {} {} in {}ms: {e}",
+                        parts.method,
+                        parts.uri,
+                        backoff.as_millis()
+                    );
+
+                    time::sleep(backoff).await;
+                    attempt += 1;
+                }
+
+                res => return res,
+            }
+        }
+    }
+
+    /// How long to wait before retry number `attempt`, counting from 0
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1 << attempt.min(16);
+        Duration::from_millis(self.config.retry_backoff.saturating_mul(factor))
+    }
+
+    async fn attempt(
+        &self,
+        req: Request<Body>,
+        deadline: Option<Instant>,
+    ) -> Result<Response<Body>, UpstreamError> {
+        let response_deadline = self
+            .config
+            .response_timeout
+            .map(|t| Instant::now() + Duration::from_secs(t));
+
+        let timeout = match (response_deadline, deadline) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+
+        let res = match timeout {
+            Some(timeout) => time::timeout_at(timeout, self.client.request(req))
+                .await
+                .map_err(|_| UpstreamError::Timeout)?,
+            None => self.client.request(req).await,
+        }
+        .context(RequestSnafu)?;
+
+        let res = match deadline {
+            Some(deadline) => res.map(|body| Body::new(Deadline::new(Body::new(body), deadline))),
+            None => res.map(Body::new),
+        };
+
+        Ok(res)
+    }
+}
+
+fn rebuild(parts: &Parts, body: Body) -> Request<Body> {
+    let mut req = Request::new(body);
+    *req.method_mut() = parts.method.clone();
+    *req.uri_mut() = parts.uri.clone();
+    *req.version_mut() = parts.version;
+    *req.headers_mut() = parts.headers.clone();
+
+    req
+}
+
+fn is_timeout(error: &legacy::Error) -> bool {
+    let mut source = error.source();
+    while let Some(e) = source {
+        if e.downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() == io::ErrorKind::TimedOut)
+        {
+            return true;
+        }
+
+        source = e.source();
+    }
+
+    false
+}
+
+/// Connects to the backend no matter which host a request's uri names
+#[derive(Debug, Clone)]
+struct BackendConnector {
+    inner: HttpConnector,
+    backend: Uri,
+}
+
+impl Service<Uri> for BackendConnector {
+    type Response = TokioIo<TcpStream>;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, _: Uri) -> Self::Future {
+        let future = self.inner.call(self.backend.clone());
+        Box::pin(async move { future.await.map_err(Into::into) })
+    }
+}
+
+/// Limits retries to a fraction of requests, so a struggling backend isn't flooded with them
+///
+/// Every request adds `ratio` of a retry to the budget, and every retry takes a whole one out
+#[derive(Debug)]
+struct RetryBudget {
+    // in thousandths of a retry
+    per_request: u64,
+    balance: AtomicU64,
+}
+
+impl RetryBudget {
+    fn new(ratio: f64) -> Self {
+        Self {
+            per_request: (ratio.max(0.0) * 1000.0) as u64,
+            balance: AtomicU64::new(MAX_BUDGET),
+        }
+    }
+
+    fn deposit(&self) {
+        _ = self
+            .balance
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |b| {
+                Some(b.saturating_add(self.per_request).min(MAX_BUDGET))
+            });
+    }
+
+    fn withdraw(&self) -> bool {
+        self.balance
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |b| {
+                b.checked_sub(1000)
+            })
+            .is_ok()
+    }
+}
+
+/// A response body which fails once the total timeout has passed
+struct Deadline {
+    body: Body,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl Deadline {
+    fn new(body: Body, deadline: Instant) -> Self {
+        Self {
+            body,
+            sleep: Box::pin(time::sleep_until(deadline)),
+        }
+    }
+}
+
+impl HttpBody for Deadline {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if self.sleep.as_mut().poll(cx).is_ready() {
+            let error = io::Error::new(io::ErrorKind::TimedOut, "backend response timed out");
+            return Poll::Ready(Some(Err(axum::Error::new(error))));
+        }
+
+        Pin::new(&mut self.body).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use axum::{
+        Router,
+        extract::ConnectInfo,
+        http::{HeaderMap, Version, header::HOST},
+    };
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Answers with the connection's port, the Host header and the http version it saw
+    async fn backend() -> String {
+        let router =
+            Router::new().fallback(
+                |ConnectInfo(peer): ConnectInfo<SocketAddr>,
+                 version: Version,
+                 headers: HeaderMap| async move {
+                    let host = headers
+                        .get(HOST)
+                        .and_then(|h| h.to_str().ok())
+                        .unwrap_or("");
+                    format!("{} {host} {version:?}", peer.port())
+                },
+            );
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let service = router.into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, service).await });
+
+        addr
+    }
+
+    async fn get(upstream: &Upstream, backend: &str, host: &'static str) -> String {
+        let req = Request::get(format!("http://{backend}/"))
+            .header(HOST, host)
+            .body(Body::empty())
+            .unwrap();
+
+        let res = upstream.send(req).await.unwrap();
+        let body = axum::body::to_bytes(Body::new(res.into_body()), usize::MAX);
+        String::from_utf8(body.await.unwrap().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn http2_hosts_share_a_connection() {
+        let backend = backend().await;
+        let config = config::Upstream {
+            http2: true,
+            ..Default::default()
+        };
+        let upstream = Upstream::new(&config, &backend).unwrap();
+
+        let a = get(&upstream, &backend, "a.example.com").await;
+        let b = get(&upstream, &backend, "b.example.com").await;
+
+        let (port_a, rest_a) = a.split_once(' ').unwrap();
+        let (port_b, rest_b) = b.split_once(' ').unwrap();
+        assert_eq!(port_a, port_b);
+        assert_eq!(rest_a, "a.example.com HTTP/2.0");
+        assert_eq!(rest_b, "b.example.com HTTP/2.0");
+    }
+
+    /// An address nothing is listening on
+    async fn closed() -> String {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    fn request(backend: &str) -> Request<Body> {
+        Request::get(format!("http://{backend}/"))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn budget_allows_a_fraction_of_requests() {
+        let budget = RetryBudget::new(0.5);
+        budget.balance.store(0, Ordering::Relaxed);
+
+        budget.deposit();
+        assert!(!budget.withdraw());
+        budget.deposit();
+        assert!(budget.withdraw());
+        assert!(!budget.withdraw());
+    }
+
+    #[test]
+    fn budget_is_capped() {
+        let budget = RetryBudget::new(1.0);
+        for _ in 0..100 {
+            budget.deposit();
+        }
+
+        let mut retries = 0;
+        while budget.withdraw() {
+            retries += 1;
+        }
+        assert_eq!(retries, MAX_BUDGET / 1000);
+    }
+
+    #[test]
+    fn huge_budgets_dont_overflow() {
+        let budget = RetryBudget::new(f64::MAX);
+        assert_eq!(budget.per_request, u64::MAX);
+
+        budget.deposit();
+        budget.deposit();
+        assert_eq!(budget.balance.load(Ordering::Relaxed), MAX_BUDGET);
+    }
+
+    #[test]
+    fn negative_budgets_allow_no_more_retries() {
+        let budget = RetryBudget::new(-1.0);
+        assert_eq!(budget.per_request, 0);
+    }
+
+    #[test]
+    fn backoff_doubles_and_saturates() {
+        let config = config::Upstream {
+            retry_backoff: 100,
+            ..Default::default()
+        };
+        let upstream = Upstream::new(&config, "127.0.0.1:1").unwrap();
+        assert_eq!(upstream.backoff(0), Duration::from_millis(100));
+        assert_eq!(upstream.backoff(1), Duration::from_millis(200));
+        assert_eq!(upstream.backoff(3), Duration::from_millis(800));
+        assert_eq!(upstream.backoff(100), upstream.backoff(16));
+
+        let config = config::Upstream {
+            retry_backoff: u64::MAX,
+            ..Default::default()
+        };
+        let upstream = Upstream::new(&config, "127.0.0.1:1").unwrap();
+        assert_eq!(upstream.backoff(3), Duration::from_millis(u64::MAX));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_its_retries() {
+        let backend = closed().await;
+        let config = config::Upstream {
+            retries: 2,
+            retry_backoff: 1,
+            ..Default::default()
+        };
+        let upstream = Upstream::new(&config, &backend).unwrap();
+
+        let e = upstream.send(request(&backend)).await.unwrap_err();
+        assert!(e.is_connect());
+        // each retry is paid for out of the budget
+        assert_eq!(
+            upstream.budget.balance.load(Ordering::Relaxed),
+            MAX_BUDGET - 2000
+        );
+    }
+
+    #[tokio::test]
+    async fn doesnt_retry_with_an_empty_budget() {
+        let backend = closed().await;
+        let config = config::Upstream {
+            retry_backoff: 1,
+            retry_budget: 0.0,
+            ..Default::default()
+        };
+        let upstream = Upstream::new(&config, &backend).unwrap();
+        upstream.budget.balance.store(0, Ordering::Relaxed);
+
+        let e = upstream.send(request(&backend)).await.unwrap_err();
+        assert!(e.is_connect());
+    }
+
+    #[tokio::test]
+    async fn doesnt_retry_requests_with_bodies() {
+        let backend = closed().await;
+        let config = config::Upstream {
+            retry_backoff: 1,
+            ..Default::default()
+        };
+        let upstream = Upstream::new(&config, &backend).unwrap();
+
+        let req = Request::put(format!("http://{backend}/"))
+            .body(Body::from("body"))
+            .unwrap();
+        assert!(upstream.send(req).await.unwrap_err().is_connect());
+        assert_eq!(upstream.budget.balance.load(Ordering::Relaxed), MAX_BUDGET);
+    }
+
+    #[tokio::test]
+    async fn backoff_doesnt_outlast_the_total_timeout() {
+        let backend = closed().await;
+        let config = config::Upstream {
+            total_timeout: Some(1),
+            retry_backoff: 5000,
+            ..Default::default()
+        };
+        let upstream = Upstream::new(&config, &backend).unwrap();
+
+        let started = Instant::now();
+        let e = upstream.send(request(&backend)).await.unwrap_err();
+        assert!(matches!(e, UpstreamError::Timeout));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        // the retry that was never made wasn't paid for
+        assert_eq!(upstream.budget.balance.load(Ordering::Relaxed), MAX_BUDGET);
+    }
+}
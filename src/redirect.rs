@@ -1,18 +1,34 @@
-use std::io;
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
 
 use axum::{
-    handler::HandlerWithoutStateExt as _,
+    Router,
+    body::Body,
+    extract::{Request, State},
+    middleware as amiddleware,
     http::{
         Method, StatusCode, Uri,
+        header::LOCATION,
         uri::{self, Authority, InvalidUri, InvalidUriParts},
     },
-    response::{IntoResponse as _, Redirect},
+    response::{IntoResponse as _, Response},
 };
 use axum_extra::{TypedHeader, headers::Host};
-use snafu::{ResultExt, Snafu};
+use snafu::{OptionExt, ResultExt, Snafu};
+use tower_service::Service as _;
 use tracing::info;
 
-use crate::{config::ProxyAddr, error_pages::error_page, utils::format_req};
+use crate::{
+    StateData,
+    config::{Redirect, Www},
+    error_pages::error_page,
+    listener::{self, Acceptor},
+    middleware,
+    utils::{format_req, normalize_path, path_matches},
+};
 
 #[derive(Debug, Snafu)]
 pub enum RedirectError {
@@ -22,46 +38,251 @@ pub enum RedirectError {
     Uri { source: InvalidUri },
     #[snafu(display("{source}"))]
     Io { source: io::Error },
+    #[snafu(display("missing Host header"))]
+    MissingHost,
 }
 
-pub async fn redirect_http(addr: ProxyAddr) -> Result<(), RedirectError> {
-    let make_https = move |host: &str, uri: Uri| -> Result<Uri, RedirectError> {
-        let mut parts = uri.into_parts();
-
-        parts.scheme = Some(uri::Scheme::HTTPS);
-
-        if parts.path_and_query.is_none() {
-            parts.path_and_query = Some("/".parse().unwrap());
-        }
+/// Serves the http listener, redirecting to https
+///
+/// `backend` is the https listener's router, which excluded paths are served by so they go through
+/// the same auth, limits and headers
+pub async fn redirect_http(
+    data: Arc<StateData>,
+    backend: Router,
+    acceptor: Acceptor,
+) -> Result<(), RedirectError> {
+    let addr = data.proxy_addr.http_addr();
+    let mut server = axum_server::bind(addr).acceptor(acceptor);
+    listener::configure(server.http_builder(), &data.config.limits);
 
-        let https_host = host.replace(&addr.http_port.to_string(), &addr.ssl_port.to_string());
-        let authority = https_host.parse::<Authority>().context(UriSnafu)?;
+    let mut redirects = Router::new().fallback(redirect);
 
-        parts.authority = Some(authority);
+    if !data.config.access.site.is_empty() || !data.config.access.routes.is_empty() {
+        redirects = redirects.layer(amiddleware::from_fn_with_state(
+            data.clone(),
+            middleware::access,
+        ));
+    }
 
-        Uri::from_parts(parts).context(UriPartsSnafu)
-    };
+    let redirects = redirects
+        .layer(amiddleware::from_fn_with_state(
+            data.clone(),
+            middleware::request_limits,
+        ))
+        .layer(amiddleware::from_fn_with_state(
+            data.clone(),
+            middleware::error_pages,
+        ))
+        .layer(amiddleware::from_fn_with_state(
+            data.clone(),
+            middleware::request_id,
+        ))
+        .with_state(data.clone());
 
-    let redirect = move |method: Method, TypedHeader(host): TypedHeader<Host>, uri: Uri| async move {
-        let path = format_req(&method, &uri);
+    let router = Router::new().fallback(move |req: Request| {
+        let path = normalize_path(req.uri().path());
+        let excluded = data
+            .config
+            .redirect
+            .exclude
+            .iter()
+            .any(|p| path_matches(&path, p));
 
-        match make_https(host.hostname(), uri) {
-            Ok(uri) => {
-                info!("{path} 308 Permanent Redirect");
-                Redirect::permanent(&uri.to_string()).into_response()
-            }
+        let mut router = if excluded {
+            backend.clone()
+        } else {
+            redirects.clone()
+        };
 
-            Err(error) => {
-                info!("{path} 400 Bad Request");
-                error_page(StatusCode::BAD_REQUEST, error).into_response()
+        async move {
+            // routers are always ready
+            match router.call(req).await {
+                Ok(res) => res,
+                Err(never) => match never {},
             }
         }
-    };
+    });
 
-    axum_server::bind(addr.http_addr())
-        .serve(redirect.into_make_service())
+    server
+        .serve(router.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .context(IoSnafu)?;
 
     Ok(())
 }
+
+async fn redirect(
+    State(state): State<Arc<StateData>>,
+    method: Method,
+    host: Option<TypedHeader<Host>>,
+    uri: Uri,
+) -> Response<Body> {
+    let config = &state.config.redirect;
+
+    let path = format_req(&method, &uri);
+
+    // absolute-form requests carry the host in the uri instead
+    let host = host
+        .as_ref()
+        .map(|TypedHeader(host)| host.hostname().to_owned())
+        .or_else(|| uri.host().map(str::to_owned));
+
+    match make_https(config, state.proxy_addr.ssl_port, host.as_deref(), uri) {
+        Ok(uri) => {
+            let status = config.status.0;
+            info!("{path} {status}");
+
+            (status, [(LOCATION, uri.to_string())]).into_response()
+        }
+
+        Err(error) => {
+            info!("{path} 400 Bad Request");
+            error_page(StatusCode::BAD_REQUEST, error)
+        }
+    }
+}
+
+fn make_https(
+    config: &Redirect,
+    ssl_port: u16,
+    host: Option<&str>,
+    uri: Uri,
+) -> Result<Uri, RedirectError> {
+    let host = config
+        .canonical_host
+        .as_deref()
+        .or(host)
+        .context(MissingHostSnafu)?;
+
+    let host = normalise_www(config.www, host);
+
+    let port = config.canonical_port.unwrap_or(ssl_port);
+    let authority = if port == 443 {
+        host.parse::<Authority>()
+    } else {
+        format!("{host}:{port}").parse::<Authority>()
+    }
+    .context(UriSnafu)?;
+
+    let mut parts = uri.into_parts();
+
+    parts.scheme = Some(uri::Scheme::HTTPS);
+    parts.authority = Some(authority);
+
+    if parts.path_and_query.is_none() {
+        parts.path_and_query = Some("/".parse().unwrap());
+    }
+
+    Uri::from_parts(parts).context(UriPartsSnafu)
+}
+
+fn normalise_www(www: Www, host: &str) -> String {
+    // ip addresses don't have subdomains
+    let hostname = host
+        .parse::<Authority>()
+        .map(|a| a.host().trim_matches(['[', ']']).to_owned())
+        .unwrap_or_default();
+    if hostname.parse::<IpAddr>().is_ok() {
+        return host.to_owned();
+    }
+
+    let bare = host.strip_prefix("www.").unwrap_or(host);
+
+    match www {
+        Www::Keep => host.to_owned(),
+        Www::Add => format!("www.{bare}"),
+        Www::Remove => bare.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn https(config: &Redirect, host: Option<&str>, uri: &str) -> String {
+        make_https(config, 443, host, uri.parse().unwrap())
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn omits_the_default_port() {
+        let config = Redirect::default();
+        assert_eq!(
+            https(&config, Some("example.com"), "/a?b=c"),
+            "https://example.com/a?b=c"
+        );
+        assert_eq!(
+            https(&config, Some("example.com"), "http://example.com"),
+            "https://example.com/"
+        );
+    }
+
+    #[test]
+    fn keeps_other_ports() {
+        let config = Redirect::default();
+        let uri = make_https(&config, 8443, Some("example.com"), "/a".parse().unwrap());
+        assert_eq!(uri.unwrap().to_string(), "https://example.com:8443/a");
+
+        let config = Redirect {
+            canonical_port: Some(4443),
+            ..Default::default()
+        };
+        assert_eq!(
+            https(&config, Some("example.com"), "/"),
+            "https://example.com:4443/"
+        );
+    }
+
+    #[test]
+    fn handles_ipv6_hosts() {
+        let config = Redirect {
+            www: Www::Add,
+            ..Default::default()
+        };
+        assert_eq!(https(&config, Some("[::1]"), "/a"), "https://[::1]/a");
+
+        let uri = make_https(&config, 8443, Some("[::1]"), "/a".parse().unwrap());
+        assert_eq!(uri.unwrap().to_string(), "https://[::1]:8443/a");
+    }
+
+    #[test]
+    fn canonical_host_wins() {
+        let config = Redirect {
+            canonical_host: Some("example.com".to_owned()),
+            www: Www::Add,
+            ..Default::default()
+        };
+        assert_eq!(
+            https(&config, Some("other.com"), "/a"),
+            "https://www.example.com/a"
+        );
+        assert_eq!(https(&config, None, "/a"), "https://www.example.com/a");
+    }
+
+    #[test]
+    fn needs_a_host() {
+        let uri = make_https(&Redirect::default(), 443, None, "/".parse().unwrap());
+        assert!(matches!(uri, Err(RedirectError::MissingHost)));
+    }
+
+    #[test]
+    fn adds_and_removes_www() {
+        for (www, host, expected) in [
+            (Www::Keep, "example.com", "example.com"),
+            (Www::Keep, "www.example.com", "www.example.com"),
+            (Www::Add, "example.com", "www.example.com"),
+            (Www::Add, "www.example.com", "www.example.com"),
+            (Www::Add, "example.com:8443", "www.example.com:8443"),
+            (Www::Remove, "www.example.com", "example.com"),
+            (Www::Remove, "example.com", "example.com"),
+            (Www::Remove, "www.example.com:8443", "example.com:8443"),
+            (Www::Add, "127.0.0.1", "127.0.0.1"),
+            (Www::Add, "127.0.0.1:8443", "127.0.0.1:8443"),
+            (Www::Add, "[::1]", "[::1]"),
+            (Www::Add, "[::1]:8443", "[::1]:8443"),
+        ] {
+            assert_eq!(normalise_www(www, host), expected, "{www:?} {host}");
+        }
+    }
+}
@@ -0,0 +1,29 @@
+use ulid::Ulid;
+use uuid::Uuid;
+
+use crate::config::RequestIdFormat;
+
+// longer incoming ids are replaced, so clients can't bloat our logs
+const MAX_LEN: usize = 128;
+
+/// Identifies a request across our logs, the backend's logs and error pages
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    pub fn generate(format: RequestIdFormat) -> Self {
+        match format {
+            RequestIdFormat::Uuid => Self(Uuid::new_v4().to_string()),
+            RequestIdFormat::Ulid => Self(Ulid::new().to_string()),
+        }
+    }
+
+    /// Accepts an id from a trusted proxy, if it's sensible
+    pub fn parse(id: &str) -> Option<Self> {
+        let valid = !id.is_empty()
+            && id.len() <= MAX_LEN
+            && id.bytes().all(|b| b.is_ascii_graphic());
+
+        valid.then(|| Self(id.to_owned()))
+    }
+}
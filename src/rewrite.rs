@@ -0,0 +1,485 @@
+use std::{
+    collections::VecDeque,
+    future::poll_fn,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    http::{
+        HeaderMap, HeaderValue, Method, StatusCode,
+        header::{
+            ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_LOCATION, CONTENT_TYPE,
+            ETAG, LOCATION, REFRESH, SET_COOKIE,
+        },
+    },
+    response::Response,
+};
+use hyper::body::Frame;
+use snafu::{ResultExt, Snafu};
+
+use crate::config::{Config, Rewrite};
+
+#[derive(Debug, Snafu)]
+pub enum RewriteError {
+    #[snafu(display("failed to read response body for rewriting: {source}"))]
+    Body { source: axum::Error },
+}
+
+/// Maps urls pointing at the backend to the public https origin in backend responses
+#[derive(Debug)]
+pub struct Rewriter {
+    config: Rewrite,
+    // lowercase, without trailing slashes
+    backend_origins: Vec<String>,
+}
+
+impl Rewriter {
+    pub fn new(config: &Config) -> Self {
+        let backend = format!("http://{}", config.addresses.backend);
+
+        let backend_origins = [&backend]
+            .into_iter()
+            .chain(&config.rewrite.backend_aliases)
+            .map(|o| o.trim_end_matches('/').to_ascii_lowercase())
+            .collect();
+
+        Self {
+            config: config.rewrite.clone(),
+            backend_origins,
+        }
+    }
+
+    /// Adjusts the request headers sent to the backend
+    pub fn request(&self, headers: &mut HeaderMap) {
+        // compressed bodies can't be rewritten
+        if self.config.body {
+            headers.remove(ACCEPT_ENCODING);
+        }
+    }
+
+    /// Rewrites a backend response for a client which reached us at `public_origin`
+    ///
+    /// `public_origin` is in the form `https://host[:port]`
+    pub async fn response(
+        &self,
+        method: &Method,
+        public_origin: &str,
+        mut res: Response<Body>,
+    ) -> Result<Response<Body>, RewriteError> {
+        let has_body = !(*method == Method::HEAD
+            || res.status().is_informational()
+            || res.status() == StatusCode::NO_CONTENT
+            || res.status() == StatusCode::NOT_MODIFIED);
+
+        let headers = res.headers_mut();
+
+        if self.config.headers {
+            for name in [LOCATION, CONTENT_LOCATION] {
+                if let Some(url) = headers.get(&name).and_then(|v| v.to_str().ok())
+                    && let Some(url) = self.rewrite_url(url, public_origin)
+                    && let Ok(url) = HeaderValue::from_str(&url)
+                {
+                    headers.insert(name, url);
+                }
+            }
+
+            if let Some(refresh) = headers.get(REFRESH).and_then(|v| v.to_str().ok())
+                && let Some(refresh) = self.rewrite_refresh(refresh, public_origin)
+                && let Ok(refresh) = HeaderValue::from_str(&refresh)
+            {
+                headers.insert(REFRESH, refresh);
+            }
+        }
+
+        let cookies = headers
+            .get_all(SET_COOKIE)
+            .iter()
+            .map(|c| {
+                match c.to_str() {
+                    Ok(cookie) => HeaderValue::from_str(&self.rewrite_cookie(cookie)).ok(),
+                    Err(_) => None,
+                }
+                .unwrap_or_else(|| c.clone())
+            })
+            .collect::<Vec<_>>();
+
+        if !cookies.is_empty() {
+            headers.remove(SET_COOKIE);
+            for cookie in cookies {
+                headers.append(SET_COOKIE, cookie);
+            }
+        }
+
+        if has_body && self.should_rewrite_body(headers) {
+            let (mut parts, mut body) = res.into_parts();
+
+            // the length may not be known up front, so it's read until it turns out to be too big
+            let mut frames = VecDeque::new();
+            let mut len = 0;
+            let mut complete = true;
+            while let Some(frame) = poll_fn(|cx| Pin::new(&mut body).poll_frame(cx)).await {
+                let frame = frame.context(BodySnafu)?;
+
+                match frame.data_ref() {
+                    Some(data) => len += data.len(),
+                    None => complete = false,
+                }
+
+                frames.push_back(frame);
+
+                if !complete || len > self.config.body_max_size {
+                    complete = false;
+                    break;
+                }
+            }
+
+            // too large to buffer, or has trailers, so it's passed through untouched
+            if !complete {
+                let body = Replay { frames, body };
+                return Ok(Response::from_parts(parts, Body::new(body)));
+            }
+
+            let original = frames.into_iter().filter_map(|f| f.into_data().ok()).fold(
+                Vec::with_capacity(len),
+                |mut body, data| {
+                    body.extend_from_slice(&data);
+                    body
+                },
+            );
+
+            let mut body = original.clone();
+            for origin in &self.backend_origins {
+                body = replace_bytes(&body, origin.as_bytes(), public_origin.as_bytes());
+            }
+
+            if body != original {
+                weaken_etag(&mut parts.headers);
+            }
+
+            parts.headers.insert(CONTENT_LENGTH, body.len().into());
+            res = Response::from_parts(parts, Body::from(body));
+        }
+
+        Ok(res)
+    }
+
+    fn should_rewrite_body(&self, headers: &HeaderMap) -> bool {
+        if !self.config.body {
+            return false;
+        }
+
+        if headers
+            .get(CONTENT_ENCODING)
+            .is_some_and(|e| e.as_bytes() != b"identity")
+        {
+            return false;
+        }
+
+        // too large to buffer, so it's passed through untouched
+        let len = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|l| l.to_str().ok())
+            .and_then(|l| l.parse::<usize>().ok());
+        if len.is_some_and(|len| len > self.config.body_max_size) {
+            return false;
+        }
+
+        let Some(content_type) = headers.get(CONTENT_TYPE).and_then(|c| c.to_str().ok()) else {
+            return false;
+        };
+
+        let mime = content_type.split(';').next().unwrap_or("").trim();
+
+        self.config
+            .body_content_types
+            .iter()
+            .any(|t| t.eq_ignore_ascii_case(mime))
+    }
+
+    fn rewrite_url(&self, url: &str, public_origin: &str) -> Option<String> {
+        self.backend_origins.iter().find_map(|origin| {
+            let prefix = url.get(..origin.len())?;
+            if !prefix.eq_ignore_ascii_case(origin) {
+                return None;
+            }
+
+            let rest = &url[origin.len()..];
+            if !ends_authority(rest.as_bytes()) {
+                return None;
+            }
+
+            Some(format!("{public_origin}{rest}"))
+        })
+    }
+
+    // eg: `5; url=http://127.0.0.1:5000/`
+    fn rewrite_refresh(&self, refresh: &str, public_origin: &str) -> Option<String> {
+        let start = refresh.to_ascii_lowercase().find("url=")? + "url=".len();
+        let (head, url) = refresh.split_at(start);
+
+        // the url may be quoted
+        let (quote, url) = match url.strip_prefix(['\'', '"']) {
+            Some(unquoted) => (&url[..1], unquoted),
+            None => ("", url),
+        };
+
+        let url = self.rewrite_url(url, public_origin)?;
+
+        Some(format!("{head}{quote}{url}"))
+    }
+
+    fn rewrite_cookie(&self, cookie: &str) -> String {
+        let mut attrs = cookie.split(';');
+
+        let mut out = attrs.next().unwrap_or("").trim().to_owned();
+        let mut secure = false;
+        let mut same_site = false;
+
+        for attr in attrs.map(str::trim).filter(|a| !a.is_empty()) {
+            let (name, value) = match attr.split_once('=') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => (attr, ""),
+            };
+
+            let attr = match name.to_ascii_lowercase().as_str() {
+                "domain" => self
+                    .config
+                    .cookie_domains
+                    .iter()
+                    .find(|(from, _)| from.eq_ignore_ascii_case(value.trim_start_matches('.')))
+                    .map(|(_, to)| format!("Domain={to}"))
+                    .unwrap_or_else(|| attr.to_owned()),
+
+                "path" => self
+                    .config
+                    .cookie_paths
+                    .iter()
+                    .filter(|(from, _)| value.starts_with(from.as_str()))
+                    // the most specific prefix wins
+                    .max_by_key(|(from, _)| from.len())
+                    .map(|(from, to)| format!("Path={to}{}", &value[from.len()..]))
+                    .unwrap_or_else(|| attr.to_owned()),
+
+                "secure" => {
+                    secure = true;
+                    attr.to_owned()
+                }
+
+                "samesite" => {
+                    same_site = true;
+                    attr.to_owned()
+                }
+
+                _ => attr.to_owned(),
+            };
+
+            out.push_str("; ");
+            out.push_str(&attr);
+        }
+
+        if self.config.cookie_secure && !secure {
+            out.push_str("; Secure");
+        }
+
+        if let Some(policy) = &self.config.cookie_same_site
+            && !same_site
+        {
+            out.push_str("; SameSite=");
+            out.push_str(policy);
+        }
+
+        out
+    }
+}
+
+/// The rewritten body isn't byte for byte what the backend's etag was for
+fn weaken_etag(headers: &mut HeaderMap) {
+    if let Some(etag) = headers.get(ETAG)
+        && !etag.as_bytes().starts_with(b"W/")
+    {
+        let mut weak = b"W/".to_vec();
+        weak.extend_from_slice(etag.as_bytes());
+
+        match HeaderValue::from_bytes(&weak) {
+            Ok(weak) => headers.insert(ETAG, weak),
+            Err(_) => headers.remove(ETAG),
+        };
+    }
+}
+
+/// A body which has been partly read, sending what was read before the rest
+struct Replay {
+    frames: VecDeque<Frame<Bytes>>,
+    body: Body,
+}
+
+impl HttpBody for Replay {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if let Some(frame) = self.frames.pop_front() {
+            return Poll::Ready(Some(Ok(frame)));
+        }
+
+        Pin::new(&mut self.body).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.frames.is_empty() && self.body.is_end_stream()
+    }
+}
+
+/// Whether `rest`, following an origin, means the whole authority matched
+///
+/// eg: `:5000` isn't a prefix of `:50001`
+fn ends_authority(rest: &[u8]) -> bool {
+    rest.is_empty() || matches!(rest[0], b'/' | b'?' | b'#' | b'\'' | b'"')
+}
+
+/// Replaces every `from` origin in `haystack`, ignoring case like urls in headers do
+///
+/// `from` has to be lowercase
+fn replace_bytes(haystack: &[u8], from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(haystack.len());
+    let mut rest = haystack;
+
+    while !rest.is_empty() {
+        let matched = rest
+            .get(..from.len())
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case(from));
+
+        if matched && ends_authority(&rest[from.len()..]) {
+            out.extend_from_slice(to);
+            rest = &rest[from.len()..];
+        } else {
+            out.push(rest[0]);
+            rest = &rest[1..];
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUBLIC: &str = "https://example.com";
+
+    fn rewriter(rewrite: Rewrite) -> Rewriter {
+        let mut config = Config {
+            rewrite,
+            ..Default::default()
+        };
+        config.addresses.backend = "127.0.0.1:5000".to_owned();
+        config.rewrite.backend_aliases = vec!["http://LocalHost:5000/".to_owned()];
+
+        Rewriter::new(&config)
+    }
+
+    #[test]
+    fn rewrites_backend_urls() {
+        let rewriter = rewriter(Rewrite::default());
+        let url = |url| rewriter.rewrite_url(url, PUBLIC);
+
+        assert_eq!(url("http://127.0.0.1:5000").unwrap(), PUBLIC);
+        assert_eq!(
+            url("http://127.0.0.1:5000/a?b#c").unwrap(),
+            "https://example.com/a?b#c"
+        );
+        assert_eq!(
+            url("HTTP://LOCALHOST:5000/a").unwrap(),
+            "https://example.com/a"
+        );
+        assert_eq!(url("http://127.0.0.1:50001/a"), None);
+        assert_eq!(url("http://127.0.0.1:5000.evil.com/"), None);
+        assert_eq!(url("https://127.0.0.1:5000/"), None);
+        assert_eq!(url("/relative"), None);
+    }
+
+    #[test]
+    fn rewrites_refresh_urls() {
+        let rewriter = rewriter(Rewrite::default());
+        let refresh = |refresh| rewriter.rewrite_refresh(refresh, PUBLIC);
+
+        assert_eq!(
+            refresh("5; url=http://127.0.0.1:5000/next").unwrap(),
+            "5; url=https://example.com/next"
+        );
+        assert_eq!(
+            refresh("0;URL='http://127.0.0.1:5000/next'").unwrap(),
+            "0;URL='https://example.com/next'"
+        );
+        assert_eq!(refresh("5; url=http://other.com/"), None);
+        assert_eq!(refresh("5"), None);
+    }
+
+    #[test]
+    fn rewrites_cookies() {
+        let rewriter = rewriter(Rewrite {
+            cookie_same_site: Some("Lax".to_owned()),
+            cookie_domains: [("localhost".to_owned(), "example.com".to_owned())].into(),
+            cookie_paths: [
+                ("/".to_owned(), "/app/".to_owned()),
+                ("/api/".to_owned(), "/v2/".to_owned()),
+            ]
+            .into(),
+            ..Default::default()
+        });
+
+        assert_eq!(
+            rewriter.rewrite_cookie("a=1; Domain=.LocalHost; Path=/api/x; HttpOnly"),
+            "a=1; Domain=example.com; Path=/v2/x; HttpOnly; Secure; SameSite=Lax"
+        );
+        assert_eq!(
+            rewriter.rewrite_cookie("a=1; path=/x; secure; SameSite=Strict"),
+            "a=1; Path=/app/x; secure; SameSite=Strict"
+        );
+        assert_eq!(
+            rewriter.rewrite_cookie("a=1; Domain=other.com"),
+            "a=1; Domain=other.com; Secure; SameSite=Lax"
+        );
+    }
+
+    #[test]
+    fn leaves_cookies_alone_when_not_configured() {
+        let rewriter = rewriter(Rewrite {
+            cookie_secure: false,
+            ..Default::default()
+        });
+
+        assert_eq!(
+            rewriter.rewrite_cookie("a=1; Domain=localhost; Path=/"),
+            "a=1; Domain=localhost; Path=/"
+        );
+    }
+
+    #[test]
+    fn replaces_whole_origins_in_bodies() {
+        let replace = |body: &str| {
+            let out = replace_bytes(body.as_bytes(), b"http://127.0.0.1:5000", PUBLIC.as_bytes());
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(
+            replace(r#"<a href="http://127.0.0.1:5000/a">"#),
+            r#"<a href="https://example.com/a">"#
+        );
+        assert_eq!(
+            replace("'HTTP://127.0.0.1:5000' http://127.0.0.1:5000?q"),
+            "'https://example.com' https://example.com?q"
+        );
+        assert_eq!(
+            replace("http://127.0.0.1:50001/x http://127.0.0.1:5000.evil.com/"),
+            "http://127.0.0.1:50001/x http://127.0.0.1:5000.evil.com/"
+        );
+        assert_eq!(replace("http://127.0.0.1:5000"), PUBLIC);
+        assert_eq!(replace("http://127.0.0.1:50"), "http://127.0.0.1:50");
+    }
+}
@@ -2,6 +2,7 @@ use std::borrow::Cow;
 
 use axum::http::{Method, Uri};
 use owo_colors::OwoColorize;
+use percent_encoding::percent_decode_str;
 use url::form_urlencoded;
 
 pub fn format_req(method: &Method, uri: &Uri) -> String {
@@ -15,6 +16,23 @@ pub fn format_req(method: &Method, uri: &Uri) -> String {
     format!("{method} {path}")
 }
 
+pub fn html_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
 pub fn format_query(uri: &str) -> String {
     let mut query = Vec::new();
 
@@ -39,3 +57,95 @@ pub fn format_query(uri: &str) -> String {
 
     query.to_string()
 }
+
+/// The path as a backend would most likely read it: percent-decoded, without empty or dot segments
+///
+/// Rules are matched against this, so `/%61dmin/`, `//admin/` and `/./admin/` can't get around one
+/// for `/admin/`
+pub fn normalize_path(path: &str) -> String {
+    let decoded = percent_decode_str(path).decode_utf8_lossy();
+
+    let mut segments = Vec::new();
+    let mut trailing_slash = false;
+    // some backends take a backslash to be a slash too
+    for segment in decoded.split(['/', '\\']) {
+        trailing_slash = matches!(segment, "" | "." | "..");
+
+        match segment {
+            "" | "." => {}
+            ".." => _ = segments.pop(),
+            segment => segments.push(segment),
+        }
+    }
+
+    let mut normalized = String::with_capacity(decoded.len());
+    for segment in segments {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+
+    if trailing_slash || normalized.is_empty() {
+        normalized.push('/');
+    }
+
+    normalized
+}
+
+/// Whether the normalized path is under the prefix, matching whole segments only
+///
+/// `/admin` and `/admin/` both match `/admin` and `/admin/x`, but not `/adminx`
+pub fn path_matches(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+
+    path.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_paths() {
+        assert_eq!(normalize_path("/"), "/");
+        assert_eq!(normalize_path(""), "/");
+        assert_eq!(normalize_path("/admin/"), "/admin/");
+        assert_eq!(normalize_path("/admin"), "/admin");
+        assert_eq!(normalize_path("/%61dmin/"), "/admin/");
+        assert_eq!(normalize_path("//admin//x"), "/admin/x");
+        assert_eq!(normalize_path("/./admin/."), "/admin/");
+        assert_eq!(normalize_path("/x/../admin/"), "/admin/");
+        assert_eq!(normalize_path("/../../admin"), "/admin");
+        assert_eq!(normalize_path("/x/%2e%2e/admin"), "/admin");
+        assert_eq!(normalize_path("/x\\..\\admin"), "/admin");
+        assert_eq!(normalize_path("/admin/x/.."), "/admin/");
+    }
+
+    #[test]
+    fn matches_whole_segments() {
+        assert!(path_matches("/admin", "/admin"));
+        assert!(path_matches("/admin/", "/admin"));
+        assert!(path_matches("/admin/x", "/admin"));
+        assert!(path_matches("/admin", "/admin/"));
+        assert!(path_matches("/admin/x", "/admin/"));
+        assert!(!path_matches("/adminx", "/admin"));
+        assert!(!path_matches("/adminx", "/admin/"));
+        assert!(!path_matches("/", "/admin"));
+
+        assert!(path_matches("/", "/"));
+        assert!(path_matches("/anything", "/"));
+    }
+
+    #[test]
+    fn normalized_paths_match() {
+        for path in [
+            "/%61dmin/",
+            "//admin/",
+            "/./admin/",
+            "/x/../admin",
+            "/ADMIN/../admin/",
+        ] {
+            assert!(path_matches(&normalize_path(path), "/admin/"), "{path}");
+        }
+    }
+}
@@ -1,11 +1,17 @@
-use std::{collections::HashMap, sync::Arc};
+mod deflate;
+mod keepalive;
+mod logging;
+mod recording;
+mod replay;
+mod upgrade;
+
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 
 use axum::{
-    extract::{
-        ws::{CloseFrame, Message as AMessage, Utf8Bytes as AUtf8Bytes, WebSocket},
-        Query, State, WebSocketUpgrade,
-    },
-    response::IntoResponse,
+    Extension,
+    extract::{ConnectInfo, Query, State},
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::Response,
 };
 use derive_more::derive::Display;
 use futures::{
@@ -14,12 +20,31 @@ use futures::{
 };
 use owo_colors::OwoColorize;
 use serde::Deserialize;
-use tokio::{net::TcpStream, select};
-use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
-use tracing::{error, info};
-use tungstenite::Message as TMessage;
+use tokio::{net::TcpStream, select, sync::Mutex};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, connect_async_with_config};
+use tracing::{error, info, warn};
+use tungstenite::{
+    Message,
+    client::IntoClientRequest as _,
+    protocol::{CloseFrame, WebSocketConfig, frame::coding::CloseCode},
+};
 
-use crate::{utils::format_query, StateData};
+use crate::{
+    StateData,
+    access::client_ip,
+    auth::Identity,
+    config::Websocket,
+    error_pages::error_page,
+    limits::LimitError,
+    request_id::RequestId,
+    utils::format_query,
+};
+use keepalive::Activity;
+pub use logging::WsLogger;
+use logging::{Direction, SessionStats};
+use recording::Recorder;
+pub use replay::{ReplayError, replay};
+use upgrade::{ClientSocket, ClientUpgrade};
 
 #[derive(Debug, Deserialize)]
 pub struct QueryString {
@@ -28,14 +53,74 @@ pub struct QueryString {
 }
 
 pub async fn handler(
-    ws: WebSocketUpgrade,
+    ws: ClientUpgrade,
     Query(query): Query<QueryString>,
     State(state): State<Arc<StateData>>,
-) -> impl IntoResponse {
-    ws.on_upgrade(|socket| handle_socket(socket, state, query))
+    Extension(request_id): Extension<RequestId>,
+    identity: Option<Extension<Identity>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Response {
+    let ip = client_ip(addr.ip(), &headers, &state.config.access);
+    let permit = match state.websocket_sessions.acquire(ip) {
+        Ok(permit) => permit,
+        Err(e) => {
+            let status = match e {
+                LimitError::Full { .. } => StatusCode::SERVICE_UNAVAILABLE,
+                LimitError::FullForIp { .. } => StatusCode::TOO_MANY_REQUESTS,
+            };
+
+            warn!("refused websocket session: {e}");
+            return error_page(status, format_args!("too many websocket sessions, {e}"));
+        }
+    };
+
+    let config = socket_config(&state.config.websocket);
+    ws.on_upgrade(config, |socket| async move {
+        handle_socket(socket, state, query, request_id, identity.map(|i| i.0)).await;
+        drop(permit);
+    })
+}
+
+fn socket_config(config: &Websocket) -> WebSocketConfig {
+    let mut socket_config = WebSocketConfig::default();
+    if let Some(max) = config.max_message_size {
+        socket_config = socket_config.max_message_size(Some(max));
+    }
+    if let Some(max) = config.max_frame_size {
+        socket_config = socket_config.max_frame_size(Some(max));
+    }
+
+    socket_config
+}
+
+type DestSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+struct Sinks {
+    client: Mutex<SplitSink<ClientSocket, Message>>,
+    dest: Mutex<SplitSink<DestSocket, Message>>,
 }
 
-async fn handle_socket(socket: WebSocket, state: Arc<StateData>, query: QueryString) {
+/// Why a session ended
+#[derive(Debug, Copy, Clone)]
+enum Ended {
+    /// a side sent a close frame, which was forwarded to the other side
+    Closed,
+    /// a side disconnected or errored without a close frame
+    Gone(Direction),
+    /// a side sent a message over the size limit
+    TooBig(Direction),
+    IdleTimeout,
+    SessionTimeout,
+}
+
+async fn handle_socket(
+    socket: ClientSocket,
+    state: Arc<StateData>,
+    query: QueryString,
+    request_id: RequestId,
+    identity: Option<Identity>,
+) {
     let (mut client_sender, client_receiver) = socket.split();
 
     let Some(mut url) = state.websocket_destination.clone() else {
@@ -50,23 +135,46 @@ async fn handle_socket(socket: WebSocket, state: Arc<StateData>, query: QueryStr
     let query = format_query(url.query().unwrap_or(""));
 
     let path = format!("{path}{query}");
-    let path = path.cyan();
 
-    info!(url = %path, "connecting to ws");
+    info!(url = %path.cyan(), "connecting to ws");
+
+    let config = &state.config.websocket;
+    let dest_config = socket_config(config);
 
     let dest_socket = {
-        let Ok((dest, _)) = connect_async(url.as_str()).await else {
+        let request = url.as_str().into_client_request().map(|mut request| {
+            if state.config.request_id.forward
+                && let Ok(id) = HeaderValue::from_str(&request_id.0)
+            {
+                request
+                    .headers_mut()
+                    .insert(state.request_id_header.clone(), id);
+            }
+
+            if let Some(Identity(headers)) = identity {
+                request.headers_mut().extend(headers);
+            }
+
+            request
+        });
+
+        let connected = match request {
+            Ok(request) => connect_async_with_config(request, Some(dest_config), false).await,
+            Err(e) => Err(e),
+        };
+
+        let Ok((dest, _)) = connected else {
             // failed to connect to destination, so the client connection isn't needed
 
             error!("failed to connect");
 
             let frame = CloseFrame {
                 // Bad Gateway
-                code: 1014,
-                reason: AUtf8Bytes::from_static("Failed to open connection to destination server"),
+                code: CloseCode::from(1014),
+                reason: "Failed to open connection to destination server".into(),
             };
 
-            _ = client_sender.send(AMessage::Close(Some(frame))).await;
+            _ = client_sender.send(Message::Close(Some(frame))).await;
 
             _ = client_sender.close().await;
 
@@ -78,99 +186,215 @@ async fn handle_socket(socket: WebSocket, state: Arc<StateData>, query: QueryStr
 
     let (dest_sender, dest_receiver) = dest_socket.split();
 
-    let client_fut = handle_from_client(client_receiver, dest_sender);
-    let dest_fut = handle_from_server(client_sender, dest_receiver);
+    let sinks = Sinks {
+        client: Mutex::new(client_sender),
+        dest: Mutex::new(dest_sender),
+    };
+
+    let recorder = match &state.websocket_record_dir {
+        Some(dir) => match Recorder::create(dir, &path, config.record_max_size).await {
+            Ok(recorder) => Some(recorder),
+            Err(e) => {
+                error!("failed to start ws recording: {e}");
+                None
+            }
+        },
+
+        None => None,
+    };
 
-    // whichever future completes first, abort the other one since they're a pair
-    select! {
-        _ = client_fut => (),
-        _ = dest_fut => ()
+    let stats = SessionStats::new();
+    let activity = Activity::new();
+    let relay = Relay {
+        config,
+        log: &state.websocket_log,
+        stats: &stats,
+        activity: &activity,
+        recorder: recorder.as_ref(),
+        sinks: &sinks,
+    };
+
+    // whichever future completes first ends the session, since they're all tied to the same pair
+    let ended = select! {
+        ended = handle_from_client(client_receiver, &relay) => ended,
+        ended = handle_from_server(dest_receiver, &relay) => ended,
+        ended = keepalive::timeouts(config, &activity) => ended,
+        _ = keepalive::pinger(config, &sinks) => unreachable!(),
+    };
+
+    close(&sinks, ended).await;
+
+    relay.log.summary(&stats);
+}
+
+/// Everything the relay halves share for the duration of a session
+struct Relay<'a> {
+    config: &'a Websocket,
+    log: &'a WsLogger,
+    stats: &'a SessionStats,
+    activity: &'a Activity,
+    recorder: Option<&'a Recorder>,
+    sinks: &'a Sinks,
+}
+
+impl Relay<'_> {
+    fn too_big(&self, msg: &Message) -> bool {
+        self.config
+            .max_message_size
+            .is_some_and(|max| msg.len() > max)
+    }
+
+    fn record(&self, direction: Direction, msg: &Message) {
+        self.log.message(direction, msg);
+        self.stats.record(direction, msg);
+
+        if let Some(recorder) = self.recorder {
+            recorder.record(direction, msg);
+        }
     }
 }
 
 async fn handle_from_client(
-    mut client_receiver: SplitStream<WebSocket>,
-    mut dest_sender: SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, TMessage>,
-) {
-    while let Some(Ok(msg)) = client_receiver.next().await {
-        let msg = into_tmessage(msg);
+    mut client_receiver: SplitStream<ClientSocket>,
+    relay: &Relay<'_>,
+) -> Ended {
+    const DIRECTION: Direction = Direction::ClientToServer;
+
+    loop {
+        let msg = match client_receiver.next().await {
+            Some(Ok(msg)) => msg,
+
+            Some(Err(tungstenite::Error::Capacity(_))) => return Ended::TooBig(DIRECTION),
+            Some(Err(e)) => {
+                error!("client ws error: {e}");
+                return Ended::Gone(DIRECTION);
+            }
 
-        info!(ty = %msg_ty(&msg), %msg, "client->server");
+            None => return Ended::Gone(DIRECTION),
+        };
 
-        if dest_sender.send(msg).await.is_err() {
-            break;
+        relay.activity.touch(DIRECTION);
+
+        if let Message::Pong(p) = &msg
+            && keepalive::is_own_pong(p)
+        {
+            continue;
+        }
+
+        if relay.too_big(&msg) {
+            return Ended::TooBig(DIRECTION);
+        }
+
+        relay.record(DIRECTION, &msg);
+
+        let is_close = matches!(msg, Message::Close(_));
+
+        if relay.sinks.dest.lock().await.send(msg).await.is_err() {
+            return Ended::Gone(Direction::ServerToClient);
+        }
+
+        if is_close {
+            return Ended::Closed;
         }
     }
 }
 
 async fn handle_from_server(
-    mut client_sender: SplitSink<WebSocket, AMessage>,
-    mut dest_receiver: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
-) {
-    while let Some(Ok(msg)) = dest_receiver.next().await {
-        info!(ty = %msg_ty(&msg), %msg, "server->client");
+    mut dest_receiver: SplitStream<DestSocket>,
+    relay: &Relay<'_>,
+) -> Ended {
+    const DIRECTION: Direction = Direction::ServerToClient;
+
+    loop {
+        let msg = match dest_receiver.next().await {
+            Some(Ok(msg)) => msg,
+
+            Some(Err(tungstenite::Error::Capacity(_))) => return Ended::TooBig(DIRECTION),
+            Some(Err(e)) => {
+                error!("server ws error: {e}");
+                return Ended::Gone(DIRECTION);
+            }
 
-        let Some(msg) = into_amessage(msg) else {
-            continue;
+            None => return Ended::Gone(DIRECTION),
         };
 
-        if client_sender.send(msg).await.is_err() {
-            break;
+        relay.activity.touch(DIRECTION);
+
+        if let Message::Pong(p) = &msg
+            && keepalive::is_own_pong(p)
+        {
+            continue;
+        }
+
+        if relay.too_big(&msg) {
+            return Ended::TooBig(DIRECTION);
+        }
+
+        relay.record(DIRECTION, &msg);
+
+        let is_close = matches!(msg, Message::Close(_));
+
+        if relay.sinks.client.lock().await.send(msg).await.is_err() {
+            return Ended::Gone(Direction::ClientToServer);
+        }
+
+        if is_close {
+            return Ended::Closed;
         }
     }
 }
 
-fn into_tmessage(msg: AMessage) -> TMessage {
-    use tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
-
-    match msg {
-        AMessage::Text(t) => TMessage::Text(t.as_str().into()),
-        AMessage::Binary(b) => TMessage::Binary(b),
-        AMessage::Ping(p) => TMessage::Ping(p),
-        AMessage::Pong(p) => TMessage::Pong(p),
-        AMessage::Close(c) => match c {
-            Some(frame) => {
-                let frame = CloseFrame {
-                    code: CloseCode::from(frame.code),
-                    reason: frame.reason.as_str().into(),
-                };
-
-                TMessage::Close(Some(frame))
-            }
+/// Closes whichever sides of the session are still open
+async fn close(sinks: &Sinks, ended: Ended) {
+    let (code, reason) = match ended {
+        // the close frame was already forwarded, the other side will finish the handshake
+        Ended::Closed => (None, ""),
+        Ended::Gone(Direction::ClientToServer) => (Some(CloseCode::Away), "Client went away"),
+        Ended::Gone(Direction::ServerToClient) => {
+            (Some(CloseCode::Away), "Destination server went away")
+        }
+        Ended::TooBig(_) => (Some(CloseCode::Size), "Message too big"),
+        Ended::IdleTimeout => (Some(CloseCode::Away), "Idle timeout"),
+        Ended::SessionTimeout => (Some(CloseCode::Away), "Max session duration reached"),
+    };
 
-            None => TMessage::Close(None),
-        },
+    if let Ended::TooBig(direction) = ended {
+        warn!("{direction} message exceeded the max message size");
     }
-}
 
-fn into_amessage(msg: TMessage) -> Option<AMessage> {
-    use axum::extract::ws::CloseCode;
-
-    let msg = match msg {
-        TMessage::Text(t) => AMessage::Text(t.as_str().into()),
-        TMessage::Binary(b) => AMessage::Binary(b),
-        TMessage::Ping(p) => AMessage::Ping(p),
-        TMessage::Pong(p) => AMessage::Pong(p),
-        TMessage::Close(c) => match c {
-            Some(frame) => {
-                let frame = CloseFrame {
-                    code: CloseCode::from(frame.code),
-                    reason: frame.reason.as_str().into(),
-                };
-
-                AMessage::Close(Some(frame))
-            }
+    if let Some(code) = code {
+        info!(%code, reason, "closing ws session");
 
-            None => AMessage::Close(None),
-        },
+        let frame = CloseFrame {
+            code,
+            reason: reason.into(),
+        };
 
-        TMessage::Frame(_) => return None,
-    };
+        // the side which went away can't be told about it
+        if !matches!(ended, Ended::Gone(Direction::ClientToServer)) {
+            _ = sinks
+                .client
+                .lock()
+                .await
+                .send(Message::Close(Some(frame.clone())))
+                .await;
+        }
+
+        if !matches!(ended, Ended::Gone(Direction::ServerToClient)) {
+            _ = sinks
+                .dest
+                .lock()
+                .await
+                .send(Message::Close(Some(frame)))
+                .await;
+        }
+    }
 
-    Some(msg)
+    _ = sinks.client.lock().await.close().await;
+    _ = sinks.dest.lock().await.close().await;
 }
 
-fn msg_ty(msg: &TMessage) -> PrintableMessage {
+fn msg_ty(msg: &Message) -> PrintableMessage {
     msg.into()
 }
 
@@ -184,15 +408,15 @@ enum PrintableMessage {
     Frame,
 }
 
-impl From<&TMessage> for PrintableMessage {
-    fn from(value: &TMessage) -> Self {
+impl From<&Message> for PrintableMessage {
+    fn from(value: &Message) -> Self {
         match value {
-            TMessage::Text(_) => Self::Text,
-            TMessage::Binary(_) => Self::Binary,
-            TMessage::Ping(_) => Self::Ping,
-            TMessage::Pong(_) => Self::Pong,
-            TMessage::Close(_) => Self::Close,
-            TMessage::Frame(_) => Self::Frame,
+            Message::Text(_) => Self::Text,
+            Message::Binary(_) => Self::Binary,
+            Message::Ping(_) => Self::Ping,
+            Message::Pong(_) => Self::Pong,
+            Message::Close(_) => Self::Close,
+            Message::Frame(_) => Self::Frame,
         }
     }
 }
@@ -0,0 +1,834 @@
+//! permessage-deflate (RFC 7692) for client connections
+//!
+//! tungstenite doesn't support the extension, so it's done underneath it instead: incoming
+//! compressed frames are inflated before tungstenite reads them, and outgoing frames are
+//! deflated after tungstenite writes them.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+
+use axum::http::{HeaderMap, HeaderValue, header::SEC_WEBSOCKET_EXTENSIONS};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::config::Deflate;
+
+// every compressed message ends with an empty stored block, which is stripped on the wire
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+// zlib can't produce raw deflate streams with an 8 bit window
+const MIN_WINDOW_BITS: u8 = 9;
+const MAX_WINDOW_BITS: u8 = 15;
+
+/// Parameters agreed on with the client during the handshake
+#[derive(Debug, Copy, Clone)]
+pub struct DeflateParams {
+    server_max_window_bits: u8,
+    // whether to tell the client about it in the response
+    send_server_max_window_bits: bool,
+    client_max_window_bits: Option<u8>,
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool,
+}
+
+impl DeflateParams {
+    /// Picks the first permessage-deflate offer in the request which we can accept
+    pub fn negotiate(config: &Deflate, headers: &HeaderMap) -> Option<Self> {
+        if !config.enabled {
+            return None;
+        }
+
+        headers
+            .get_all(SEC_WEBSOCKET_EXTENSIONS)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .find_map(|offer| Self::accept(config, offer))
+    }
+
+    fn accept(config: &Deflate, offer: &str) -> Option<Self> {
+        let mut params = offer.split(';').map(str::trim);
+
+        if params.next()? != "permessage-deflate" {
+            return None;
+        }
+
+        let mut server_max_window_bits = None;
+        let mut client_max_window_bits = None;
+        let mut server_no_context_takeover = false;
+        let mut client_no_context_takeover = false;
+
+        for param in params {
+            let (name, value) = match param.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (param, None),
+            };
+
+            // a duplicated or unknown parameter means the offer must be declined
+            match (name, value) {
+                ("server_max_window_bits", Some(bits)) if server_max_window_bits.is_none() => {
+                    server_max_window_bits = Some(parse_window_bits(bits)?);
+                }
+                ("client_max_window_bits", bits) if client_max_window_bits.is_none() => {
+                    let bits = bits
+                        .map(parse_window_bits)
+                        .unwrap_or(Some(MAX_WINDOW_BITS))?;
+                    client_max_window_bits = Some(bits);
+                }
+                ("server_no_context_takeover", None) if !server_no_context_takeover => {
+                    server_no_context_takeover = true;
+                }
+                ("client_no_context_takeover", None) if !client_no_context_takeover => {
+                    client_no_context_takeover = true;
+                }
+
+                _ => return None,
+            }
+        }
+
+        let server_bits = config
+            .server_max_window_bits
+            .clamp(MIN_WINDOW_BITS, MAX_WINDOW_BITS)
+            .min(server_max_window_bits.unwrap_or(MAX_WINDOW_BITS));
+
+        if server_bits < MIN_WINDOW_BITS {
+            return None;
+        }
+
+        // only allowed in the response if the client offered it
+        let client_bits = client_max_window_bits.map(|offered| {
+            config
+                .client_max_window_bits
+                .clamp(MIN_WINDOW_BITS, MAX_WINDOW_BITS)
+                .min(offered)
+        });
+
+        Some(Self {
+            server_max_window_bits: server_bits,
+            send_server_max_window_bits: server_max_window_bits.is_some()
+                || server_bits < MAX_WINDOW_BITS,
+            client_max_window_bits: client_bits.filter(|&b| b < MAX_WINDOW_BITS),
+            server_no_context_takeover: server_no_context_takeover
+                || config.server_no_context_takeover,
+            client_no_context_takeover: client_no_context_takeover
+                || config.client_no_context_takeover,
+        })
+    }
+
+    /// Value of the `Sec-WebSocket-Extensions` response header
+    pub fn header_value(&self) -> HeaderValue {
+        let mut value = String::from("permessage-deflate");
+
+        if self.send_server_max_window_bits {
+            value.push_str(&format!(
+                "; server_max_window_bits={}",
+                self.server_max_window_bits
+            ));
+        }
+
+        if let Some(bits) = self.client_max_window_bits {
+            value.push_str(&format!("; client_max_window_bits={bits}"));
+        }
+
+        if self.server_no_context_takeover {
+            value.push_str("; server_no_context_takeover");
+        }
+
+        if self.client_no_context_takeover {
+            value.push_str("; client_no_context_takeover");
+        }
+
+        HeaderValue::from_str(&value).expect("extension header is ascii")
+    }
+}
+
+fn parse_window_bits(bits: &str) -> Option<u8> {
+    // window bits 8 is valid on the wire, but only usable by clients
+    bits.parse::<u8>()
+        .ok()
+        .filter(|b| (8..=MAX_WINDOW_BITS).contains(b))
+}
+
+/// Client connection which transparently applies permessage-deflate if it was negotiated
+pub struct DeflateStream<S> {
+    inner: S,
+    codec: Option<Codec>,
+}
+
+impl<S> DeflateStream<S> {
+    /// `max_frame_size` bounds how much a single incoming frame may inflate to
+    pub fn new(inner: S, params: Option<DeflateParams>, max_frame_size: usize) -> Self {
+        Self {
+            inner,
+            codec: params.map(|params| Codec::new(params, max_frame_size)),
+        }
+    }
+}
+
+struct Codec {
+    params: DeflateParams,
+    max_frame_size: usize,
+
+    compress: Compress,
+    decompress: Decompress,
+
+    // bytes read from the client which don't yet make up a whole frame
+    read_raw: Vec<u8>,
+    // inflated frames waiting to be read by tungstenite
+    read_decoded: Vec<u8>,
+    // whether the message currently being read is compressed
+    inflating: bool,
+
+    // bytes written by tungstenite which don't yet make up a whole frame
+    write_raw: Vec<u8>,
+    // deflated frames waiting to be written to the client
+    write_encoded: Vec<u8>,
+}
+
+impl Codec {
+    fn new(params: DeflateParams, max_frame_size: usize) -> Self {
+        Self {
+            params,
+            max_frame_size,
+            compress: Compress::new_with_window_bits(
+                Compression::default(),
+                false,
+                params.server_max_window_bits,
+            ),
+            decompress: Decompress::new_with_window_bits(false, MAX_WINDOW_BITS),
+            read_raw: Vec::new(),
+            read_decoded: Vec::new(),
+            inflating: false,
+            write_raw: Vec::new(),
+            write_encoded: Vec::new(),
+        }
+    }
+
+    /// Moves every complete frame in `read_raw` to `read_decoded`, inflating them if needed
+    fn decode(&mut self) -> io::Result<()> {
+        while let Some(header) = FrameHeader::parse(&self.read_raw) {
+            // hand oversized frames over as-is so tungstenite rejects them before they're buffered
+            if header.payload_len > self.max_frame_size {
+                self.read_decoded.append(&mut self.read_raw);
+                return Ok(());
+            }
+
+            let frame_len = header.header_len + header.payload_len;
+            if self.read_raw.len() < frame_len {
+                return Ok(());
+            }
+
+            let mut frame = self.read_raw.drain(..frame_len).collect::<Vec<_>>();
+
+            if header.is_control() {
+                self.read_decoded.append(&mut frame);
+                continue;
+            }
+
+            if !header.is_continuation() {
+                self.inflating = header.rsv1;
+            }
+
+            if !self.inflating {
+                self.read_decoded.append(&mut frame);
+                continue;
+            }
+
+            let payload = &mut frame[header.header_len..];
+            if let Some(mask) = header.mask {
+                for (i, b) in payload.iter_mut().enumerate() {
+                    *b ^= mask[i % 4];
+                }
+            }
+
+            let mut inflated = self.inflate(payload)?;
+            if header.fin {
+                inflated.extend(self.inflate(&TAIL)?);
+                // the tail is inflated on its own, so it can take the frame past the limit too
+                inflated.truncate(self.max_frame_size.saturating_add(1));
+
+                self.inflating = false;
+                if self.params.client_no_context_takeover {
+                    self.decompress.reset(false);
+                }
+            }
+
+            // re-masked with an all zero key, since tungstenite requires client frames to be masked
+            let header = FrameHeader {
+                rsv1: false,
+                mask: Some([0; 4]),
+                payload_len: inflated.len(),
+                ..header
+            };
+
+            header.write(&mut self.read_decoded);
+            self.read_decoded.extend(inflated);
+        }
+
+        Ok(())
+    }
+
+    /// Moves every complete frame in `write_raw` to `write_encoded`, deflating data frames
+    fn encode(&mut self) -> io::Result<()> {
+        while let Some(header) = FrameHeader::parse(&self.write_raw) {
+            let frame_len = header.header_len + header.payload_len;
+            if self.write_raw.len() < frame_len {
+                return Ok(());
+            }
+
+            let mut frame = self.write_raw.drain(..frame_len).collect::<Vec<_>>();
+
+            if header.is_control() {
+                self.write_encoded.append(&mut frame);
+                continue;
+            }
+
+            let mut deflated = self.deflate(&frame[header.header_len..])?;
+            if header.fin {
+                deflated.truncate(deflated.len().saturating_sub(TAIL.len()));
+
+                // an empty message deflates to a single empty block
+                if deflated.is_empty() {
+                    deflated.push(0x00);
+                }
+
+                if self.params.server_no_context_takeover {
+                    self.compress.reset();
+                }
+            }
+
+            let header = FrameHeader {
+                rsv1: !header.is_continuation(),
+                payload_len: deflated.len(),
+                ..header
+            };
+
+            header.write(&mut self.write_encoded);
+            self.write_encoded.extend(deflated);
+        }
+
+        Ok(())
+    }
+
+    fn deflate(&mut self, input: &[u8]) -> io::Result<Vec<u8>> {
+        let mut output = Vec::with_capacity(input.len() / 2 + 64);
+        let start = self.compress.total_in();
+
+        loop {
+            if output.len() == output.capacity() {
+                output.reserve(output.capacity());
+            }
+
+            let consumed = (self.compress.total_in() - start) as usize;
+            self.compress
+                .compress_vec(&input[consumed..], &mut output, FlushCompress::Sync)
+                .map_err(io::Error::other)?;
+
+            // the flush is complete once there's output space left over
+            let consumed = (self.compress.total_in() - start) as usize;
+            if consumed == input.len() && output.len() < output.capacity() {
+                return Ok(output);
+            }
+        }
+    }
+
+    fn inflate(&mut self, input: &[u8]) -> io::Result<Vec<u8>> {
+        // one past the limit, so tungstenite sees the frame is too big
+        let limit = self.max_frame_size.saturating_add(1);
+
+        let mut output = Vec::with_capacity((input.len() * 4).clamp(64, limit));
+        let start = self.decompress.total_in();
+
+        loop {
+            if output.len() == output.capacity() {
+                if output.len() >= limit {
+                    return Ok(output);
+                }
+
+                output.reserve(output.capacity().min(limit - output.len()));
+            }
+
+            let (before_in, before_out) = (self.decompress.total_in(), output.len());
+
+            let consumed = (before_in - start) as usize;
+            self.decompress
+                .decompress_vec(&input[consumed..], &mut output, FlushDecompress::Sync)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+            let consumed = (self.decompress.total_in() - start) as usize;
+            let progressed = self.decompress.total_in() != before_in || output.len() != before_out;
+
+            if (consumed == input.len() && output.len() < output.capacity()) || !progressed {
+                return Ok(output);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct FrameHeader {
+    fin: bool,
+    rsv1: bool,
+    // rsv2, rsv3 and opcode, which are passed through untouched
+    rest: u8,
+    mask: Option<[u8; 4]>,
+    header_len: usize,
+    payload_len: usize,
+}
+
+impl FrameHeader {
+    fn parse(buf: &[u8]) -> Option<Self> {
+        let [b0, b1, ..] = *buf else {
+            return None;
+        };
+
+        let masked = b1 & 0x80 != 0;
+
+        let (payload_len, mut header_len) = match b1 & 0x7f {
+            126 => (
+                u16::from_be_bytes(buf.get(2..4)?.try_into().ok()?) as usize,
+                4,
+            ),
+            127 => (
+                u64::from_be_bytes(buf.get(2..10)?.try_into().ok()?)
+                    .try_into()
+                    .unwrap_or(usize::MAX),
+                10,
+            ),
+            len => (len as usize, 2),
+        };
+
+        let mask = if masked {
+            let mask = buf.get(header_len..header_len + 4)?.try_into().ok()?;
+            header_len += 4;
+            Some(mask)
+        } else {
+            None
+        };
+
+        Some(Self {
+            fin: b0 & 0x80 != 0,
+            rsv1: b0 & 0x40 != 0,
+            rest: b0 & 0x3f,
+            mask,
+            header_len,
+            payload_len,
+        })
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        let mut b0 = self.rest;
+        if self.fin {
+            b0 |= 0x80;
+        }
+        if self.rsv1 {
+            b0 |= 0x40;
+        }
+
+        out.push(b0);
+
+        let mask_bit = if self.mask.is_some() { 0x80 } else { 0 };
+        match self.payload_len {
+            len @ 0..=125 => out.push(mask_bit | len as u8),
+            len @ 126..=0xffff => {
+                out.push(mask_bit | 126);
+                out.extend((len as u16).to_be_bytes());
+            }
+            len => {
+                out.push(mask_bit | 127);
+                out.extend((len as u64).to_be_bytes());
+            }
+        }
+
+        if let Some(mask) = self.mask {
+            out.extend(mask);
+        }
+    }
+
+    fn opcode(&self) -> u8 {
+        self.rest & 0x0f
+    }
+
+    fn is_control(&self) -> bool {
+        self.opcode() & 0x08 != 0
+    }
+
+    fn is_continuation(&self) -> bool {
+        self.opcode() == 0
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for DeflateStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        let Some(codec) = &mut this.codec else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+
+        loop {
+            if !codec.read_decoded.is_empty() {
+                let n = buf.remaining().min(codec.read_decoded.len());
+                buf.put_slice(&codec.read_decoded[..n]);
+                codec.read_decoded.drain(..n);
+
+                return Poll::Ready(Ok(()));
+            }
+
+            let mut chunk = [0; 8 * 1024];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+
+            if chunk.filled().is_empty() {
+                // eof, hand over any partial frame so tungstenite sees the truncation
+                if codec.read_raw.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+
+                codec.read_decoded.append(&mut codec.read_raw);
+                continue;
+            }
+
+            codec.read_raw.extend_from_slice(chunk.filled());
+            codec.decode()?;
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> DeflateStream<S> {
+    fn poll_write_encoded(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let Some(codec) = &mut self.codec else {
+            return Poll::Ready(Ok(()));
+        };
+
+        while !codec.write_encoded.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &codec.write_encoded))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+
+            codec.write_encoded.drain(..n);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for DeflateStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if this.codec.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+
+        // apply backpressure once a frame's worth of output is waiting
+        if this
+            .codec
+            .as_ref()
+            .is_some_and(|c| c.write_encoded.len() >= c.max_frame_size)
+        {
+            ready!(this.poll_write_encoded(cx))?;
+        }
+
+        if let Some(codec) = &mut this.codec {
+            codec.write_raw.extend_from_slice(buf);
+            codec.encode()?;
+        }
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_encoded(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_encoded(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: u8 = 0x1;
+    const CONTINUATION: u8 = 0x0;
+    const PING: u8 = 0x9;
+
+    fn enabled() -> Deflate {
+        Deflate {
+            enabled: true,
+            ..Default::default()
+        }
+    }
+
+    fn offer(extensions: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            SEC_WEBSOCKET_EXTENSIONS,
+            HeaderValue::from_static(extensions),
+        );
+        headers
+    }
+
+    fn negotiated(config: &Deflate, extensions: &'static str) -> Option<String> {
+        DeflateParams::negotiate(config, &offer(extensions))
+            .map(|p| p.header_value().to_str().unwrap().to_owned())
+    }
+
+    fn frame(fin: bool, opcode: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+        let header = FrameHeader {
+            fin,
+            rsv1: false,
+            rest: opcode,
+            mask,
+            header_len: 0,
+            payload_len: payload.len(),
+        };
+
+        let mut frame = Vec::new();
+        header.write(&mut frame);
+        frame.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(i, b)| b ^ mask.map_or(0, |m| m[i % 4])),
+        );
+        frame
+    }
+
+    /// The frames in `buf`, with their payloads unmasked
+    fn frames(mut buf: &[u8]) -> Vec<(FrameHeader, Vec<u8>)> {
+        let mut frames = Vec::new();
+
+        while let Some(header) = FrameHeader::parse(buf) {
+            let end = header.header_len + header.payload_len;
+            let payload = buf[header.header_len..end]
+                .iter()
+                .enumerate()
+                .map(|(i, b)| b ^ header.mask.map_or(0, |m| m[i % 4]))
+                .collect();
+
+            frames.push((header, payload));
+            buf = &buf[end..];
+        }
+
+        assert!(buf.is_empty(), "partial frame left over");
+        frames
+    }
+
+    fn params(config: &Deflate) -> DeflateParams {
+        DeflateParams::negotiate(config, &offer("permessage-deflate")).unwrap()
+    }
+
+    /// What the server sends, deflated
+    fn encode(codec: &mut Codec, frames: &[Vec<u8>]) -> Vec<u8> {
+        codec.write_raw.extend(frames.concat());
+        codec.encode().unwrap();
+        std::mem::take(&mut codec.write_encoded)
+    }
+
+    /// What a client sent, inflated
+    fn decode(codec: &mut Codec, bytes: &[u8]) -> Vec<u8> {
+        codec.read_raw.extend_from_slice(bytes);
+        codec.decode().unwrap();
+        std::mem::take(&mut codec.read_decoded)
+    }
+
+    #[test]
+    fn negotiates_offers() {
+        assert_eq!(negotiated(&Deflate::default(), "permessage-deflate"), None);
+        assert_eq!(
+            negotiated(&enabled(), "permessage-deflate").as_deref(),
+            Some("permessage-deflate")
+        );
+        assert_eq!(
+            negotiated(
+                &enabled(),
+                "permessage-deflate; client_max_window_bits; server_max_window_bits=10"
+            )
+            .as_deref(),
+            Some("permessage-deflate; server_max_window_bits=10")
+        );
+
+        let config = Deflate {
+            server_max_window_bits: 12,
+            client_max_window_bits: 11,
+            client_no_context_takeover: true,
+            ..enabled()
+        };
+        assert_eq!(
+            negotiated(&config, "permessage-deflate; client_max_window_bits").as_deref(),
+            Some(
+                "permessage-deflate; server_max_window_bits=12; client_max_window_bits=11; \
+                 client_no_context_takeover"
+            )
+        );
+        // the client can't be told about client_max_window_bits unless it offered it
+        assert_eq!(
+            negotiated(&config, "permessage-deflate").as_deref(),
+            Some("permessage-deflate; server_max_window_bits=12; client_no_context_takeover")
+        );
+    }
+
+    #[test]
+    fn declines_bad_offers() {
+        for offer in [
+            "x-webkit-deflate-frame",
+            "permessage-deflate; unknown",
+            "permessage-deflate; server_no_context_takeover; server_no_context_takeover",
+            "permessage-deflate; server_max_window_bits",
+            "permessage-deflate; server_max_window_bits=16",
+            // zlib can't compress with a window that small
+            "permessage-deflate; server_max_window_bits=8",
+            "permessage-deflate; server_no_context_takeover=1",
+        ] {
+            assert_eq!(negotiated(&enabled(), offer), None, "{offer}");
+        }
+
+        // the next one will do instead
+        assert_eq!(
+            negotiated(
+                &enabled(),
+                "permessage-deflate; unknown, permessage-deflate; server_no_context_takeover"
+            )
+            .as_deref(),
+            Some("permessage-deflate; server_no_context_takeover")
+        );
+    }
+
+    #[test]
+    fn parses_frame_headers() {
+        for len in [0, 125, 126, 0xffff, 0x10000] {
+            for mask in [None, Some([1, 2, 3, 4])] {
+                let payload = vec![7; len];
+                let bytes = frame(true, TEXT, &payload, mask);
+
+                let header = FrameHeader::parse(&bytes).unwrap();
+                assert!(header.fin);
+                assert!(!header.rsv1);
+                assert_eq!(header.opcode(), TEXT);
+                assert_eq!(header.mask, mask);
+                assert_eq!(header.payload_len, len);
+                assert_eq!(header.header_len + len, bytes.len());
+
+                // incomplete headers wait for more
+                assert!(FrameHeader::parse(&bytes[..header.header_len - 1]).is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn round_trips_messages() {
+        let params = params(&enabled());
+        let mut server = Codec::new(params, 1 << 20);
+        let mut client = Codec::new(params, 1 << 20);
+
+        let message = b"hello hello hello hello hello".repeat(10);
+
+        // twice, the second time using the context of the first
+        let mut sizes = Vec::new();
+        for _ in 0..2 {
+            let encoded = encode(&mut server, &[frame(true, TEXT, &message, None)]);
+            let [(header, payload)] = &frames(&encoded)[..] else {
+                panic!("expected one frame");
+            };
+            assert!(header.rsv1);
+            assert!(payload.len() < message.len());
+            sizes.push(payload.len());
+
+            let decoded = decode(&mut client, &encoded);
+            let [(header, payload)] = &frames(&decoded)[..] else {
+                panic!("expected one frame");
+            };
+            assert!(!header.rsv1);
+            assert_eq!(header.mask, Some([0; 4]));
+            assert_eq!(payload, &message);
+        }
+        assert!(sizes[1] < sizes[0]);
+    }
+
+    #[test]
+    fn handles_fragments_and_control_frames() {
+        let params = params(&enabled());
+        let mut server = Codec::new(params, 1 << 20);
+        let mut client = Codec::new(params, 1 << 20);
+
+        let encoded = encode(
+            &mut server,
+            &[
+                frame(false, TEXT, b"hello ", None),
+                frame(true, PING, b"ping", None),
+                frame(true, CONTINUATION, b"world", None),
+            ],
+        );
+
+        let sent = frames(&encoded);
+        assert_eq!(sent.len(), 3);
+        // only the first frame of a message is marked as compressed
+        assert!(sent[0].0.rsv1);
+        assert!(!sent[1].0.rsv1);
+        assert_eq!(sent[1].1, b"ping");
+        assert!(!sent[2].0.rsv1);
+
+        // fed in one byte at a time, the way the network might
+        let mut decoded = Vec::new();
+        for b in &encoded {
+            decoded.extend(decode(&mut client, &[*b]));
+        }
+
+        let received = frames(&decoded)
+            .into_iter()
+            .map(|(h, payload)| (h.opcode(), payload))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            received,
+            [
+                (TEXT, b"hello ".to_vec()),
+                (PING, b"ping".to_vec()),
+                (CONTINUATION, b"world".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn passes_uncompressed_messages_through() {
+        let mut client = Codec::new(params(&enabled()), 1 << 20);
+
+        let sent = frame(true, TEXT, b"plain", Some([9, 8, 7, 6]));
+        assert_eq!(decode(&mut client, &sent), sent);
+    }
+
+    #[test]
+    fn stops_inflating_at_the_frame_limit() {
+        let params = params(&enabled());
+        let mut server = Codec::new(params, 1 << 20);
+        let mut client = Codec::new(params, 100);
+
+        let encoded = encode(&mut server, &[frame(true, TEXT, &[0; 10_000], None)]);
+        let decoded = decode(&mut client, &encoded);
+
+        // one past the limit, for tungstenite to refuse
+        let [(header, payload)] = &frames(&decoded)[..] else {
+            panic!("expected one frame");
+        };
+        assert!(!header.rsv1);
+        assert_eq!(payload.len(), 101);
+    }
+}
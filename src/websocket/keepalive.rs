@@ -0,0 +1,210 @@
+use std::{
+    future,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use axum::body::Bytes;
+use futures::sink::SinkExt;
+use tokio::time::{self, Instant, MissedTickBehavior};
+use tungstenite::Message;
+
+use super::{Direction, Ended, Sinks};
+use crate::config::Websocket;
+
+// payload of proxy-originated pings, so their pongs can be told apart from the peers' own
+pub const PING_PAYLOAD: &[u8] = b"ssl-ifier";
+
+/// Tracks when each side of a session last sent anything, pongs to our pings included
+#[derive(Debug)]
+pub struct Activity {
+    started: Instant,
+    // millis since `started`
+    client: AtomicU64,
+    backend: AtomicU64,
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            client: AtomicU64::new(0),
+            backend: AtomicU64::new(0),
+        }
+    }
+
+    /// Records that the side sending in `direction` was heard from
+    pub fn touch(&self, direction: Direction) {
+        let elapsed = self.started.elapsed().as_millis() as u64;
+        self.side(direction).fetch_max(elapsed, Ordering::Relaxed);
+    }
+
+    fn last(&self, direction: Direction) -> Instant {
+        self.started + Duration::from_millis(self.side(direction).load(Ordering::Relaxed))
+    }
+
+    fn side(&self, direction: Direction) -> &AtomicU64 {
+        match direction {
+            Direction::ClientToServer => &self.client,
+            Direction::ServerToClient => &self.backend,
+        }
+    }
+}
+
+/// Whether `msg` is the reply to one of our own pings
+pub fn is_own_pong(payload: &[u8]) -> bool {
+    payload == PING_PAYLOAD
+}
+
+/// Pings both sides of the session on the configured interval. Never completes.
+pub async fn pinger(config: &Websocket, sinks: &Sinks) {
+    let Some(secs) = config.ping_interval.filter(|&s| s > 0) else {
+        return future::pending().await;
+    };
+
+    let period = Duration::from_secs(secs);
+    let mut interval = time::interval_at(Instant::now() + period, period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        let payload = Bytes::from_static(PING_PAYLOAD);
+
+        // failures show up on the receiving halves, which end the session
+        _ = sinks
+            .client
+            .lock()
+            .await
+            .send(Message::Ping(payload.clone()))
+            .await;
+        _ = sinks.dest.lock().await.send(Message::Ping(payload)).await;
+    }
+}
+
+/// Completes once the session has been idle or open for too long
+///
+/// With pings, both sides answer even when they've nothing to say, so one going quiet means it's
+/// gone. Without them, the session is only idle once neither side has sent anything
+pub async fn timeouts(config: &Websocket, activity: &Activity) -> Ended {
+    let pinging = config.ping_interval.is_some_and(|s| s > 0);
+    let last = || {
+        let client = activity.last(Direction::ClientToServer);
+        let backend = activity.last(Direction::ServerToClient);
+
+        if pinging {
+            client.min(backend)
+        } else {
+            client.max(backend)
+        }
+    };
+
+    let idle = config
+        .idle_timeout
+        .filter(|&s| s > 0)
+        .map(Duration::from_secs);
+    let session_deadline = config
+        .max_session_duration
+        .filter(|&s| s > 0)
+        .map(|s| activity.started + Duration::from_secs(s));
+
+    loop {
+        let idle_deadline = idle.map(|idle| last() + idle);
+
+        let deadline = match (idle_deadline, session_deadline) {
+            (Some(i), Some(s)) => i.min(s),
+            (Some(d), None) | (None, Some(d)) => d,
+            (None, None) => return future::pending().await,
+        };
+
+        time::sleep_until(deadline).await;
+
+        if session_deadline.is_some_and(|d| Instant::now() >= d) {
+            return Ended::SessionTimeout;
+        }
+
+        // activity may have moved the idle deadline while we slept
+        if let Some(idle) = idle
+            && last().elapsed() >= idle
+        {
+            return Ended::IdleTimeout;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(ping_interval: Option<u64>) -> Websocket {
+        Websocket {
+            ping_interval,
+            idle_timeout: Some(120),
+            max_session_duration: None,
+            ..Default::default()
+        }
+    }
+
+    /// Runs `timeouts` while `direction`'s side keeps sending every 10s, returning when it ended
+    async fn ends_after(config: &Websocket, direction: Direction) -> (Ended, Duration) {
+        let activity = Activity::new();
+        let start = Instant::now();
+
+        let chatty = async {
+            loop {
+                activity.touch(direction);
+                time::sleep(Duration::from_secs(10)).await;
+            }
+        };
+
+        let ended = tokio::select! {
+            ended = timeouts(config, &activity) => ended,
+            _ = chatty => unreachable!(),
+            _ = time::sleep(Duration::from_secs(3600)) => return (Ended::Closed, start.elapsed()),
+        };
+
+        (ended, start.elapsed())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_client_times_out_while_backend_answers() {
+        let (ended, after) = ends_after(&config(Some(30)), Direction::ServerToClient).await;
+
+        assert!(matches!(ended, Ended::IdleTimeout));
+        assert_eq!(after.as_secs(), 120);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_backend_times_out_while_client_answers() {
+        let (ended, after) = ends_after(&config(Some(30)), Direction::ClientToServer).await;
+
+        assert!(matches!(ended, Ended::IdleTimeout));
+        assert_eq!(after.as_secs(), 120);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn either_side_keeps_unpinged_sessions_open() {
+        let (ended, _) = ends_after(&config(None), Direction::ServerToClient).await;
+
+        // still going when the test gave up on it
+        assert!(matches!(ended, Ended::Closed));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sessions_end_at_their_max_duration() {
+        let config = Websocket {
+            max_session_duration: Some(600),
+            ..config(None)
+        };
+        let (ended, after) = ends_after(&config, Direction::ClientToServer).await;
+
+        assert!(matches!(ended, Ended::SessionTimeout));
+        assert_eq!(after.as_secs(), 600);
+    }
+
+    #[test]
+    fn tells_own_pongs_apart() {
+        assert!(is_own_pong(PING_PAYLOAD));
+        assert!(!is_own_pong(b"client's own"));
+    }
+}
@@ -0,0 +1,154 @@
+use std::{
+    borrow::Cow,
+    sync::atomic::{AtomicU16, AtomicU64, Ordering},
+    time::Instant,
+};
+
+use derive_more::derive::Display;
+use regex::Regex;
+use tracing::info;
+use tungstenite::Message;
+
+use super::msg_ty;
+use crate::config::{Websocket, WebsocketLog};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Display)]
+pub enum Direction {
+    #[display("client->server")]
+    ClientToServer,
+    #[display("server->client")]
+    ServerToClient,
+}
+
+#[derive(Debug)]
+pub struct WsLogger {
+    mode: WebsocketLog,
+    max_payload: usize,
+    redact: Vec<Regex>,
+    summary: bool,
+}
+
+impl WsLogger {
+    pub fn new(config: &Websocket) -> Result<Self, regex::Error> {
+        let redact = config
+            .log_redact
+            .iter()
+            .map(|r| Regex::new(r))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            mode: config.log,
+            max_payload: config.log_max_payload,
+            redact,
+            summary: config.log_summary,
+        })
+    }
+
+    pub fn message(&self, direction: Direction, msg: &Message) {
+        match self.mode {
+            WebsocketLog::Off => (),
+
+            WebsocketLog::Metadata => {
+                info!(ty = %msg_ty(msg), size = msg.len(), "{direction}");
+            }
+
+            WebsocketLog::Payload => {
+                let payload = self.payload(msg);
+                info!(ty = %msg_ty(msg), size = msg.len(), %payload, "{direction}");
+            }
+        }
+    }
+
+    pub fn summary(&self, stats: &SessionStats) {
+        if !self.summary {
+            return;
+        }
+
+        let close_code = match stats.close_code.load(Ordering::Relaxed) {
+            0 => Cow::Borrowed("none"),
+            code => Cow::Owned(code.to_string()),
+        };
+
+        info!(
+            duration = ?stats.started.elapsed(),
+            client_messages = stats.client_messages.load(Ordering::Relaxed),
+            client_bytes = stats.client_bytes.load(Ordering::Relaxed),
+            server_messages = stats.server_messages.load(Ordering::Relaxed),
+            server_bytes = stats.server_bytes.load(Ordering::Relaxed),
+            %close_code,
+            "ws session closed"
+        );
+    }
+
+    fn payload(&self, msg: &Message) -> String {
+        let payload = match msg {
+            Message::Text(t) => Cow::Borrowed(t.as_str()),
+            Message::Binary(b) | Message::Ping(b) | Message::Pong(b) => String::from_utf8_lossy(b),
+            Message::Close(Some(frame)) => Cow::Owned(format!("{} {}", frame.code, frame.reason)),
+            Message::Close(None) => Cow::Borrowed(""),
+            Message::Frame(f) => String::from_utf8_lossy(f.payload()),
+        };
+
+        let mut payload = payload.into_owned();
+        for regex in &self.redact {
+            if let Cow::Owned(redacted) = regex.replace_all(&payload, "******REDACTED******") {
+                payload = redacted;
+            }
+        }
+
+        if payload.len() > self.max_payload {
+            let mut end = self.max_payload;
+            while !payload.is_char_boundary(end) {
+                end -= 1;
+            }
+
+            payload.truncate(end);
+            payload.push_str("...");
+        }
+
+        payload
+    }
+}
+
+#[derive(Debug)]
+pub struct SessionStats {
+    started: Instant,
+    client_messages: AtomicU64,
+    client_bytes: AtomicU64,
+    server_messages: AtomicU64,
+    server_bytes: AtomicU64,
+    // 0 if no close frame was seen
+    close_code: AtomicU16,
+}
+
+impl SessionStats {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            client_messages: AtomicU64::new(0),
+            client_bytes: AtomicU64::new(0),
+            server_messages: AtomicU64::new(0),
+            server_bytes: AtomicU64::new(0),
+            close_code: AtomicU16::new(0),
+        }
+    }
+
+    pub fn record(&self, direction: Direction, msg: &Message) {
+        let (messages, bytes) = match direction {
+            Direction::ClientToServer => (&self.client_messages, &self.client_bytes),
+            Direction::ServerToClient => (&self.server_messages, &self.server_bytes),
+        };
+
+        messages.fetch_add(1, Ordering::Relaxed);
+        bytes.fetch_add(msg.len() as u64, Ordering::Relaxed);
+
+        if let Message::Close(Some(frame)) = msg {
+            _ = self.close_code.compare_exchange(
+                0,
+                frame.code.into(),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+        }
+    }
+}
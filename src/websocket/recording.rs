@@ -0,0 +1,466 @@
+//! Websocket session recordings
+//!
+//! Each session is written to its own `<unix millis>-<session>.wsrec` file. The format is plain
+//! text, one frame per line, after a header:
+//!
+//! ```text
+//! # ssl-ifier websocket recording v1
+//! # url: /ws?apiKey=******REDACTED******
+//! <millis since session start> <c|s> <text|binary|ping|pong|close> <payload as hex>
+//! ```
+//!
+//! `c` frames were sent by the client, `s` frames by the backend. Close payloads are the close
+//! code (2 bytes, big endian) followed by the reason, as on the wire. Payloads are recorded as-is,
+//! without any of the log redaction applied.
+//!
+//! Frames which arrive faster than the disk can keep up with are dropped rather than buffered,
+//! leaving a `# dropped <n> frames` comment where they were. Recordings stop at
+//! `websocket.record_max_size`, ending with a `# reached max size` comment.
+
+use std::{
+    fmt::Write as _,
+    io,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::body::Bytes;
+use snafu::{OptionExt, ResultExt, Snafu};
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncWriteExt, BufWriter},
+    sync::mpsc,
+    task,
+};
+use tracing::{error, warn};
+use tungstenite::{
+    Message,
+    protocol::{CloseFrame, frame::coding::CloseCode},
+};
+
+use super::logging::Direction;
+
+const HEADER: &str = "# ssl-ifier websocket recording v1";
+
+// lines waiting to be written, past which frames are dropped
+const BUFFER: usize = 1024;
+
+static SESSION: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Snafu)]
+pub enum RecordingError {
+    #[snafu(display("io error: {source}"))]
+    Io { source: io::Error },
+    #[snafu(display("not a websocket recording"))]
+    MissingHeader,
+    #[snafu(display("line {line}: {message}"))]
+    Parse { line: usize, message: String },
+}
+
+/// Writes the frames of a single session to disk
+#[derive(Debug)]
+pub struct Recorder {
+    started: Instant,
+    lines: mpsc::Sender<String>,
+    // frames dropped since the last line that made it
+    dropped: AtomicU64,
+}
+
+impl Recorder {
+    /// Starts recording to a new file in `dir`, which stops once it's `max_size` bytes
+    pub async fn create(
+        dir: &Path,
+        url: &str,
+        max_size: Option<u64>,
+    ) -> Result<Self, RecordingError> {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let session = SESSION.fetch_add(1, Ordering::Relaxed);
+
+        tokio::fs::create_dir_all(dir).await.context(IoSnafu)?;
+
+        let path = dir.join(format!("{millis}-{session}.wsrec"));
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        // payloads aren't redacted, so only the owner may read them
+        #[cfg(unix)]
+        options.mode(0o600);
+        let file = options.open(&path).await.context(IoSnafu)?;
+
+        let (lines, mut rx) = mpsc::channel::<String>(BUFFER);
+
+        // written from a separate task so a slow disk doesn't hold up the relay
+        task::spawn(async move {
+            let mut file = BufWriter::new(file);
+            let mut written = 0;
+
+            while let Some(line) = rx.recv().await {
+                written += line.len() as u64;
+                if max_size.is_some_and(|max| written > max) {
+                    warn!("ws recording {} reached its max size", path.display());
+                    _ = file.write_all(b"# reached max size\n").await;
+                    break;
+                }
+
+                if let Err(e) = file.write_all(line.as_bytes()).await {
+                    error!("failed to write ws recording {}: {e}", path.display());
+                    return;
+                }
+            }
+
+            _ = file.flush().await;
+        });
+
+        _ = lines.try_send(format!("{HEADER}\n# url: {url}\n"));
+
+        Ok(Self {
+            started: Instant::now(),
+            lines,
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    pub fn record(&self, direction: Direction, msg: &Message) {
+        let (opcode, payload) = match msg {
+            Message::Text(t) => ("text", t.as_bytes()),
+            Message::Binary(b) => ("binary", &b[..]),
+            Message::Ping(p) => ("ping", &p[..]),
+            Message::Pong(p) => ("pong", &p[..]),
+            Message::Close(frame) => {
+                self.send(self.line(direction, "close", &close_payload(frame.as_ref())));
+                return;
+            }
+            Message::Frame(_) => return,
+        };
+
+        self.send(self.line(direction, opcode, payload));
+    }
+
+    /// Queues the line for writing, dropping it if the writer is too far behind
+    fn send(&self, mut line: String) {
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            line.insert_str(0, &format!("# dropped {dropped} frames\n"));
+        }
+
+        match self.lines.try_send(line) {
+            Ok(()) => {}
+
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                if dropped == 0 {
+                    warn!("ws recording can't keep up, dropping frames");
+                }
+
+                // put back what was taken, along with this frame
+                self.dropped.fetch_add(dropped + 1, Ordering::Relaxed);
+            }
+
+            // the writer stopped, which has already been logged
+            Err(mpsc::error::TrySendError::Closed(_)) => {}
+        }
+    }
+
+    fn line(&self, direction: Direction, opcode: &str, payload: &[u8]) -> String {
+        let side = match direction {
+            Direction::ClientToServer => 'c',
+            Direction::ServerToClient => 's',
+        };
+
+        let mut line = format!("{} {side} {opcode} ", self.started.elapsed().as_millis());
+        for b in payload {
+            _ = write!(line, "{b:02x}");
+        }
+        line.push('\n');
+
+        line
+    }
+}
+
+fn close_payload(frame: Option<&CloseFrame>) -> Vec<u8> {
+    let Some(frame) = frame else {
+        return Vec::new();
+    };
+
+    let mut payload = u16::from(frame.code).to_be_bytes().to_vec();
+    payload.extend_from_slice(frame.reason.as_bytes());
+    payload
+}
+
+/// A frame read back from a recording
+#[derive(Debug)]
+pub struct RecordedFrame {
+    pub elapsed: Duration,
+    pub direction: Direction,
+    pub message: Message,
+}
+
+/// A parsed recording
+#[derive(Debug)]
+pub struct Recording {
+    pub url: Option<String>,
+    pub frames: Vec<RecordedFrame>,
+}
+
+impl Recording {
+    pub async fn load(path: &Path) -> Result<Self, RecordingError> {
+        let contents = tokio::fs::read_to_string(path).await.context(IoSnafu)?;
+        Self::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, RecordingError> {
+        let mut lines = contents.lines().enumerate();
+
+        let (_, header) = lines.next().context(MissingHeaderSnafu)?;
+        if header != HEADER {
+            return MissingHeaderSnafu.fail();
+        }
+
+        let mut url = None;
+        let mut frames = Vec::new();
+
+        for (i, line) in lines {
+            let line_no = i + 1;
+            let fail = |message: &str| {
+                ParseSnafu {
+                    line: line_no,
+                    message,
+                }
+                .fail()
+            };
+
+            if let Some(comment) = line.strip_prefix('#') {
+                if let Some(u) = comment.trim().strip_prefix("url:") {
+                    url = Some(u.trim().to_owned());
+                }
+
+                continue;
+            }
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let mut fields = line.split(' ');
+            let (Some(elapsed), Some(side), Some(opcode), payload) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                return fail("expected `<millis> <c|s> <opcode> <payload>`");
+            };
+
+            let Ok(elapsed) = elapsed.parse::<u64>() else {
+                return fail("invalid timestamp");
+            };
+
+            let direction = match side {
+                "c" => Direction::ClientToServer,
+                "s" => Direction::ServerToClient,
+                _ => return fail("direction must be `c` or `s`"),
+            };
+
+            let Some(payload) = from_hex(payload.unwrap_or("")) else {
+                return fail("invalid hex payload");
+            };
+
+            let message = match opcode {
+                "text" => match String::from_utf8(payload) {
+                    Ok(t) => Message::Text(t.into()),
+                    Err(_) => return fail("text payload is not utf-8"),
+                },
+                "binary" => Message::Binary(Bytes::from(payload)),
+                "ping" => Message::Ping(Bytes::from(payload)),
+                "pong" => Message::Pong(Bytes::from(payload)),
+                "close" => match &payload[..] {
+                    [] => Message::Close(None),
+                    [hi, lo, reason @ ..] => {
+                        let Ok(reason) = std::str::from_utf8(reason) else {
+                            return fail("close reason is not utf-8");
+                        };
+
+                        Message::Close(Some(CloseFrame {
+                            code: CloseCode::from(u16::from_be_bytes([*hi, *lo])),
+                            reason: reason.into(),
+                        }))
+                    }
+                    _ => return fail("close payload is too short"),
+                },
+
+                _ => return fail("unknown opcode"),
+            };
+
+            frames.push(RecordedFrame {
+                elapsed: Duration::from_millis(elapsed),
+                direction,
+                message,
+            });
+        }
+
+        Ok(Self { url, frames })
+    }
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recorder(buffer: usize) -> (Recorder, mpsc::Receiver<String>) {
+        let (lines, rx) = mpsc::channel(buffer);
+        let recorder = Recorder {
+            started: Instant::now(),
+            lines,
+            dropped: AtomicU64::new(0),
+        };
+
+        (recorder, rx)
+    }
+
+    fn close(code: u16, reason: &str) -> Message {
+        Message::Close(Some(CloseFrame {
+            code: CloseCode::from(code),
+            reason: reason.into(),
+        }))
+    }
+
+    #[test]
+    fn recordings_round_trip() {
+        let messages = [
+            (Direction::ClientToServer, Message::Text("hello".into())),
+            (
+                Direction::ServerToClient,
+                Message::Binary(Bytes::from_static(&[0, 1, 255])),
+            ),
+            (Direction::ClientToServer, Message::Text("".into())),
+            (Direction::ServerToClient, Message::Binary(Bytes::new())),
+            (Direction::ClientToServer, Message::Ping(Bytes::new())),
+            (
+                Direction::ServerToClient,
+                Message::Pong(Bytes::from_static(b"p")),
+            ),
+            (Direction::ServerToClient, close(1001, "going away")),
+            (Direction::ClientToServer, close(1000, "")),
+            (Direction::ClientToServer, Message::Close(None)),
+        ];
+
+        let (recorder, mut rx) = recorder(messages.len());
+        for (direction, msg) in &messages {
+            recorder.record(*direction, msg);
+        }
+
+        let mut contents = format!("{HEADER}\n# url: /ws?a=1\n");
+        while let Ok(line) = rx.try_recv() {
+            contents.push_str(&line);
+        }
+
+        let recording = Recording::parse(&contents).unwrap();
+        assert_eq!(recording.url.as_deref(), Some("/ws?a=1"));
+        assert_eq!(recording.frames.len(), messages.len());
+        for (frame, (direction, msg)) in recording.frames.iter().zip(&messages) {
+            assert_eq!(frame.direction, *direction);
+            assert_eq!(frame.message, *msg);
+        }
+    }
+
+    #[test]
+    fn counts_dropped_frames() {
+        let (recorder, mut rx) = recorder(1);
+        let msg = Message::Text("a".into());
+
+        recorder.record(Direction::ClientToServer, &msg);
+        recorder.record(Direction::ClientToServer, &msg);
+        recorder.record(Direction::ClientToServer, &msg);
+        assert!(!rx.try_recv().unwrap().starts_with('#'));
+
+        recorder.record(Direction::ClientToServer, &msg);
+        let line = rx.try_recv().unwrap();
+        assert!(line.starts_with("# dropped 2 frames\n"));
+        assert_eq!(recorder.dropped.load(Ordering::Relaxed), 0);
+
+        let contents = format!("{HEADER}\n{line}");
+        assert_eq!(Recording::parse(&contents).unwrap().frames.len(), 1);
+    }
+
+    #[test]
+    fn rejects_bad_recordings() {
+        assert!(matches!(
+            Recording::parse(""),
+            Err(RecordingError::MissingHeader)
+        ));
+        assert!(matches!(
+            Recording::parse("0 c text 61\n"),
+            Err(RecordingError::MissingHeader)
+        ));
+
+        let line = |line: &str| Recording::parse(&format!("{HEADER}\n{line}\n"));
+        for bad in [
+            "0 c text 6",
+            "0 c text zz",
+            "0 c text ff",
+            "0 x text 61",
+            "x c text 61",
+            "0 c nope 61",
+            "0 c close 03",
+            "0 c",
+        ] {
+            assert!(
+                matches!(line(bad), Err(RecordingError::Parse { line: 2, .. })),
+                "{bad}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_private_capped_files() {
+        let dir = std::env::temp_dir().join(format!("wsrec-test-{}", std::process::id()));
+        let recorder = Recorder::create(&dir, "/ws", Some(100)).await.unwrap();
+        for _ in 0..10 {
+            recorder.record(
+                Direction::ClientToServer,
+                &Message::Text("0123456789".into()),
+            );
+        }
+        drop(recorder);
+
+        let path = std::fs::read_dir(&dir)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+
+        // the writer finishes in the background
+        let mut contents = String::new();
+        for _ in 0..100 {
+            contents = tokio::fs::read_to_string(&path).await.unwrap();
+            if contents.ends_with("# reached max size\n") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert!(contents.ends_with("# reached max size\n"), "{contents}");
+        let recording = Recording::parse(&contents).unwrap();
+        assert!(!recording.frames.is_empty() && recording.frames.len() < 10);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt as _;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,144 @@
+//! Replays one side of a recorded session, for reproducing bugs offline
+//!
+//! - `ssl-ifier replay client <recording> <ws url>`: connects to a backend and sends it the
+//!   frames the client sent
+//! - `ssl-ifier replay server <recording> <listen addr>`: accepts plain `ws://` clients and sends
+//!   each of them the frames the backend sent
+//!
+//! Frames are sent with the same timing they were recorded with. Anything the peer sends back is
+//! logged.
+
+use std::{io, path::Path, sync::Arc, time::Duration};
+
+use futures::{
+    sink::SinkExt,
+    stream::{SplitStream, StreamExt},
+};
+use snafu::{ResultExt, Snafu};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    select, task,
+    time::{self, Instant},
+};
+use tokio_tungstenite::{WebSocketStream, accept_async, connect_async};
+use tracing::{error, info};
+
+use super::{
+    logging::Direction,
+    msg_ty,
+    recording::{Recording, RecordingError},
+};
+
+// how long to wait for the peer to close after the last recorded frame was sent
+const GRACE: Duration = Duration::from_secs(5);
+
+const USAGE: &str =
+    "usage: ssl-ifier replay <client <recording> <ws url> | server <recording> <listen addr>>";
+
+#[derive(Debug, Snafu)]
+pub enum ReplayError {
+    #[snafu(display("{USAGE}"))]
+    Usage,
+    #[snafu(display("failed to load recording: {source}"))]
+    Load { source: RecordingError },
+    #[snafu(display("failed to connect: {source}"))]
+    Connect { source: tungstenite::Error },
+    #[snafu(display("io error: {source}"))]
+    Io { source: io::Error },
+}
+
+pub async fn replay(args: &[String]) -> Result<(), ReplayError> {
+    let [mode, recording, target] = args else {
+        return UsageSnafu.fail();
+    };
+
+    let recording = Recording::load(Path::new(recording))
+        .await
+        .context(LoadSnafu)?;
+
+    if let Some(url) = &recording.url {
+        info!("replaying session recorded from {url}");
+    }
+
+    match mode.as_str() {
+        "client" => {
+            let (socket, _) = connect_async(target.as_str()).await.context(ConnectSnafu)?;
+            replay_side(socket, &recording, Direction::ClientToServer).await;
+        }
+
+        "server" => {
+            let listener = TcpListener::bind(target.as_str()).await.context(IoSnafu)?;
+            info!("waiting for clients on ws://{target}");
+
+            let recording = Arc::new(recording);
+
+            loop {
+                let (stream, addr) = listener.accept().await.context(IoSnafu)?;
+                let recording = recording.clone();
+
+                task::spawn(async move {
+                    match accept_async(stream).await {
+                        Ok(socket) => {
+                            info!("replaying to {addr}");
+                            replay_side(socket, &recording, Direction::ServerToClient).await;
+                        }
+
+                        Err(e) => error!("handshake with {addr} failed: {e}"),
+                    }
+                });
+            }
+        }
+
+        _ => return UsageSnafu.fail(),
+    }
+
+    Ok(())
+}
+
+/// Sends the frames `direction` sent in the recording, logging whatever comes back
+async fn replay_side<S>(socket: WebSocketStream<S>, recording: &Recording, direction: Direction)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut sender, receiver) = socket.split();
+    let started = Instant::now();
+
+    let send = async {
+        let frames = recording.frames.iter().filter(|f| f.direction == direction);
+
+        for frame in frames {
+            time::sleep_until(started + frame.elapsed).await;
+
+            let msg = &frame.message;
+            info!(ty = %msg_ty(msg), size = msg.len(), "{direction}");
+
+            if let Err(e) = sender.send(msg.clone()).await {
+                error!("failed to send: {e}");
+                return;
+            }
+        }
+
+        time::sleep(GRACE).await;
+    };
+
+    select! {
+        _ = send => info!("replay finished"),
+        _ = receive(receiver, direction) => info!("peer closed the connection"),
+    }
+}
+
+async fn receive<S>(mut receiver: SplitStream<WebSocketStream<S>>, direction: Direction)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // the peer plays the other side
+    let direction = match direction {
+        Direction::ClientToServer => Direction::ServerToClient,
+        Direction::ServerToClient => Direction::ClientToServer,
+    };
+
+    while let Some(Ok(msg)) = receiver.next().await {
+        info!(ty = %msg_ty(&msg), size = msg.len(), %msg, "{direction}");
+    }
+}
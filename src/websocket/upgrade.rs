@@ -0,0 +1,149 @@
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    extract::FromRequestParts,
+    http::{
+        HeaderMap, HeaderValue, Method, StatusCode,
+        header::{self, HeaderName},
+        request::Parts,
+    },
+    response::Response,
+};
+use hyper::upgrade::{OnUpgrade, Upgraded};
+use hyper_util::rt::TokioIo;
+use tokio::task;
+use tokio_tungstenite::WebSocketStream;
+use tracing::{Instrument as _, Span, error};
+use tungstenite::{
+    handshake::derive_accept_key,
+    protocol::{Role, WebSocketConfig},
+};
+
+use super::deflate::{DeflateParams, DeflateStream};
+use crate::{StateData, error_pages::error_page};
+
+pub type ClientSocket = WebSocketStream<DeflateStream<TokioIo<Upgraded>>>;
+
+/// Websocket upgrade request from a client
+///
+/// Used instead of axum's `WebSocketUpgrade` so extensions (permessage-deflate) can be negotiated
+pub struct ClientUpgrade {
+    key: HeaderValue,
+    on_upgrade: OnUpgrade,
+    deflate: Option<DeflateParams>,
+}
+
+impl FromRequestParts<Arc<StateData>> for ClientUpgrade {
+    type Rejection = Response<Body>;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<StateData>,
+    ) -> Result<Self, Self::Rejection> {
+        let reject = |error| error_page(StatusCode::BAD_REQUEST, error);
+
+        if parts.method != Method::GET {
+            return Err(reject("websocket upgrades must use GET"));
+        }
+
+        if !header_contains(&parts.headers, header::CONNECTION, "upgrade")
+            || !header_eq(&parts.headers, header::UPGRADE, "websocket")
+        {
+            return Err(reject("not a websocket upgrade request"));
+        }
+
+        if !header_eq(&parts.headers, header::SEC_WEBSOCKET_VERSION, "13") {
+            return Err(reject("unsupported websocket version"));
+        }
+
+        let key = parts
+            .headers
+            .get(header::SEC_WEBSOCKET_KEY)
+            .cloned()
+            .ok_or_else(|| reject("missing Sec-WebSocket-Key"))?;
+
+        let on_upgrade = parts
+            .extensions
+            .remove::<OnUpgrade>()
+            .ok_or_else(|| reject("connection is not upgradable"))?;
+
+        let deflate = DeflateParams::negotiate(&state.config.websocket.deflate, &parts.headers);
+
+        Ok(Self {
+            key,
+            on_upgrade,
+            deflate,
+        })
+    }
+}
+
+impl ClientUpgrade {
+    /// Completes the handshake, then calls `callback` with the upgraded connection
+    pub fn on_upgrade<F, Fut>(self, config: WebSocketConfig, callback: F) -> Response<Body>
+    where
+        F: FnOnce(ClientSocket) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let Self {
+            key,
+            on_upgrade,
+            deflate,
+        } = self;
+
+        task::spawn(
+            async move {
+                let upgraded = match on_upgrade.await {
+                    Ok(upgraded) => upgraded,
+                    Err(e) => {
+                        error!("failed to upgrade client connection: {e}");
+                        return;
+                    }
+                };
+
+                let max_frame_size = config.max_frame_size.unwrap_or(usize::MAX);
+                let stream = DeflateStream::new(TokioIo::new(upgraded), deflate, max_frame_size);
+                let socket =
+                    WebSocketStream::from_raw_socket(stream, Role::Server, Some(config)).await;
+
+                callback(socket).await;
+            }
+            // keeps the request's span (and id) on the session's logs
+            .instrument(Span::current()),
+        );
+
+        let mut response = Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(header::CONNECTION, "upgrade")
+            .header(header::UPGRADE, "websocket")
+            .header(
+                header::SEC_WEBSOCKET_ACCEPT,
+                derive_accept_key(key.as_bytes()),
+            )
+            .body(Body::empty())
+            .expect("handshake response is valid");
+
+        if let Some(deflate) = deflate {
+            response
+                .headers_mut()
+                .insert(header::SEC_WEBSOCKET_EXTENSIONS, deflate.header_value());
+        }
+
+        response
+    }
+}
+
+fn header_eq(headers: &HeaderMap, key: HeaderName, value: &str) -> bool {
+    headers
+        .get(key)
+        .is_some_and(|h| h.as_bytes().eq_ignore_ascii_case(value.as_bytes()))
+}
+
+fn header_contains(headers: &HeaderMap, key: HeaderName, value: &str) -> bool {
+    headers
+        .get_all(key)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case(value))
+}